@@ -1,12 +1,14 @@
-use std::sync::mpsc::{self, Sender, Receiver};
+use std::sync::mpsc::{self, Sender, Receiver, TryRecvError};
 use eframe::egui;
 use super::task::TaskResult;
-use super::task::TaskType;
 use super::state::login::LoginState;
 use super::state::create_account::CreateAccountState;
 use super::state::chat::ChatState;
 use super::state::Page;
 use super::thread::http_thread::{init_http_thread, TaskWrapper};
+use super::thread::key_store_thread::{init_key_store_thread, KeyStoreJob};
+use super::util::settings::{Settings, SettingsOverrides, ServerEndpoints};
+use super::http::HttpClient;
 
 pub struct App {
     current_page: Page,
@@ -14,47 +16,131 @@ pub struct App {
     create_account_state: CreateAccountState,
     chat_state: ChatState,
     http_thread: Sender<TaskWrapper>,
+    key_store_thread: Sender<KeyStoreJob>,
     result_queue: Vec<Receiver<TaskResult>>
 }
 
 impl Default for App {
     fn default() -> Self {
-        let (http_thread_sender, http_thread_receiver) = mpsc::channel();
-        std::thread::spawn(|| init_http_thread(http_thread_receiver));
+        let mut login_state = LoginState::default();
+
+        let mut errors = Vec::new();
+
+        let saved_settings = Settings::load().unwrap_or_else(|e| {
+            errors.push(format!("Couldn't read saved settings, using defaults: {}", e));
+            Settings::default()
+        });
+        let (settings_overrides, override_errors) = SettingsOverrides::from_env();
+        errors.extend(override_errors.iter().map(|e| e.to_string()));
+
+        let mut settings = saved_settings.with_overrides(&settings_overrides);
+        let endpoints = settings.validate().unwrap_or_else(|e| {
+            errors.push(format!("Invalid server settings, using defaults: {}", e));
+            settings = Settings::default();
+            ServerEndpoints::default()
+        });
+        login_state.error = errors.join("\n");
+
+        let http_thread = spawn_http_thread(&endpoints, &settings);
+        login_state.server_settings = settings;
+        login_state.saved_settings = saved_settings;
+        login_state.settings_overrides = settings_overrides;
+
+        let chat_state = ChatState {
+            endpoints: endpoints.clone(),
+            ..Default::default()
+        };
 
         Self {
             current_page: Page::Login,
-            login_state: LoginState::default(),
+            login_state,
             create_account_state: CreateAccountState::default(),
-            chat_state: ChatState::default(),
-            http_thread: http_thread_sender,
+            chat_state,
+            http_thread,
+            key_store_thread: init_key_store_thread(),
             result_queue: Vec::new()
         }
     }
 }
 
+fn spawn_http_thread(endpoints: &ServerEndpoints, settings: &Settings) -> Sender<TaskWrapper> {
+    let http_client = HttpClient::new(
+        endpoints.http_url.clone(),
+        endpoints.ca_certificate.as_deref(),
+        settings.http_client_options()
+    );
+    let worker_count = settings.http_worker_count();
+    let (http_thread_sender, http_thread_receiver) = mpsc::channel();
+    std::thread::spawn(move || init_http_thread(http_client, http_thread_receiver, worker_count));
+
+    http_thread_sender
+}
+
 impl App {
+    fn apply_server_endpoints(&mut self) {
+        if let Some(endpoints) = self.login_state.applied_endpoints.take() {
+            // dropping the old sender lets the previous http thread finish its queue and exit
+            self.http_thread = spawn_http_thread(&endpoints, &self.login_state.server_settings);
+            self.chat_state.endpoints = endpoints;
+        }
+    }
+
     fn process_result_queue(&mut self, ctx: &egui::Context) {
-        for i in 0..self.result_queue.len() {
+        // several workers can finish in the same frame, so only advance when nothing was removed
+        let mut i = 0;
+        while i < self.result_queue.len() {
             match self.result_queue[i].try_recv() {
                 Ok(task_result) => {
-                    match task_result.task_type {
-                        TaskType::Login => self.login_state.handle_task_login(
-                            task_result,
+                    self.result_queue.swap_remove(i);
+
+                    match task_result {
+                        TaskResult::Login(result) => self.login_state.handle_task_login(result),
+                        TaskResult::CreateAccount(result) => self.create_account_state.handle_task_create_account(
+                            result,
+                            self.login_state.server_settings.key_store
+                        ),
+                        TaskResult::SearchUser(result) => self.chat_state.handle_task_search_user(result),
+                        TaskResult::SendInviteContact(receiver_id, receiver_email, result) => self.chat_state.handle_task_send_invite_contact(receiver_id, receiver_email, result),
+                        TaskResult::AcceptInviteContact(contact_id, result) => self.chat_state.handle_task_accept_invite_contact(contact_id, result, ctx),
+                        TaskResult::FetchChatMessages(chat_id, result) => self.chat_state.handle_task_fetch_chat_messages(chat_id, result),
+                        TaskResult::SyncChatMessages(chat_id, offset, result) => self.chat_state.handle_task_sync_chat_messages(
+                            chat_id,
+                            offset,
+                            result
+                        ),
+                        TaskResult::FetchPublicKey(contact_id, result) => self.chat_state.handle_task_fetch_public_key(contact_id, result),
+                        TaskResult::ReadPrivateKey(result) => self.login_state.handle_task_read_private_key(result),
+                        TaskResult::UnsealPrivateKey(result) => self.login_state.handle_task_unseal_private_key(
+                            result,
+                            &self.key_store_thread,
+                            &mut self.result_queue,
+                            &mut self.chat_state,
+                            &mut self.current_page,
+                            ctx
+                        ),
+                        TaskResult::SavePrivateKey(result) => self.login_state.handle_task_save_private_key(
+                            result,
+                            &self.http_thread,
+                            &mut self.result_queue,
                             &mut self.chat_state,
                             &mut self.current_page,
                             ctx
                         ),
-                        TaskType::CreateAccount => self.create_account_state.handle_task_create_account(task_result),
-                        TaskType::SearchUser => self.chat_state.handle_task_search_user(task_result),
-                        TaskType::SendInviteContact => self.chat_state.handle_task_send_invite_contact(task_result),
-                        TaskType::AcceptInviteContact => self.chat_state.handle_task_accept_invite_contact(task_result, ctx),
-                        TaskType::FetchChatMessages => self.chat_state.handle_task_fetch_chat_messages(task_result)
+                        TaskResult::UpdatePublicKey(result) => match self.current_page {
+                            Page::Chat => self.chat_state.handle_task_update_public_key(result),
+                            _ => self.login_state.handle_task_update_public_key(
+                                result,
+                                &mut self.chat_state,
+                                &mut self.current_page,
+                                ctx
+                            )
+                        }
                     }
-
+                },
+                Err(TryRecvError::Disconnected) => {
                     self.result_queue.swap_remove(i);
                 },
-                Err(_e) => {}
+                Err(TryRecvError::Empty) => i += 1
             }
         }
     }
@@ -65,6 +151,7 @@ impl eframe::App for App {
         match self.current_page {
             Page::Login => self.login_state.show_login_page(
                 &self.http_thread,
+                &self.key_store_thread,
                 &mut self.result_queue,
                 &mut self.current_page,
                 ctx
@@ -82,6 +169,7 @@ impl eframe::App for App {
             )
         }
 
+        self.apply_server_endpoints();
         self.process_result_queue(ctx);
     }
 }
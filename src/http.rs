@@ -1,35 +1,71 @@
+use rand::Rng;
 use reqwest::{
     Url,
+    Certificate,
+    Method,
     header::HeaderMap,
-    blocking::{Client, Response},
+    blocking::{Client, RequestBuilder, Response},
     Error
 };
 use serde_json::Value;
+use std::thread;
+use std::time::Duration;
+
+#[derive(Clone)]
+pub struct HttpClientOptions {
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration
+}
+
+impl Default for HttpClientOptions {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(30),
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(5)
+        }
+    }
+}
 
 pub struct HttpClient {
     base_url: Url,
-    http_client: Client
+    http_client: Client,
+    options: HttpClientOptions
 }
 
 impl HttpClient {
-    pub fn new(base_url: &str) -> Self {
+    pub fn new(base_url: Url, ca_certificate: Option<&[u8]>, options: HttpClientOptions) -> Self {
+        let mut client_builder = Client::builder()
+            .connect_timeout(options.connect_timeout)
+            .timeout(options.read_timeout);
+        if let Some(pem) = ca_certificate {
+            let certificate = Certificate::from_pem(pem).expect("Bad CA certificate");
+            client_builder = client_builder.add_root_certificate(certificate);
+        }
+
         Self {
-            base_url: base_url.parse::<Url>().expect("Bad base_url"),
-            http_client: Client::new()
+            base_url,
+            http_client: client_builder.build().expect("Couldn't build http client"),
+            options
         }
     }
 
     pub fn post(&self, path: &str, body: Option<Value>, headers: Option<HeaderMap>) -> Result<Response, Error> {
         let full_url = self.base_url.join(path).expect("Bad path");
-        let mut request_builder = self.http_client.post(full_url);
 
-        if let Some(h) = headers {
-            request_builder = request_builder.headers(h);
-        }
+        self.send_with_retry(Method::POST, || {
+            let mut request_builder = self.http_client.post(full_url.clone());
+            if let Some(h) = headers.clone() {
+                request_builder = request_builder.headers(h);
+            }
 
-        request_builder
-            .json(&body)
-            .send()
+            request_builder.json(&body)
+        })
     }
 
     pub fn get(&self, path: &str, query_params: Option<Vec<String>>, headers: Option<HeaderMap>) -> Result<Response, Error> {
@@ -40,11 +76,105 @@ impl HttpClient {
             }
         }
 
-        let mut request_builder = self.http_client.get(full_url);
-        if let Some(h) = headers {
-            request_builder = request_builder.headers(h);
+        self.send_with_retry(Method::GET, || {
+            let mut request_builder = self.http_client.get(full_url.clone());
+            if let Some(h) = headers.clone() {
+                request_builder = request_builder.headers(h);
+            }
+
+            request_builder
+        })
+    }
+
+    fn send_with_retry(&self, method: Method, build_request: impl Fn() -> RequestBuilder) -> Result<Response, Error> {
+        let mut attempt = 0;
+
+        loop {
+            let result = build_request().send();
+            if attempt >= self.options.max_retries || !should_retry(&method, &result) {
+                return result;
+            }
+
+            attempt += 1;
+            thread::sleep(self.backoff_delay(attempt));
         }
+    }
+
+    fn backoff_delay(&self, attempt: u32) -> Duration {
+        let exponential = self.options.initial_backoff.saturating_mul(2u32.saturating_pow(attempt - 1));
+        let delay = exponential.min(self.options.max_backoff);
+        let jitter = rand::thread_rng().gen_range(0.5..1.5);
+
+        delay.mul_f64(jitter)
+    }
+}
+
+// a GET can always be repeated; anything else is only retried when the connection
+// was never established, since otherwise the server may already have applied it
+fn should_retry(method: &Method, result: &Result<Response, Error>) -> bool {
+    match result {
+        Err(e) if e.is_connect() => true,
+        Err(e) => *method == Method::GET && (e.is_timeout() || e.is_request()),
+        Ok(response) => *method == Method::GET && response.status().is_server_error()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    // answers one connection per canned response and returns the request lines it saw
+    fn stand_in_server(responses: Vec<&'static str>) -> (Url, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://127.0.0.1:{}/", listener.local_addr().unwrap().port()).parse().unwrap();
+
+        let server = thread::spawn(move || {
+            responses.into_iter().map(|response| {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let read = stream.read(&mut buffer).unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                }
+                stream.write_all(response.as_bytes()).unwrap();
+
+                String::from_utf8_lossy(&request).lines().next().unwrap().to_owned()
+            }).collect()
+        });
+
+        (url, server)
+    }
+
+    fn test_client(base_url: Url, max_retries: u32) -> HttpClient {
+        HttpClient::new(base_url, None, HttpClientOptions {
+            max_retries,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            ..Default::default()
+        })
+    }
+
+    const UNAVAILABLE: &str = "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+    const OK: &str = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}";
+
+    #[test]
+    fn get_is_retried_after_a_server_error() {
+        let (url, server) = stand_in_server(vec![UNAVAILABLE, OK]);
+
+        let response = test_client(url, 2).get("chat_api/message/1/0", None, None).unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(server.join().unwrap(), vec!["GET /chat_api/message/1/0 HTTP/1.1"; 2]);
+    }
+
+    #[test]
+    fn post_is_not_retried_after_a_server_error() {
+        let (url, server) = stand_in_server(vec![UNAVAILABLE]);
 
-        request_builder.send()
+        let response = test_client(url, 2).post("login", None, None).unwrap();
+        assert_eq!(response.status(), 503);
+        assert_eq!(server.join().unwrap(), vec!["POST /login HTTP/1.1"]);
     }
 }
@@ -1,125 +1,270 @@
 use crate::egui;
-use crate::thread::http_thread::TaskWrapper;
-use crate::task::TaskResult;
+use crate::thread::http_thread::{TaskWrapper, CancellationHandle};
+use crate::thread::store_thread::{StoreWrite, StoreData, init_store_thread};
+use crate::task::{TaskResult, TaskError};
 use crate::task::search_user_task::SearchUserTask;
 use crate::task::search_user_task::SearchUserResponse;
 use crate::task::send_invite_contact_task::SendInviteContactTask;
 use crate::task::accept_invite_contact_task::AcceptInviteContactTask;
 use crate::task::fetch_chat_messages::FetchChatMessagesTask;
+use crate::task::update_public_key_task::UpdatePublicKeyTask;
+use crate::task::fetch_public_key_task::{FetchPublicKeyTask, PublicKeyResponse};
 use crate::task::GenericResultError;
-use crate::util::encryption::{encrypt_plain_text, generate_cipher};
+use crate::util::encryption::{generate_assymetric_keypair, decode_and_decrypt};
+use crate::util::ratchet::{RatchetSession, load_sessions};
+use crate::util::local_store::LogStatus;
+use crate::util::message_store::{MessageStore, StoredMessage, load_message_store};
+use crate::util::search_index::{SearchIndex, SearchHit, load_search_index};
+use crate::util::chat_export::{ExportFormat, ExportedMessage, ChatExport, write_chat_export, read_chat_export};
+use crate::util::time_format::{message_time, full_time, local_date, day_separator};
+use crate::util::outbox::{OutboxEntry, MAX_DELIVERY_ATTEMPTS, save_outbox, load_outbox};
+use crate::util::contact_keys::{ContactKeys, save_contact_keys, load_contact_keys};
+use crate::util::verified_contacts::{VerifiedContacts, save_verified_contacts, load_verified_contacts};
+use crate::util::safety_number::{safety_number, safety_grid};
+use crate::util::settings::{ServerEndpoints, home_folder_path};
+use crate::util::keyring_handler::{
+    export_private_key,
+    get_private_key,
+    save_private_key,
+    KeyStoreKind,
+    PrivateKeySet,
+    MIN_PASSPHRASE_LENGTH,
+    DEFAULT_BACKUP_FILE_NAME
+};
 use crate::thread::websocket_thread::{
     init_websocket,
     init_websocket_thread,
+    set_nonblocking,
+    ConnectionStatus,
+    WsEvent,
     WsContentMessage,
     ContentMessageWrapper,
     InviteMessage,
     AcceptInvite,
+    KeyRotatedMessage,
     MessageType
 };
 use super::ContactInfo;
+use super::ContactInfoJSON;
+use super::decode_public_key;
 use super::Message;
+use super::MessageStatus;
 use super::FetchMessage;
 use base64::prelude::*;
+use chrono::{DateTime, Utc};
 use serde_json::Value;
 use tungstenite::{WebSocket, stream::MaybeTlsStream};
-use serde::{Deserialize, Serialize};
 use x25519_dalek::{StaticSecret, PublicKey};
-use chacha20poly1305::aead::generic_array::GenericArray;
-use chacha20poly1305::XNonce;
-use chacha20poly1305::aead::Aead;
 use std::cell::OnceCell;
-use std::sync::mpsc::{Sender, Receiver};
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Sender, Receiver};
 use std::net::TcpStream;
-use std::collections::LinkedList;
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Clone)]
+pub enum RetryAction {
+    SearchUser,
+    SendInvite(u64, String),
+    AcceptInvite(u64),
+    FetchMessages,
+    SyncMessages,
+    FetchPublicKey(u64)
+}
+
+#[derive(PartialEq)]
+pub enum VerificationStatus {
+    Unverified,
+    Verified,
+    KeyChanged
+}
 
 pub struct ChatState {
+    pub endpoints: ServerEndpoints,
     pub token: String,
     pub user_id: u64,
+    pub email: String,
+    pub key_store: KeyStoreKind,
     pub private_key: Option<StaticSecret>,
+    pub previous_private_keys: Vec<StaticSecret>,
+    pub contact_keys: ContactKeys,
+    pub verified_contacts: VerifiedContacts,
 
     pub contacts: Vec<ContactInfo>,
+    // sessions of contacts left out at login, saved along with the others so they aren't lost
+    pub left_out_sessions: HashMap<u64, RatchetSession>,
 
     pub current_selected_id: u64,
     pub clicked_contact_id: Option<u64>,
     pub is_fetching_messages: bool,
 
     pub modal_error: String,
+    pub retry_action: Option<RetryAction>,
+    pub fetch_messages_error: bool,
     pub typed_message: String,
 
+    pub search_index: SearchIndex,
+    pub message_query: String,
+    pub message_search_results: Vec<SearchHit>,
+    pub jump_to_message: Option<SearchHit>,
+    pub highlighted_message_id: Option<u64>,
+
     pub show_search_modal: bool,
     pub search_email: String,
     pub searched_users: Vec<SearchUserResponse>,
     pub should_search: bool,
     pub is_search_loading: bool,
+    pub search_cancellation: Option<CancellationHandle>,
 
     pub clicked_invite_contact_id: Option<u64>,
     pub clicked_invite_contact_email: Option<String>,
-    pub is_send_invite_loading: bool,
+    pub sending_invite_ids: Vec<u64>,
 
     pub received_invites: Vec<InviteMessage>,
     pub sent_invites: Vec<InviteMessage>,
     pub accepted_contact_id: Option<u64>,
+    pub accepting_invite_ids: Vec<u64>,
 
     pub show_invites_modal: bool,
-    pub is_loading_accept_invite: bool,
+    pub show_backup_modal: bool,
+    pub backup_path: String,
+    pub backup_passphrase: String,
+    pub backup_confirm_passphrase: String,
+    pub backup_error: String,
+    pub backup_success: String,
+
+    pub show_rotate_key_modal: bool,
+    pub rotate_key_passphrase: String,
+    pub rotate_key_error: String,
+    pub is_rotating_key: bool,
+    pub rotated_private_key: Option<StaticSecret>,
+
+    pub show_verify_modal: bool,
+    pub show_key_changed_modal: bool,
+
+    pub show_export_modal: bool,
+    pub export_path: String,
+    pub export_format: ExportFormat,
+    pub export_error: String,
+    pub export_success: String,
+    pub pending_export_chat_id: Option<u64>,
+
+    pub show_import_modal: bool,
+    pub import_path: String,
+    pub import_error: String,
+    pub import_success: String,
 
     pub y_chat_scroll_offset: f32,
     pub should_scroll_down: bool,
 
-    pub message_thread_sender: OnceCell<Sender<String>>,
-    pub message_ui_receiver: OnceCell<Receiver<String>>
-}
+    pub connection_status: ConnectionStatus,
+    pub should_sync_messages: bool,
+    pub pending_sync_pages: Vec<(u64, u64)>,
+    // contacts that announced a new key, checked against the one registered with the server
+    pub pending_key_checks: Vec<u64>,
 
-#[derive(Serialize, Deserialize)]
-struct ChatWithMessages {
-    chat_id: u64
-}
+    pub outbox: Vec<OutboxEntry>,
+    pub resend_message_nonce: Option<[u8; 24]>,
+    pub discard_message_nonce: Option<[u8; 24]>,
 
-#[derive(Serialize, Deserialize)]
-struct FetchContactDataSuccess {
-    contact_id: u64,
-    chat_with_messages: ChatWithMessages
+    pub message_thread_sender: OnceCell<Sender<String>>,
+    pub message_ui_receiver: OnceCell<Receiver<String>>,
+    pub ws_event_receiver: OnceCell<Receiver<WsEvent>>,
+    pub store_thread_sender: OnceCell<Sender<StoreWrite>>,
+    pub store_error_receiver: OnceCell<Receiver<String>>
 }
 
 impl Default for ChatState {
     fn default() -> Self {
         Self {
+            endpoints: ServerEndpoints::default(),
             token: String::new(),
             user_id: 0,
+            email: String::new(),
+            key_store: KeyStoreKind::default(),
             private_key: None,
+            previous_private_keys: Vec::new(),
+            contact_keys: ContactKeys::new(),
+            verified_contacts: VerifiedContacts::new(),
 
             contacts: Vec::new(),
+            left_out_sessions: HashMap::new(),
 
             current_selected_id: 0,
             clicked_contact_id: None,
             is_fetching_messages: false,
 
             modal_error: String::new(),
+            retry_action: None,
+            fetch_messages_error: false,
             typed_message: String::new(),
 
+            search_index: SearchIndex::default(),
+            message_query: String::new(),
+            message_search_results: Vec::new(),
+            jump_to_message: None,
+            highlighted_message_id: None,
+
             show_search_modal: false,
             search_email: String::new(),
             searched_users: Vec::new(),
             should_search: false,
             is_search_loading: false,
+            search_cancellation: None,
 
             clicked_invite_contact_id: None,
             clicked_invite_contact_email: None,
-            is_send_invite_loading: false,
+            sending_invite_ids: Vec::new(),
 
             received_invites: Vec::new(),
             sent_invites: Vec::new(),
             accepted_contact_id: None,
+            accepting_invite_ids: Vec::new(),
 
             show_invites_modal: false,
-            is_loading_accept_invite: false,
+            show_backup_modal: false,
+            backup_path: String::new(),
+            backup_passphrase: String::new(),
+            backup_confirm_passphrase: String::new(),
+            backup_error: String::new(),
+            backup_success: String::new(),
+            show_rotate_key_modal: false,
+            rotate_key_passphrase: String::new(),
+            rotate_key_error: String::new(),
+            is_rotating_key: false,
+            rotated_private_key: None,
+            show_verify_modal: false,
+            show_key_changed_modal: false,
+
+            show_export_modal: false,
+            export_path: String::new(),
+            export_format: ExportFormat::Json,
+            export_error: String::new(),
+            export_success: String::new(),
+            pending_export_chat_id: None,
+
+            show_import_modal: false,
+            import_path: String::new(),
+            import_error: String::new(),
+            import_success: String::new(),
 
             y_chat_scroll_offset: 0.,
             should_scroll_down: false,
 
+            connection_status: ConnectionStatus::Connected,
+            should_sync_messages: false,
+            pending_sync_pages: Vec::new(),
+            pending_key_checks: Vec::new(),
+
+            outbox: Vec::new(),
+            resend_message_nonce: None,
+            discard_message_nonce: None,
+
             message_thread_sender: OnceCell::new(),
-            message_ui_receiver: OnceCell::new()
+            message_ui_receiver: OnceCell::new(),
+            ws_event_receiver: OnceCell::new(),
+            store_thread_sender: OnceCell::new(),
+            store_error_receiver: OnceCell::new()
         }
     }
 }
@@ -131,7 +276,7 @@ impl ChatState {
         result_queue: &mut Vec<Receiver<TaskResult>>,
         ctx: &egui::Context
     ) {
-        if self.modal_error.len() > 0 {
+        if !self.modal_error.is_empty() {
             self.show_error_modal(ctx);
         }
         if self.show_search_modal {
@@ -140,10 +285,44 @@ impl ChatState {
         if self.show_invites_modal {
             self.show_invites_modal(ctx);
         }
-        
+        if self.show_backup_modal {
+            self.show_backup_modal(ctx);
+        }
+        if self.show_rotate_key_modal {
+            self.show_rotate_key_modal(http_thread, result_queue, ctx);
+        }
+        if self.show_verify_modal {
+            self.show_verify_modal(ctx);
+        }
+        if self.show_key_changed_modal {
+            self.show_key_changed_modal(ctx);
+        }
+        if self.show_export_modal {
+            self.show_export_modal(ctx);
+        }
+        if self.show_import_modal {
+            self.show_import_modal(ctx);
+        }
+
         egui::SidePanel::left("left_panel")
             .resizable(false)
             .show(ctx, |ui| {
+                ui.add_space(5.);
+                match &self.connection_status {
+                    ConnectionStatus::Connected => ui.label(
+                        egui::RichText::new("● Online").color(egui::Color32::GREEN)
+                    ),
+                    ConnectionStatus::Reconnecting { attempt, retry_in } => ui.label(
+                        egui::RichText::new(format!(
+                            "● Reconnecting (attempt {}, {}s)",
+                            attempt,
+                            retry_in.as_secs().max(1)
+                        )).color(egui::Color32::YELLOW)
+                    ),
+                    ConnectionStatus::Refused(_) => ui.label(
+                        egui::RichText::new("● Offline, log in again").color(egui::Color32::RED)
+                    )
+                };
                 ui.add_space(5.);
                 ui.horizontal_wrapped(|ui| {
                     if ui.button(format!("Invites ( {} )", self.received_invites.len())).clicked() {
@@ -152,9 +331,77 @@ impl ChatState {
                     if ui.button("Search").clicked() {
                         self.show_search_modal = true;
                     }
+                    if ui.button("Backup key").clicked() {
+                        self.backup_path = home_folder_path().join(DEFAULT_BACKUP_FILE_NAME).display().to_string();
+                        self.backup_error.clear();
+                        self.backup_success.clear();
+                        self.show_backup_modal = true;
+                    }
+                    if ui.button("Rotate key").clicked() {
+                        self.rotate_key_error.clear();
+                        self.show_rotate_key_modal = true;
+                    }
+                    if ui.button("Import chat").clicked() {
+                        self.import_error.clear();
+                        self.import_success.clear();
+                        self.show_import_modal = true;
+                    }
                 });
                 ui.add_space(10.);
 
+                let query_response = ui.add(
+                    egui::TextEdit::singleline(&mut self.message_query)
+                        .hint_text("Search messages")
+                        .desired_width(f32::INFINITY)
+                );
+                if query_response.changed() {
+                    self.search_messages();
+                }
+
+                if !self.message_query.trim().is_empty() {
+                    let mut opened_hit = None;
+
+                    ui.vertical(|ui| {
+                        ui.add_space(5.);
+                        ui.heading(format!("Messages ( {} )", self.message_search_results.len()));
+
+                        ui.add_space(5.);
+                        egui::ScrollArea::vertical().show(ui, |ui| {
+                            ui.with_layout(
+                                egui::Layout::top_down(egui::Align::LEFT).with_cross_justify(true),
+                                |ui| {
+                                    for &(chat_id, message_id) in self.message_search_results.iter() {
+                                        let Some(contact) = self.contacts.iter().find(|c| c.contact.chat_id == chat_id) else {
+                                            continue;
+                                        };
+                                        // older pages may not be loaded yet, they're fetched when opened
+                                        let preview = contact.messages.iter()
+                                            .find(|m| m._id == Some(message_id))
+                                            .map_or("Older message", |m| m.content.as_str());
+
+                                        let selected = self.highlighted_message_id == Some(message_id);
+                                        let response = ui.add_enabled(
+                                            !self.is_fetching_messages,
+                                            egui::Button::selectable(
+                                                selected,
+                                                format!("{}: {}", contact.contact.contact_email, preview)
+                                            ).wrap_mode(egui::TextWrapMode::Truncate)
+                                        );
+                                        if response.clicked() {
+                                            opened_hit = Some((chat_id, message_id));
+                                        }
+                                    }
+                                }
+                            );
+                        });
+                    });
+
+                    if let Some(hit) = opened_hit {
+                        self.open_search_hit(hit);
+                    }
+                    return;
+                }
+
                 ui.vertical(|ui| {
                     ui.add_space(5.);
                     ui.heading("Contacts");
@@ -168,16 +415,26 @@ impl ChatState {
                                     egui::Frame::NONE
                                         .show(ui, |ui| {
                                             let selected = self.current_selected_id == contact.contact.contact_id;
+                                            let label = match verification_status(&self.verified_contacts, contact) {
+                                                VerificationStatus::Unverified => egui::RichText::new(contact.contact.contact_email.clone()),
+                                                VerificationStatus::Verified => egui::RichText::new(
+                                                    format!("✔ {}", contact.contact.contact_email)
+                                                ),
+                                                VerificationStatus::KeyChanged => egui::RichText::new(
+                                                    format!("⚠ {}", contact.contact.contact_email)
+                                                ).color(egui::Color32::RED)
+                                            };
                                             let response = ui.add_enabled(
                                                 !self.is_fetching_messages,
-                                                egui::Button::selectable(
-                                                    selected,
-                                                    egui::RichText::new(contact.contact.contact_email.clone())
-                                                ).wrap_mode(egui::TextWrapMode::Truncate)
+                                                egui::Button::selectable(selected, label)
+                                                    .wrap_mode(egui::TextWrapMode::Truncate)
                                             );
                                             if response.clicked() {
+                                                self.fetch_messages_error = false;
                                                 self.clicked_contact_id = Some(contact.contact.contact_id);
                                                 self.current_selected_id = contact.contact.contact_id;
+                                                self.jump_to_message = None;
+                                                self.highlighted_message_id = None;
                                             }
                                         });
                                 }
@@ -216,25 +473,91 @@ impl ChatState {
         egui::CentralPanel::default().show(ctx, |ui| {
             let mut scroll_area = egui::ScrollArea::vertical().stick_to_bottom(true);
 
+            // a pending jump scrolls to its message instead
             if self.should_scroll_down {
                 self.should_scroll_down = false;
-                scroll_area = scroll_area.vertical_scroll_offset(100.);
+                if self.jump_to_message.is_none() {
+                    scroll_area = scroll_area.vertical_scroll_offset(100.);
+                }
             }
 
+            let mut resend_message_nonce = None;
+            let mut discard_message_nonce = None;
+            let mut open_verify_modal = false;
+            let mut open_export_modal = false;
+            let mut reached_jump_target = false;
+
             let scroll_output = scroll_area.show(ui, |ui| {
                 ui.heading("Chat");
 
                 let user_id = self.user_id;
+                let highlighted_message_id = self.highlighted_message_id;
+                let jump_to_message = self.jump_to_message;
                 if let Some(contact) = self.get_selected_contact() {
+                    let jump_message_id = jump_to_message
+                        .filter(|(chat_id, _)| *chat_id == contact.contact.chat_id)
+                        .map(|(_, message_id)| message_id);
+                    let status = verification_status(&self.verified_contacts, contact);
+                    ui.horizontal(|ui| {
+                        ui.label(&contact.contact.contact_email);
+                        match status {
+                            VerificationStatus::Unverified if contact.key_changed => ui.label(
+                                egui::RichText::new("⚠ Key changed, not verified").color(egui::Color32::YELLOW)
+                            ),
+                            VerificationStatus::Unverified => ui.label(egui::RichText::new("Not verified").weak()),
+                            VerificationStatus::Verified => ui.label(
+                                egui::RichText::new("✔ Verified").color(egui::Color32::GREEN)
+                            ),
+                            VerificationStatus::KeyChanged => ui.label(
+                                egui::RichText::new("⚠ Key changed").color(egui::Color32::RED)
+                            )
+                        };
+                        if ui.small_button("Safety number").clicked() {
+                            open_verify_modal = true;
+                        }
+                        if ui.small_button("Export").clicked() {
+                            open_export_modal = true;
+                        }
+                    });
+                    if status == VerificationStatus::KeyChanged {
+                        egui::Frame::NONE
+                            .fill(egui::Color32::from_rgb(90, 10, 10))
+                            .corner_radius(2.)
+                            .inner_margin(8.)
+                            .show(ui, |ui| {
+                                ui.label(
+                                    egui::RichText::new(
+                                        "The encryption key of this contact changed since you verified it. \
+                                        Compare safety numbers again before trusting this conversation."
+                                    ).color(egui::Color32::WHITE)
+                                );
+                            });
+                    }
+                    ui.separator();
+
                     if self.is_fetching_messages {
                         ui.vertical_centered(|ui| {
                             ui.spinner();
                         });
                     }
 
+                    let now = Utc::now();
+                    let today = local_date(now);
+                    let mut previous_date = None;
+
                     for message in &contact.messages {
                         let is_sender = message.sender_id == user_id;
 
+                        if let Some(sent_at) = message.sent_at {
+                            let date = local_date(sent_at);
+                            if previous_date != Some(date) {
+                                previous_date = Some(date);
+                                ui.vertical_centered(|ui| {
+                                    ui.label(egui::RichText::new(day_separator(date, today)).small().weak());
+                                });
+                            }
+                        }
+
                         ui.with_layout(
                             if is_sender {
                             egui::Layout::right_to_left(egui::Align::Min)
@@ -242,21 +565,71 @@ impl ChatState {
                             egui::Layout::left_to_right(egui::Align::Min)
                         },
                         |ui| {
-                            egui::Frame::NONE
+                            let is_highlighted = message._id.is_some() && message._id == highlighted_message_id;
+                            let frame_response = egui::Frame::NONE
                                 .fill(if is_sender {
                                     egui::Color32::from_rgb(0, 93, 128)
                                 } else {
                                     egui::Color32::from_rgb(18, 19, 18)
                                 })
+                                .stroke(if is_highlighted {
+                                    egui::Stroke::new(2., egui::Color32::YELLOW)
+                                } else {
+                                    egui::Stroke::NONE
+                                })
                                 .corner_radius(2.)
                                 .inner_margin(8.)
                                 .show(ui, |ui| {
                                     ui.set_max_width(ui.available_width() * 0.6);
-                                    let label = egui::Label::new(
-                                        egui::RichText::new(&message.content).size(15.)
-                                    ).wrap().halign(egui::Align::LEFT);
-                                    ui.add(label);
+                                    match &message.decrypt_error {
+                                        None => {
+                                            let label = egui::Label::new(
+                                                egui::RichText::new(&message.content).size(15.)
+                                            ).wrap().halign(egui::Align::LEFT);
+                                            ui.add(label);
+                                        },
+                                        Some(e) => {
+                                            ui.label(
+                                                egui::RichText::new("🔒 Unable to decrypt this message")
+                                                    .size(15.)
+                                                    .italics()
+                                                    .weak()
+                                            ).on_hover_text(e.to_string());
+                                        }
+                                    }
+
+                                    if let Some(sent_at) = message.sent_at {
+                                        ui.label(egui::RichText::new(message_time(sent_at, now)).small().weak())
+                                            .on_hover_text(full_time(sent_at));
+                                    }
+
+                                    match message.status {
+                                        MessageStatus::Sent => {},
+                                        MessageStatus::Pending => {
+                                            ui.label(egui::RichText::new("Sending...").small().weak());
+                                        },
+                                        MessageStatus::Failed => {
+                                            ui.horizontal(|ui| {
+                                                ui.label(
+                                                    egui::RichText::new("Not sent")
+                                                        .small()
+                                                        .color(egui::Color32::RED)
+                                                );
+                                                if ui.small_button("Resend").clicked() {
+                                                    resend_message_nonce = message.nonce;
+                                                }
+                                                if ui.small_button("Discard").clicked() {
+                                                    discard_message_nonce = message.nonce;
+                                                }
+                                            });
+                                        }
+                                    }
                                 });
+
+                            if jump_message_id.is_some() && message._id == jump_message_id {
+                                frame_response.response.scroll_to_me(Some(egui::Align::Center));
+                                reached_jump_target = true;
+                            }
                         });
                     }
                 } else {
@@ -269,11 +642,26 @@ impl ChatState {
             });
 
             self.y_chat_scroll_offset = scroll_output.state.offset.y;
+            self.resend_message_nonce = resend_message_nonce;
+            self.discard_message_nonce = discard_message_nonce;
+            if open_verify_modal {
+                self.show_verify_modal = true;
+            }
+            if open_export_modal {
+                self.open_export_modal();
+            }
+            if reached_jump_target {
+                self.jump_to_message = None;
+            }
         });
 
 
+        self.handle_ws_events();
+        self.handle_store_errors();
         self.handle_user_interaction(http_thread, result_queue);
         self.handle_messages(ctx);
+        // relative times keep moving without any input
+        ctx.request_repaint_after(Duration::from_secs(30));
     }
 
     fn show_error_modal(&mut self, ctx: &egui::Context) {
@@ -282,12 +670,42 @@ impl ChatState {
                 egui::RichText::new(self.modal_error.to_owned())
                     .color(egui::Color32::RED)
             );
-            if ui.button("Ok").clicked() {
-                self.modal_error.clear();
-            }
+            ui.horizontal(|ui| {
+                if ui.button("Ok").clicked() {
+                    self.modal_error.clear();
+                    self.retry_action = None;
+                }
+                if self.retry_action.is_some() && ui.button("Retry").clicked() {
+                    self.modal_error.clear();
+                    self.retry();
+                }
+            });
         });
     }
 
+    fn retry(&mut self) {
+        match self.retry_action.take() {
+            Some(RetryAction::SearchUser) => self.should_search = true,
+            Some(RetryAction::SendInvite(receiver_id, receiver_email)) => {
+                self.clicked_invite_contact_id = Some(receiver_id);
+                self.clicked_invite_contact_email = Some(receiver_email);
+            },
+            Some(RetryAction::AcceptInvite(contact_id)) => self.accepted_contact_id = Some(contact_id),
+            Some(RetryAction::FetchMessages) => {
+                self.fetch_messages_error = false;
+                self.clicked_contact_id = Some(self.current_selected_id);
+            },
+            Some(RetryAction::SyncMessages) => self.should_sync_messages = true,
+            Some(RetryAction::FetchPublicKey(contact_id)) => self.pending_key_checks.push(contact_id),
+            None => {}
+        }
+    }
+
+    fn handle_task_failure(&mut self, error: TaskError, retry_action: RetryAction) {
+        self.retry_action = error.is_retryable().then_some(retry_action);
+        self.modal_error = error.to_string();
+    }
+
     fn show_search_modal(&mut self, ctx: &egui::Context) {
         egui::Modal::new(egui::Id::new("modal_search")).show(ctx, |ui| {
             ui.set_width(250.);
@@ -296,10 +714,8 @@ impl ChatState {
                     egui::TextEdit::singleline(&mut self.search_email)
                         .hint_text("Search user by e-mail")
                 );
-                if ui.button("Search").clicked() {
-                    if self.search_email.len() > 0 {
-                        self.should_search = true;
-                    }
+                if ui.button("Search").clicked() && !self.search_email.is_empty() {
+                    self.should_search = true;
                 }
             });
 
@@ -307,7 +723,7 @@ impl ChatState {
             egui::ScrollArea::vertical().show(ui, |ui| {
                 ui.set_height(100.);
 
-                if self.is_search_loading || self.is_send_invite_loading || self.is_loading_accept_invite {
+                if self.is_search_loading {
                     ui.vertical_centered(|ui| {
                         ui.spinner();
                     });
@@ -323,13 +739,16 @@ impl ChatState {
                         ui.label(user.email.clone());
 
                         ui.with_layout(egui::Layout::right_to_left(egui::Align::Max), |ui| {
-                            if self.sent_invites.iter().find(|i| i.receiver_id == user.id).is_some() {
+                            if self.sent_invites.iter().any(|i| i.receiver_id == user.id) {
                                 ui.add_enabled(false, egui::Button::new("Sent"));
+                            } else if self.sending_invite_ids.contains(&user.id) {
+                                ui.add_enabled(false, egui::Button::new("Sending..."));
                             } else if let Some(invite) = self.received_invites.iter().find(|i| i.sender_id == user.id) {
-                                if ui.button("Accept").clicked() {
+                                let is_accepting = self.accepting_invite_ids.contains(&invite.id);
+                                if ui.add_enabled(!is_accepting, egui::Button::new("Accept")).clicked() {
                                     self.accepted_contact_id = Some(invite.id);
                                 }
-                            } else if let Some(_) = self.contacts.iter().find(|i| i.contact.contact_id == user.id) {
+                            } else if self.contacts.iter().any(|i| i.contact.contact_id == user.id) {
                                 ui.add_enabled(false, egui::Button::new("Added"));
                             } else {
                                 if ui.button("Add").clicked() {
@@ -347,6 +766,10 @@ impl ChatState {
 
             if ui.button("Close").clicked() {
                 self.show_search_modal = false;
+                if let Some(search_cancellation) = self.search_cancellation.take() {
+                    search_cancellation.cancel();
+                    self.is_search_loading = false;
+                }
             }
         });
     }
@@ -368,7 +791,8 @@ impl ChatState {
                         ui.label(invite.sender_email.clone());
 
                         ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
-                            if ui.button("Accept").clicked() {
+                            let is_accepting = self.accepting_invite_ids.contains(&invite.id);
+                            if ui.add_enabled(!is_accepting, egui::Button::new("Accept")).clicked() {
                                 self.accepted_contact_id = Some(invite.id);
                             }
                         });
@@ -385,359 +809,1842 @@ impl ChatState {
         });
     }
 
-    fn handle_user_interaction(
-        &mut self,
-        http_thread: &Sender<TaskWrapper>,
-        result_queue: &mut Vec<Receiver<TaskResult>>
-    ) {
-        if self.clicked_contact_id.is_some() {
-            if self.fetch_messages(http_thread, result_queue) {
-                self.is_fetching_messages = true;
-            }
-        }
-        if self.should_search {
-            self.search_user(http_thread, result_queue);
+    fn show_backup_modal(&mut self, ctx: &egui::Context) {
+        egui::Modal::new(egui::Id::new("modal_backup")).show(ctx, |ui| {
+            ui.set_width(300.);
+            ui.label(egui::RichText::new("Backup private key").size(16.));
+            ui.add_space(8.);
+            ui.label("Keep this file somewhere safe, it's needed to read your chats on another computer.");
+
+            ui.label("File");
+            ui.add(
+                egui::TextEdit::singleline(&mut self.backup_path)
+                    .desired_width(f32::INFINITY)
+            );
+            ui.add(
+                egui::TextEdit::singleline(&mut self.backup_passphrase)
+                    .hint_text("Backup passphrase")
+                    .password(true)
+            );
+            ui.add(
+                egui::TextEdit::singleline(&mut self.backup_confirm_passphrase)
+                    .hint_text("Confirm backup passphrase")
+                    .password(true)
+            );
+
+            ui.label(
+                egui::RichText::new(self.backup_success.to_owned())
+                    .color(egui::Color32::GREEN)
+            );
+            ui.label(
+                egui::RichText::new(self.backup_error.to_owned())
+                    .color(egui::Color32::RED)
+            );
+            ui.separator();
+
+            ui.horizontal(|ui| {
+                if ui.button("Export").clicked() {
+                    self.export_backup();
+                }
+                if ui.button("Close").clicked() {
+                    self.backup_passphrase.clear();
+                    self.backup_confirm_passphrase.clear();
+                    self.show_backup_modal = false;
+                }
+            });
+        });
+    }
+
+    fn export_backup(&mut self) {
+        self.backup_error.clear();
+        self.backup_success.clear();
+
+        if self.backup_passphrase.len() < MIN_PASSPHRASE_LENGTH {
+            self.backup_error = format!("The passphrase must have at least {} characters", MIN_PASSPHRASE_LENGTH);
+            return;
         }
-        if self.clicked_invite_contact_id.is_some() {
-            self.send_invite(http_thread, result_queue);
+        if self.backup_passphrase != self.backup_confirm_passphrase {
+            self.backup_error = "The passphrases are not equal".to_owned();
+            return;
         }
-        if self.accepted_contact_id.is_some() {
-            self.accept_invite(http_thread, result_queue);
+
+        match export_private_key(Path::new(self.backup_path.trim()), &self.private_key_set(), &self.backup_passphrase) {
+            Ok(()) => {
+                self.backup_passphrase.clear();
+                self.backup_confirm_passphrase.clear();
+                self.backup_success = format!("Backup saved to {}", self.backup_path.trim());
+            },
+            Err(e) => self.backup_error = format!("Couldn't save backup: {}", e)
         }
     }
 
-    fn search_user(
-        &mut self,
-        http_thread: &Sender<TaskWrapper>,
-        result_queue: &mut Vec<Receiver<TaskResult>>
-    ) {
-        self.should_search = false;
-        self.is_search_loading = true;
-
-        let search_user_task = SearchUserTask::new(
-            self.search_email.clone(),
-            self.token.clone()
-        );
-        let (task_wrapper, task_channel_receiver) = TaskWrapper::new(Box::new(search_user_task));
+    fn open_export_modal(&mut self) {
+        let Some(contact) = self.get_selected_contact() else {
+            return;
+        };
 
-        http_thread.send(task_wrapper).unwrap();
-        result_queue.push(task_channel_receiver);
+        self.export_path = home_folder_path()
+            .join(format!("{}_chat.{}", contact.contact.contact_email, self.export_format.extension()))
+            .display()
+            .to_string();
+        self.export_error.clear();
+        self.export_success.clear();
+        self.show_export_modal = true;
     }
 
-    fn send_invite(
-        &mut self,
-        http_thread: &Sender<TaskWrapper>,
-        result_queue: &mut Vec<Receiver<TaskResult>>
-    ) {
-        self.is_send_invite_loading = true;
+    fn show_export_modal(&mut self, ctx: &egui::Context) {
+        let Some(contact) = self.get_selected_contact() else {
+            self.show_export_modal = false;
+            return;
+        };
+        let chat_id = contact.contact.chat_id;
+        let contact_email = contact.contact.contact_email.clone();
 
-        let receiver_id = self.clicked_invite_contact_id.take().unwrap();
-        let receiver_email = self.clicked_invite_contact_email.take().unwrap();
+        egui::Modal::new(egui::Id::new("modal_export")).show(ctx, |ui| {
+            ui.set_width(300.);
+            ui.label(egui::RichText::new(format!("Export chat with {}", contact_email)).size(16.));
+            ui.add_space(8.);
+            ui.label("The whole history is downloaded and saved decrypted, anyone with the file can read it.");
 
-        let send_invite_contact_task = SendInviteContactTask::new(
-            self.user_id,
-            receiver_id,
-            receiver_email,
-            self.token.clone()
-        );
-        let (task_wrapper, task_channel_receiver) = TaskWrapper::new(Box::new(send_invite_contact_task));
+            let previous_format = self.export_format;
+            ui.horizontal(|ui| {
+                ui.radio_value(&mut self.export_format, ExportFormat::Json, "JSON");
+                ui.radio_value(&mut self.export_format, ExportFormat::Text, "Text");
+                ui.radio_value(&mut self.export_format, ExportFormat::Html, "HTML");
+            });
+            if self.export_format != previous_format {
+                self.export_path = Path::new(self.export_path.trim())
+                    .with_extension(self.export_format.extension())
+                    .display()
+                    .to_string();
+            }
 
-        http_thread.send(task_wrapper).unwrap();
-        result_queue.push(task_channel_receiver);
+            ui.label("File");
+            ui.add(
+                egui::TextEdit::singleline(&mut self.export_path)
+                    .desired_width(f32::INFINITY)
+            );
+
+            let is_exporting = self.pending_export_chat_id.is_some();
+            if is_exporting {
+                ui.horizontal(|ui| {
+                    ui.spinner();
+                    ui.label("Loading the full history...");
+                });
+            }
+            ui.label(
+                egui::RichText::new(self.export_success.to_owned())
+                    .color(egui::Color32::GREEN)
+            );
+            ui.label(
+                egui::RichText::new(self.export_error.to_owned())
+                    .color(egui::Color32::RED)
+            );
+            ui.separator();
+
+            ui.horizontal(|ui| {
+                if ui.add_enabled(!is_exporting, egui::Button::new("Export")).clicked() {
+                    self.export_error.clear();
+                    self.export_success.clear();
+                    self.fetch_messages_error = false;
+                    self.pending_export_chat_id = Some(chat_id);
+                }
+                if ui.button("Close").clicked() {
+                    self.pending_export_chat_id = None;
+                    self.show_export_modal = false;
+                }
+            });
+        });
     }
 
-    fn accept_invite(
+    // pages back through the history until the server has nothing older, then writes the file
+    fn continue_export(
         &mut self,
         http_thread: &Sender<TaskWrapper>,
         result_queue: &mut Vec<Receiver<TaskResult>>
     ) {
-        self.is_loading_accept_invite = true;
-
-        let contact_id = self.accepted_contact_id.take().unwrap();
-
-        let accept_invite_contact_task = AcceptInviteContactTask::new(
-            contact_id,
-            self.token.clone()
-        );
-        let (task_wrapper, task_channel_receiver) = TaskWrapper::new(Box::new(accept_invite_contact_task));
+        let Some(chat_id) = self.pending_export_chat_id else {
+            return;
+        };
+        if self.is_fetching_messages {
+            return;
+        }
+        let Some(contact) = self.contacts.iter().find(|c| c.contact.chat_id == chat_id) else {
+            self.pending_export_chat_id = None;
+            return;
+        };
+        if self.fetch_messages_error {
+            self.pending_export_chat_id = None;
+            self.export_error = "Couldn't download the full history".to_owned();
+            return;
+        }
+        if contact.should_fetch_messages {
+            let offset = contact.server_offset();
+            self.send_fetch_messages_task(chat_id, offset, http_thread, result_queue);
+            self.is_fetching_messages = true;
+            return;
+        }
 
-        http_thread.send(task_wrapper).unwrap();
-        result_queue.push(task_channel_receiver);
+        self.pending_export_chat_id = None;
+        self.export_chat(chat_id);
     }
 
-    fn fetch_messages(
-        &mut self,
-        http_thread: &Sender<TaskWrapper>,
-        result_queue: &mut Vec<Receiver<TaskResult>>
-    ) -> bool {
-        if let Some(c) = self.get_selected_contact() {
-            let offset = c.messages.len();
-            if self.is_fetching_messages == false && c.should_fetch_messages && (offset <= 20 || self.y_chat_scroll_offset == 0.) {
-                let fetch_chat_message_task = FetchChatMessagesTask::new(
-                    c.contact.chat_id,
-                    offset as u64,
-                    self.token.clone()
-                );
-                let (task_wrapper, task_channel_receiver) = TaskWrapper::new(
-                    Box::new(fetch_chat_message_task)
-                );
+    fn export_chat(&mut self, chat_id: u64) {
+        let Some(contact) = self.contacts.iter().find(|c| c.contact.chat_id == chat_id) else {
+            return;
+        };
 
-                http_thread.send(task_wrapper).unwrap();
-                result_queue.push(task_channel_receiver);
+        // messages still waiting in the outbox have no id yet and are left out
+        let messages = contact.messages.iter()
+            .filter_map(|m| Some(ExportedMessage {
+                id: m._id?,
+                sender_id: m.sender_id,
+                sender_email: if m.sender_id == self.user_id {
+                    self.email.clone()
+                } else {
+                    contact.contact.contact_email.clone()
+                },
+                content: m.decrypt_error.is_none().then(|| m.content.clone()),
+                decrypt_error: m.decrypt_error.as_ref().map(|e| e.to_string()),
+                sent_at: m.sent_at
+            }))
+            .collect();
+        let chat_export = ChatExport {
+            chat_id,
+            user_id: self.user_id,
+            user_email: self.email.clone(),
+            contact_id: contact.contact.contact_id,
+            contact_email: contact.contact.contact_email.clone(),
+            messages
+        };
 
-                true
-            } else {
-                false
-            }
-        } else {
-            false
+        match write_chat_export(Path::new(self.export_path.trim()), self.export_format, &chat_export) {
+            Ok(()) => self.export_success = format!(
+                "{} messages saved to {}",
+                chat_export.messages.len(),
+                self.export_path.trim()
+            ),
+            Err(e) => self.export_error = format!("Couldn't save export: {}", e)
         }
     }
 
-    fn handle_messages(&mut self, ctx: &egui::Context) {
-        // maybe decrypt actions should be outside main thread?
-        let message_ui_receiver = self.message_ui_receiver.get().unwrap();
-        if let Ok(msg) = message_ui_receiver.try_recv() {
-            let parsed_msg: Value = serde_json::from_str(&msg).unwrap();
-            if parsed_msg["type"] == MessageType::Content.as_str() {
-                self.handle_content_message(ctx, msg);
-            } else if parsed_msg["type"] == MessageType::Invite.as_str() {
-                self.handle_invite_message(msg);
-            } else if parsed_msg["type"] == MessageType::InviteAccepted.as_str() {
-                self.handle_accept_invite_message(msg);
-            }
-        }
-    }
+    fn show_import_modal(&mut self, ctx: &egui::Context) {
+        egui::Modal::new(egui::Id::new("modal_import")).show(ctx, |ui| {
+            ui.set_width(300.);
+            ui.label(egui::RichText::new("Import chat").size(16.));
+            ui.add_space(8.);
+            ui.label("Restores messages from a JSON export of one of your chats, the ones already here are kept.");
 
-    fn handle_content_message(&mut self, ctx: &egui::Context, msg: String) {
-        let received_message: ContentMessageWrapper = serde_json::from_str(&msg).unwrap();
-        let contact = self.contacts.iter_mut().find(|c| c.contact.contact_id == received_message.message.user_id).unwrap();
-
-        let raw_nonce: [u8; 24] = BASE64_STANDARD
-            .decode(received_message.message.nonce)
-            .unwrap()
-            .try_into()
-            .unwrap();
-        let content: Vec<u8> = BASE64_STANDARD
-            .decode(received_message.message.content)
-            .unwrap()
-            .try_into()
-            .unwrap();
-
-        let nonce: XNonce = *GenericArray::from_slice(&raw_nonce);
-        let decrypted_message_bytes = contact.cipher.decrypt(&nonce, content.as_ref()).unwrap();
+            ui.label("File");
+            ui.add(
+                egui::TextEdit::singleline(&mut self.import_path)
+                    .hint_text("Path to a .json export")
+                    .desired_width(f32::INFINITY)
+            );
 
-        contact.messages.push_back(
-            Message {
-                _id: Some(received_message.message.id),
-                sender_id: received_message.message.user_id,
-                content: String::from_utf8(decrypted_message_bytes).unwrap()
-            }
-        );
-        ctx.request_repaint();
-    }
+            ui.label(
+                egui::RichText::new(self.import_success.to_owned())
+                    .color(egui::Color32::GREEN)
+            );
+            ui.label(
+                egui::RichText::new(self.import_error.to_owned())
+                    .color(egui::Color32::RED)
+            );
+            ui.separator();
 
-    fn handle_invite_message(&mut self, msg: String) {
-        self.received_invites.push(serde_json::from_str(&msg).unwrap());
+            ui.horizontal(|ui| {
+                if ui.button("Import").clicked() {
+                    self.import_chat();
+                }
+                if ui.button("Close").clicked() {
+                    self.show_import_modal = false;
+                }
+            });
+        });
     }
 
-    fn handle_accept_invite_message(&mut self, msg: String) {
-        let parsed_msg: AcceptInvite = serde_json::from_str(&msg).unwrap();
-        let private_key = self.private_key.clone().unwrap();
+    fn import_chat(&mut self) {
+        self.import_error.clear();
+        self.import_success.clear();
 
-        let contact_public_key_bytes: [u8; 32] = BASE64_STANDARD
-            .decode(parsed_msg.contact.contact_public_key.clone())
-            .unwrap()
-            .try_into()
-            .unwrap();
-        let contact_public_key = PublicKey::from(contact_public_key_bytes);
-        let cipher = generate_cipher(contact_public_key, private_key);
+        let chat_export = match read_chat_export(Path::new(self.import_path.trim())) {
+            Ok(chat_export) => chat_export,
+            Err(e) => {
+                self.import_error = format!("Couldn't read export: {}", e);
+                return;
+            }
+        };
 
-        self.sent_invites.retain(|i| i.id != parsed_msg.contact.id);
-        self.contacts.push(
-            ContactInfo {
-                contact: parsed_msg.contact,
-                cipher,
-                should_fetch_messages: true,
-                messages: LinkedList::new()
+        let contact_index = match export_contact_index(&self.contacts, self.user_id, &chat_export) {
+            Ok(contact_index) => contact_index,
+            Err(e) => {
+                self.import_error = e.to_owned();
+                return;
             }
-        );
-    }
+        };
 
-    fn get_mut_selected_contact(&mut self) -> Option<&mut ContactInfo> {
-        self.contacts.iter_mut()
-            .find(
-                |contact_info|
-                contact_info.contact.contact_id == self.current_selected_id
-            )
-    }
+        // imported messages don't move the paging offset, history paging picks them up as it reaches them
+        let imported_count = merge_imported_messages(&mut self.contacts[contact_index], chat_export.messages);
 
-    fn get_selected_contact(&self) -> Option<&ContactInfo> {
-        self.contacts.iter()
-            .find(
-                |contact_info|
-                contact_info.contact.contact_id == self.current_selected_id
-            )
+        self.persist_messages();
+        self.import_success = format!("{} messages restored from {}", imported_count, self.import_path.trim());
+    }
+
+    fn show_rotate_key_modal(
+        &mut self,
+        http_thread: &Sender<TaskWrapper>,
+        result_queue: &mut Vec<Receiver<TaskResult>>,
+        ctx: &egui::Context
+    ) {
+        egui::Modal::new(egui::Id::new("modal_rotate_key")).show(ctx, |ui| {
+            ui.set_width(300.);
+            ui.label(egui::RichText::new("Rotate private key").size(16.));
+            ui.add_space(8.);
+            ui.label("A new keypair is generated and announced to your contacts. Old keys are kept on this device so your history still decrypts. Export a new backup afterwards.");
+
+            ui.add(
+                egui::TextEdit::singleline(&mut self.rotate_key_passphrase)
+                    .hint_text("Key passphrase")
+                    .password(true)
+            );
+            ui.label(
+                egui::RichText::new(self.rotate_key_error.to_owned())
+                    .color(egui::Color32::RED)
+            );
+            ui.separator();
+
+            if self.is_rotating_key {
+                ui.spinner();
+                return;
+            }
+            ui.horizontal(|ui| {
+                if ui.button("Rotate").clicked() && !self.rotate_key_passphrase.is_empty() {
+                    self.rotate_key(http_thread, result_queue);
+                }
+                if ui.button("Close").clicked() {
+                    self.rotate_key_passphrase.clear();
+                    self.show_rotate_key_modal = false;
+                }
+            });
+        });
+    }
+
+    // the new key is stored first; if the upload fails the next login picks whichever
+    // stored key the server still has
+    fn rotate_key(
+        &mut self,
+        http_thread: &Sender<TaskWrapper>,
+        result_queue: &mut Vec<Receiver<TaskResult>>
+    ) {
+        self.rotate_key_error.clear();
+
+        let key_store = self.key_store.open();
+        let mut private_keys = match get_private_key(&*key_store, &self.email, &self.rotate_key_passphrase) {
+            Ok(private_keys) => private_keys,
+            Err(e) => {
+                self.rotate_key_error = e.to_string();
+                return;
+            }
+        };
+
+        let (private_key, public_key) = generate_assymetric_keypair();
+        private_keys.rotate(private_key.to_bytes());
+        if let Err(e) = save_private_key(&*key_store, &self.email, &private_keys, &self.rotate_key_passphrase) {
+            self.rotate_key_error = format!("Couldn't store the new key: {}", e);
+            return;
+        }
+
+        self.is_rotating_key = true;
+        self.rotated_private_key = Some(private_key);
+
+        let update_public_key_task = UpdatePublicKeyTask::new(*public_key.as_bytes(), self.token.clone());
+        let (task_wrapper, task_channel_receiver) = TaskWrapper::new(Box::new(update_public_key_task));
+
+        http_thread.send(task_wrapper).unwrap();
+        result_queue.push(task_channel_receiver);
+    }
+
+    pub fn handle_task_update_public_key(&mut self, result: Result<GenericResultError, TaskError>) {
+        self.is_rotating_key = false;
+        let private_key = self.rotated_private_key.take().unwrap();
+
+        if let Err(e) = result {
+            self.rotate_key_error = e.to_string();
+            return;
+        }
+
+        let old_private_key = self.private_key.replace(private_key.clone()).unwrap();
+        self.previous_private_keys.insert(0, old_private_key);
+        for contact in self.contacts.iter_mut() {
+            contact.rotate_private_key(private_key.clone());
+        }
+        self.persist_sessions();
+        self.persist_messages();
+
+        self.announce_key_rotation(&PublicKey::from(&private_key));
+        self.rotate_key_passphrase.clear();
+        self.show_rotate_key_modal = false;
+    }
+
+    // best effort: contacts that miss it get the new key from the server on their next login
+    fn announce_key_rotation(&self, public_key: &PublicKey) {
+        let Some(message_thread_sender) = self.message_thread_sender.get() else {
+            return;
+        };
+
+        for contact in self.contacts.iter() {
+            let key_rotated_message = KeyRotatedMessage {
+                sender_id: self.user_id,
+                receiver_id: contact.contact.contact_id,
+                target_id: contact.contact.contact_id,
+                receiver_email: contact.contact.contact_email.clone(),
+                r#type: MessageType::KeyRotated,
+                public_key: BASE64_STANDARD.encode(public_key.as_bytes())
+            };
+
+            let _ = message_thread_sender.send(serde_json::to_string(&key_rotated_message).unwrap());
+        }
+    }
+
+    fn show_verify_modal(&mut self, ctx: &egui::Context) {
+        let Some(contact) = self.get_selected_contact() else {
+            self.show_verify_modal = false;
+            return;
+        };
+        let own_public_key = PublicKey::from(self.private_key.as_ref().unwrap());
+        let contact_public_key = contact.public_key;
+        let contact_id = contact.contact.contact_id;
+        let contact_email = contact.contact.contact_email.clone();
+        let status = verification_status(&self.verified_contacts, contact);
+        let safety_number = safety_number(&own_public_key, &contact_public_key);
+        let safety_grid = safety_grid(&own_public_key, &contact_public_key);
+
+        egui::Modal::new(egui::Id::new("modal_verify")).show(ctx, |ui| {
+            ui.set_width(360.);
+            ui.label(egui::RichText::new(format!("Safety number with {}", contact_email)).size(16.));
+            ui.add_space(8.);
+            ui.label("Compare these numbers, or the pattern below, with your contact in person or over a channel you trust. If they match on both devices nobody is intercepting your messages.");
+            ui.add_space(8.);
+
+            for row in safety_number.chunks(4) {
+                ui.label(egui::RichText::new(row.join("  ")).monospace().size(16.));
+            }
+            ui.add_space(8.);
+            ui.label(egui::RichText::new(safety_grid).monospace().size(7.));
+            ui.add_space(8.);
+
+            match status {
+                VerificationStatus::Unverified => ui.label("You haven't verified this contact."),
+                VerificationStatus::Verified => ui.label(
+                    egui::RichText::new("✔ You verified this contact.").color(egui::Color32::GREEN)
+                ),
+                VerificationStatus::KeyChanged => ui.label(
+                    egui::RichText::new("⚠ This contact's key changed since you verified it.")
+                        .color(egui::Color32::RED)
+                )
+            };
+            ui.separator();
+
+            ui.horizontal(|ui| {
+                if status == VerificationStatus::Verified {
+                    if ui.button("Clear verification").clicked() {
+                        self.set_contact_verified(contact_id, false);
+                    }
+                } else if ui.button("Mark as verified").clicked() {
+                    self.set_contact_verified(contact_id, true);
+                }
+                if ui.button("Close").clicked() {
+                    self.show_verify_modal = false;
+                }
+            });
+        });
+    }
+
+    fn show_key_changed_modal(&mut self, ctx: &egui::Context) {
+        let changed_contacts: Vec<String> = self.contacts.iter()
+            .filter(|c| verification_status(&self.verified_contacts, c) == VerificationStatus::KeyChanged)
+            .map(|c| c.contact.contact_email.clone())
+            .collect();
+
+        egui::Modal::new(egui::Id::new("modal_key_changed")).show(ctx, |ui| {
+            ui.set_width(300.);
+            ui.label(
+                egui::RichText::new("⚠ Verified keys changed")
+                    .size(16.)
+                    .color(egui::Color32::RED)
+            );
+            ui.add_space(8.);
+            ui.label("The encryption key of these verified contacts is different from the one you checked. They may have reinstalled or rotated their key, but it can also mean someone is intercepting your messages.");
+            ui.add_space(8.);
+            for email in changed_contacts {
+                ui.label(egui::RichText::new(email).strong());
+            }
+            ui.add_space(8.);
+            ui.label("Compare safety numbers again before trusting these conversations.");
+            ui.separator();
+
+            if ui.button("Close").clicked() {
+                self.show_key_changed_modal = false;
+            }
+        });
+    }
+
+    // verifying records the contact's current key, so any later change shows up as a warning
+    fn set_contact_verified(&mut self, contact_id: u64, is_verified: bool) {
+        if is_verified {
+            let Some(contact) = self.contacts.iter().find(|c| c.contact.contact_id == contact_id) else {
+                return;
+            };
+            self.verified_contacts.insert(contact_id, contact.contact.contact_public_key.clone());
+            if let Some(contact) = self.contacts.iter_mut().find(|c| c.contact.contact_id == contact_id) {
+                contact.key_changed = false;
+            }
+        } else {
+            self.verified_contacts.remove(&contact_id);
+        }
+
+        if let Err(e) = save_verified_contacts(self.user_id, &self.verified_contacts) {
+            self.modal_error = format!("Couldn't save verified contacts: {}", e);
+        }
+    }
+
+    fn private_key_set(&self) -> PrivateKeySet {
+        PrivateKeySet {
+            current: self.private_key.as_ref().unwrap().to_bytes(),
+            previous: self.previous_private_keys.iter().map(|k| k.to_bytes()).collect()
+        }
+    }
+
+    fn own_private_keys(&self) -> Vec<StaticSecret> {
+        self.private_key.iter()
+            .chain(self.previous_private_keys.iter())
+            .cloned()
+            .collect()
+    }
+
+    pub fn set_contacts(&mut self, contacts: Vec<ContactInfoJSON>) {
+        self.contact_keys = load_contact_keys(self.user_id).unwrap_or_else(|e| {
+            self.modal_error = format!("Couldn't load contacts' previous keys: {}", e);
+            ContactKeys::new()
+        });
+        self.verified_contacts = load_verified_contacts(self.user_id).unwrap_or_else(|e| {
+            self.modal_error = format!("Couldn't load verified contacts: {}", e);
+            VerifiedContacts::new()
+        });
+        let mut sessions = load_sessions(self.user_id, &self.own_private_keys()).unwrap_or_else(|e| {
+            self.modal_error = format!("Couldn't load encryption sessions, starting new ones: {}", e);
+            HashMap::new()
+        });
+        // a store that can't be read is left as it is, only new records are appended to it
+        let (mut message_store, message_store_status) = load_message_store(self.user_id, &self.own_private_keys())
+            .unwrap_or_else(|e| {
+                self.modal_error = format!("Couldn't load stored messages, messages already read can't be opened again: {}", e);
+                (MessageStore::new(), LogStatus::default())
+            });
+        if let Some(set_aside_path) = &message_store_status.set_aside_path {
+            self.modal_error = format!(
+                "{} stored message records couldn't be read and were left out, the damaged file was copied to {}",
+                message_store_status.damaged_records,
+                set_aside_path.display()
+            );
+        }
+        let (search_index, search_index_status) = load_search_index(self.user_id, &self.own_private_keys())
+            .unwrap_or_else(|e| {
+                self.modal_error = format!("Couldn't load search index, only new messages are searchable: {}", e);
+                (SearchIndex::default(), LogStatus::default())
+            });
+        self.search_index = search_index;
+
+        // a contact whose key the server sent malformed is left out, its session and
+        // stored messages are kept for a later login
+        self.contacts = Vec::new();
+        for contact in contacts {
+            if let Err(e) = decode_public_key(&contact.contact_public_key) {
+                self.modal_error = format!("Couldn't use the key the server has for {}: {}", contact.contact_email, e);
+                continue;
+            }
+            let session = sessions.remove(&contact.contact_id);
+            let stored_messages = message_store.remove(&contact.chat_id).unwrap_or_default();
+
+            if let Ok(mut contact_info) = self.build_contact(contact, session) {
+                contact_info.messages = stored_messages.into_iter().map(Message::from).collect();
+                self.contacts.push(contact_info);
+            }
+        }
+        self.left_out_sessions = sessions;
+        self.persist_contact_keys();
+        self.persist_sessions();
+        let stored_messages: MessageStore = self.contacts.iter()
+            .map(|c| (c.contact.chat_id, c.messages.iter().filter_map(stored_message).collect()))
+            .collect();
+        // also catches messages stored without reaching the index
+        let new_entries = self.update_search_index(&stored_messages);
+        if message_store_status.needs_rewrite {
+            let mut rewritten_messages = stored_messages;
+            rewritten_messages.extend(message_store);
+            self.store(StoreData::MessageStore(rewritten_messages));
+        }
+        if search_index_status.needs_rewrite {
+            self.store(StoreData::SearchIndex(self.search_index.clone()));
+        } else if !new_entries.is_empty() {
+            self.store(StoreData::NewSearchEntries(new_entries));
+        }
+        // stored chats show right away, only what arrived since is downloaded
+        self.should_sync_messages = true;
+
+        self.show_key_changed_modal = self.contacts.iter()
+            .any(|c| verification_status(&self.verified_contacts, c) == VerificationStatus::KeyChanged);
+    }
+
+    // records the key the server reports, so it's still known after the contact rotates
+    fn build_contact(&mut self, contact: ContactInfoJSON, session: Option<RatchetSession>) -> Result<ContactInfo, std::io::Error> {
+        let public_key = decode_public_key(&contact.contact_public_key)?;
+        let known_keys = self.contact_keys.entry(contact.contact_id).or_default();
+        let key_changed = known_keys.last().is_some_and(|k| *k != contact.contact_public_key);
+        if !known_keys.contains(&contact.contact_public_key) {
+            known_keys.push(contact.contact_public_key.clone());
+        }
+
+        let previous_public_keys: Vec<PublicKey> = known_keys.iter()
+            .filter(|k| **k != contact.contact_public_key)
+            .filter_map(|k| decode_public_key(k).ok())
+            .collect();
+
+        let mut contact_info = ContactInfo::new(
+            contact,
+            public_key,
+            self.user_id,
+            &self.own_private_keys(),
+            &previous_public_keys,
+            session
+        );
+        contact_info.key_changed = key_changed;
+        Ok(contact_info)
+    }
+
+    fn add_contact(&mut self, contact: ContactInfoJSON) {
+        let contact_email = contact.contact_email.clone();
+        let contact_info = match self.build_contact(contact, None) {
+            Ok(contact_info) => contact_info,
+            Err(e) => {
+                self.modal_error = format!("Couldn't use the key the server has for {}: {}", contact_email, e);
+                return;
+            }
+        };
+        self.contacts.push(contact_info);
+        self.persist_contact_keys();
+        self.persist_sessions();
+    }
+
+    // writes go to the store thread, sealed with the key current now
+    fn store(&self, data: StoreData) {
+        self.send_store_write(data, None);
+    }
+
+    // waits until this write and the ones queued before it are on disk
+    fn store_now(&self, data: StoreData) -> Result<(), String> {
+        let (saved_sender, saved_receiver) = mpsc::channel();
+        self.send_store_write(data, Some(saved_sender));
+        saved_receiver.recv().unwrap_or_else(|_| Err("The store thread stopped".to_owned()))
+    }
+
+    fn send_store_write(&self, data: StoreData, saved_sender: Option<Sender<Result<(), String>>>) {
+        let Some(private_key) = self.private_key.clone() else {
+            return;
+        };
+        if self.store_thread_sender.get().is_none() {
+            let (store_thread_sender, store_error_receiver) = init_store_thread();
+            let _ = self.store_thread_sender.set(store_thread_sender);
+            let _ = self.store_error_receiver.set(store_error_receiver);
+        }
+
+        let store_write = StoreWrite { user_id: self.user_id, private_key, data, saved_sender };
+        let _ = self.store_thread_sender.get().unwrap().send(store_write);
+    }
+
+    fn handle_store_errors(&mut self) {
+        let Some(store_error_receiver) = self.store_error_receiver.get() else {
+            return;
+        };
+        if let Some(e) = store_error_receiver.try_iter().last() {
+            self.modal_error = e;
+        }
+    }
+
+    fn persist_sessions(&mut self) {
+        self.store(StoreData::Sessions(self.all_sessions()));
+    }
+
+    fn all_sessions(&self) -> HashMap<u64, RatchetSession> {
+        self.left_out_sessions.clone().into_iter()
+            .chain(self.contacts.iter().map(|c| (c.contact.contact_id, c.session.clone())))
+            .collect()
+    }
+
+    fn persist_messages(&mut self) {
+        let new_messages = unstored_messages(&mut self.contacts);
+        if new_messages.is_empty() {
+            return;
+        }
+
+        let new_entries = self.update_search_index(&new_messages);
+        self.store(StoreData::NewMessages(new_messages));
+        if !new_entries.is_empty() {
+            self.store(StoreData::NewSearchEntries(new_entries));
+        }
+    }
+
+    // returns an index of just the messages that weren't in it yet
+    fn update_search_index(&mut self, messages: &MessageStore) -> SearchIndex {
+        let mut new_entries = SearchIndex::default();
+        for (chat_id, messages) in messages.iter() {
+            for message in messages.iter().filter(|m| m.decrypt_error.is_none()) {
+                if self.search_index.add_message(*chat_id, message.id, &message.content) {
+                    new_entries.add_message(*chat_id, message.id, &message.content);
+                }
+            }
+        }
+
+        if !new_entries.is_empty() && !self.message_query.trim().is_empty() {
+            self.search_messages();
+        }
+        new_entries
+    }
+
+    fn persist_contact_keys(&mut self) {
+        if let Err(e) = save_contact_keys(self.user_id, &self.contact_keys) {
+            self.modal_error = format!("Couldn't save contacts' keys: {}", e);
+        }
+    }
+
+    fn handle_user_interaction(
+        &mut self,
+        http_thread: &Sender<TaskWrapper>,
+        result_queue: &mut Vec<Receiver<TaskResult>>
+    ) {
+        if self.clicked_contact_id.is_some() && self.fetch_messages(http_thread, result_queue) {
+            self.is_fetching_messages = true;
+        }
+        if self.should_search {
+            self.search_user(http_thread, result_queue);
+        }
+        if self.clicked_invite_contact_id.is_some() {
+            self.send_invite(http_thread, result_queue);
+        }
+        if self.accepted_contact_id.is_some() {
+            self.accept_invite(http_thread, result_queue);
+        }
+        if self.should_sync_messages {
+            self.sync_messages(http_thread, result_queue);
+        }
+        if !self.pending_sync_pages.is_empty() {
+            self.sync_pending_pages(http_thread, result_queue);
+        }
+        if !self.pending_key_checks.is_empty() {
+            self.check_contact_keys(http_thread, result_queue);
+        }
+        if self.jump_to_message.is_some() {
+            self.fetch_until_jump_target(http_thread, result_queue);
+        }
+        if self.pending_export_chat_id.is_some() {
+            self.continue_export(http_thread, result_queue);
+        }
+        if self.resend_message_nonce.is_some() {
+            self.resend_message();
+        }
+        if self.discard_message_nonce.is_some() {
+            self.discard_message();
+        }
+    }
+
+    fn search_user(
+        &mut self,
+        http_thread: &Sender<TaskWrapper>,
+        result_queue: &mut Vec<Receiver<TaskResult>>
+    ) {
+        self.should_search = false;
+        self.is_search_loading = true;
+
+        let search_user_task = SearchUserTask::new(
+            self.search_email.clone(),
+            self.token.clone()
+        );
+        let (task_wrapper, task_channel_receiver) = TaskWrapper::new(Box::new(search_user_task));
+        self.search_cancellation = Some(task_wrapper.cancellation_handle());
+
+        http_thread.send(task_wrapper).unwrap();
+        result_queue.push(task_channel_receiver);
+    }
+
+    fn send_invite(
+        &mut self,
+        http_thread: &Sender<TaskWrapper>,
+        result_queue: &mut Vec<Receiver<TaskResult>>
+    ) {
+        let receiver_id = self.clicked_invite_contact_id.take().unwrap();
+        let receiver_email = self.clicked_invite_contact_email.take().unwrap();
+        if self.sending_invite_ids.contains(&receiver_id) {
+            return;
+        }
+        self.sending_invite_ids.push(receiver_id);
+
+        let send_invite_contact_task = SendInviteContactTask::new(
+            self.user_id,
+            receiver_id,
+            receiver_email,
+            self.token.clone()
+        );
+        let (task_wrapper, task_channel_receiver) = TaskWrapper::new(Box::new(send_invite_contact_task));
+
+        http_thread.send(task_wrapper).unwrap();
+        result_queue.push(task_channel_receiver);
+    }
+
+    fn accept_invite(
+        &mut self,
+        http_thread: &Sender<TaskWrapper>,
+        result_queue: &mut Vec<Receiver<TaskResult>>
+    ) {
+        let contact_id = self.accepted_contact_id.take().unwrap();
+        if self.accepting_invite_ids.contains(&contact_id) {
+            return;
+        }
+        self.accepting_invite_ids.push(contact_id);
+
+        let accept_invite_contact_task = AcceptInviteContactTask::new(
+            contact_id,
+            self.token.clone()
+        );
+        let (task_wrapper, task_channel_receiver) = TaskWrapper::new(Box::new(accept_invite_contact_task));
+
+        http_thread.send(task_wrapper).unwrap();
+        result_queue.push(task_channel_receiver);
+    }
+
+    fn sync_messages(
+        &mut self,
+        http_thread: &Sender<TaskWrapper>,
+        result_queue: &mut Vec<Receiver<TaskResult>>
+    ) {
+        self.should_sync_messages = false;
+
+        let sync_started_at = Utc::now();
+        for contact in self.contacts.iter_mut() {
+            contact.sync_after_id = contact.messages.iter()
+                .filter(|m| !m.is_imported)
+                .filter_map(|m| m._id)
+                .max();
+            contact.sync_started_at = Some(sync_started_at);
+            self.pending_sync_pages.push((contact.contact.chat_id, 0));
+        }
+        self.sync_pending_pages(http_thread, result_queue);
+    }
+
+    fn sync_pending_pages(
+        &mut self,
+        http_thread: &Sender<TaskWrapper>,
+        result_queue: &mut Vec<Receiver<TaskResult>>
+    ) {
+        for (chat_id, offset) in std::mem::take(&mut self.pending_sync_pages) {
+            let sync_chat_messages_task = FetchChatMessagesTask::sync(chat_id, offset, self.token.clone());
+            let (task_wrapper, task_channel_receiver) = TaskWrapper::new(
+                Box::new(sync_chat_messages_task)
+            );
+
+            http_thread.send(task_wrapper).unwrap();
+            result_queue.push(task_channel_receiver);
+        }
+    }
+
+    fn check_contact_keys(
+        &mut self,
+        http_thread: &Sender<TaskWrapper>,
+        result_queue: &mut Vec<Receiver<TaskResult>>
+    ) {
+        for contact_id in std::mem::take(&mut self.pending_key_checks) {
+            let fetch_public_key_task = FetchPublicKeyTask::new(contact_id, self.token.clone());
+            let (task_wrapper, task_channel_receiver) = TaskWrapper::new(Box::new(fetch_public_key_task));
+
+            http_thread.send(task_wrapper).unwrap();
+            result_queue.push(task_channel_receiver);
+        }
+    }
+
+    fn fetch_messages(
+        &mut self,
+        http_thread: &Sender<TaskWrapper>,
+        result_queue: &mut Vec<Receiver<TaskResult>>
+    ) -> bool {
+        if let Some(c) = self.get_selected_contact() {
+            let offset = c.server_offset();
+            if !self.is_fetching_messages && !self.fetch_messages_error && c.should_fetch_messages && (offset <= 20 || self.y_chat_scroll_offset == 0.) {
+                self.send_fetch_messages_task(c.contact.chat_id, offset, http_thread, result_queue);
+
+                true
+            } else {
+                false
+            }
+        } else {
+            false
+        }
+    }
+
+    // pages back through the history until the message a search result points to is loaded
+    fn fetch_until_jump_target(
+        &mut self,
+        http_thread: &Sender<TaskWrapper>,
+        result_queue: &mut Vec<Receiver<TaskResult>>
+    ) {
+        let Some((chat_id, message_id)) = self.jump_to_message else {
+            return;
+        };
+        if self.is_fetching_messages {
+            return;
+        }
+        let Some(contact) = self.contacts.iter().find(|c| c.contact.chat_id == chat_id) else {
+            self.jump_to_message = None;
+            return;
+        };
+        if contact.messages.iter().any(|m| m._id == Some(message_id)) {
+            return;
+        }
+        if self.fetch_messages_error {
+            self.jump_to_message = None;
+            return;
+        }
+        if !contact.should_fetch_messages {
+            self.jump_to_message = None;
+            self.modal_error = "This message is no longer in the chat history".to_string();
+            return;
+        }
+
+        let offset = contact.server_offset();
+        self.send_fetch_messages_task(chat_id, offset, http_thread, result_queue);
+        self.is_fetching_messages = true;
+    }
+
+    fn send_fetch_messages_task(
+        &self,
+        chat_id: u64,
+        offset: u64,
+        http_thread: &Sender<TaskWrapper>,
+        result_queue: &mut Vec<Receiver<TaskResult>>
+    ) {
+        let fetch_chat_message_task = FetchChatMessagesTask::new(chat_id, offset, self.token.clone());
+        let (task_wrapper, task_channel_receiver) = TaskWrapper::new(
+            Box::new(fetch_chat_message_task)
+        );
+
+        http_thread.send(task_wrapper).unwrap();
+        result_queue.push(task_channel_receiver);
+    }
+
+    fn search_messages(&mut self) {
+        self.message_search_results = self.search_index.search(&self.message_query);
+    }
+
+    fn open_search_hit(&mut self, (chat_id, message_id): SearchHit) {
+        let Some(contact) = self.contacts.iter().find(|c| c.contact.chat_id == chat_id) else {
+            return;
+        };
+
+        self.fetch_messages_error = false;
+        self.clicked_contact_id = Some(contact.contact.contact_id);
+        self.current_selected_id = contact.contact.contact_id;
+        self.jump_to_message = Some((chat_id, message_id));
+        self.highlighted_message_id = Some(message_id);
+    }
+
+    fn handle_ws_events(&mut self) {
+        let ws_event_receiver = self.ws_event_receiver.get().unwrap();
+        let events: Vec<WsEvent> = ws_event_receiver.try_iter().collect();
+
+        for event in events {
+            match event {
+                WsEvent::Status(ConnectionStatus::Refused(e)) => {
+                    self.modal_error = format!("{}. Log in again to reconnect.", e);
+                    self.connection_status = ConnectionStatus::Refused(e);
+                },
+                WsEvent::Status(status) => {
+                    let was_reconnecting = self.connection_status != ConnectionStatus::Connected;
+                    self.connection_status = status;
+
+                    if was_reconnecting && self.connection_status == ConnectionStatus::Connected {
+                        self.should_sync_messages = true;
+                        self.deliver_outbox();
+                    }
+                },
+                WsEvent::Written(msg) => self.handle_delivery_report(msg, true),
+                WsEvent::Undelivered(msg) => self.handle_delivery_report(msg, false)
+            }
+        }
+    }
+
+    fn handle_delivery_report(&mut self, msg: String, is_written: bool) {
+        let Ok(ws_content_message) = serde_json::from_str::<WsContentMessage>(&msg) else {
+            return;
+        };
+        let nonce = ws_content_message.nonce;
+        let Some(entry) = self.outbox.iter_mut().find(|e| e.message.nonce == nonce) else {
+            return;
+        };
+        entry.in_flight = false;
+
+        // the bytes only reached the local socket buffer, a sync of the chat tells whether the server got them
+        if is_written {
+            entry.written_at = Some(Utc::now());
+            let sync_page = (ws_content_message.chat_id, 0);
+            if !self.pending_sync_pages.contains(&sync_page) {
+                self.pending_sync_pages.push(sync_page);
+            }
+        } else {
+            entry.attempts += 1;
+            if entry.attempts >= MAX_DELIVERY_ATTEMPTS {
+                self.set_message_status(ws_content_message.receiver_id, nonce, MessageStatus::Failed);
+            }
+        }
+
+        self.persist_outbox();
+    }
+
+    // drops the entries whose message the server returned
+    fn confirm_outbox(&mut self, confirmed_nonces: &[[u8; 24]]) {
+        if confirmed_nonces.is_empty() {
+            return;
+        }
+
+        self.outbox.retain(|e| !confirmed_nonces.contains(&e.message.nonce));
+        self.persist_outbox();
+    }
+
+    // a finished catch-up sync that didn't bring back a message written before it started
+    // probably lost the write, but the server may still store it later and the same nonce sent
+    // again would reach the contact as a replay, so the user decides whether to resend it
+    fn flag_unconfirmed(&mut self, contact_id: u64, sync_started_at: DateTime<Utc>) {
+        let mut unconfirmed_nonces = Vec::new();
+        for entry in self.outbox.iter_mut() {
+            if entry.message.receiver_id == contact_id
+                && entry.attempts < MAX_DELIVERY_ATTEMPTS
+                && entry.written_at.is_some_and(|t| t < sync_started_at)
+            {
+                entry.attempts = MAX_DELIVERY_ATTEMPTS;
+                unconfirmed_nonces.push(entry.message.nonce);
+            }
+        }
+
+        if unconfirmed_nonces.is_empty() {
+            return;
+        }
+        for nonce in unconfirmed_nonces {
+            self.set_message_status(contact_id, nonce, MessageStatus::Failed);
+        }
+        self.persist_outbox();
+    }
+
+    fn set_message_status(&mut self, contact_id: u64, nonce: [u8; 24], status: MessageStatus) {
+        let message = self.contacts.iter_mut()
+            .find(|c| c.contact.contact_id == contact_id)
+            .and_then(|c| c.messages.iter_mut().find(|m| m.nonce == Some(nonce)));
+
+        if let Some(message) = message {
+            message.status = status;
+        }
+    }
+
+    // pending messages are only handed to the websocket thread while it is connected,
+    // the rest waits in the outbox until the connection comes back
+    fn deliver_outbox(&mut self) {
+        if self.connection_status != ConnectionStatus::Connected {
+            return;
+        }
+
+        let Some(message_thread_sender) = self.message_thread_sender.get() else {
+            return;
+        };
+        for entry in self.outbox.iter_mut() {
+            if entry.in_flight || entry.written_at.is_some() || entry.attempts >= MAX_DELIVERY_ATTEMPTS {
+                continue;
+            }
+
+            let ws_content_message_json = serde_json::to_string(&entry.message).unwrap();
+            if message_thread_sender.send(ws_content_message_json).is_ok() {
+                entry.in_flight = true;
+            }
+        }
+    }
+
+    fn persist_outbox(&mut self) {
+        if let Err(e) = save_outbox(self.user_id, self.private_key.as_ref().unwrap(), &self.outbox) {
+            self.modal_error = format!("Couldn't save unsent messages: {}", e);
+        }
+    }
+
+    pub fn load_outbox(&mut self) {
+        let outbox = match load_outbox(self.user_id, &self.own_private_keys()) {
+            Ok(outbox) => outbox,
+            Err(e) => {
+                self.modal_error = format!("Couldn't load unsent messages: {}", e);
+                return;
+            }
+        };
+
+        for entry in outbox.iter() {
+            let message = &entry.message;
+            let Some(contact) = self.contacts.iter_mut().find(|c| c.contact.contact_id == message.receiver_id) else {
+                continue;
+            };
+
+            contact.messages.push_back(
+                Message {
+                    _id: None,
+                    content: entry.content.clone(),
+                    sender_id: message.sender_id,
+                    status: if entry.attempts >= MAX_DELIVERY_ATTEMPTS { MessageStatus::Failed } else { MessageStatus::Pending },
+                    nonce: Some(message.nonce),
+                    decrypt_error: None,
+                    sent_at: Some(entry.queued_at),
+                    is_imported: false,
+                    is_stored: false
+                }
+            );
+        }
+
+        self.outbox = outbox;
+        self.deliver_outbox();
+    }
+
+    fn resend_message(&mut self) {
+        let nonce = self.resend_message_nonce.take().unwrap();
+        let Some(index) = self.outbox.iter().position(|e| e.message.nonce == nonce) else {
+            return;
+        };
+        let contact_id = self.outbox[index].message.receiver_id;
+
+        // a written message may have reached the server after all, so it goes out sealed anew
+        // and the contact sees a second copy instead of a replay
+        let mut new_nonce = nonce;
+        if self.outbox[index].written_at.is_some() {
+            let Some(contact) = self.contacts.iter_mut().find(|c| c.contact.contact_id == contact_id) else {
+                return;
+            };
+            let (encrypted_content, sealed_nonce) = contact.encrypt(&self.outbox[index].content);
+            if let Err(e) = self.store_now(StoreData::Sessions(self.all_sessions())) {
+                self.modal_error = format!("Couldn't save encryption sessions, the message wasn't sent: {}", e);
+                return;
+            }
+
+            let entry = &mut self.outbox[index];
+            entry.message.content = encrypted_content;
+            entry.message.nonce = sealed_nonce;
+            entry.written_at = None;
+            new_nonce = sealed_nonce;
+
+            let message = self.contacts.iter_mut()
+                .find(|c| c.contact.contact_id == contact_id)
+                .and_then(|c| c.messages.iter_mut().find(|m| m.nonce == Some(nonce)));
+            if let Some(message) = message {
+                message.nonce = Some(sealed_nonce);
+            }
+        }
+        self.outbox[index].attempts = 0;
+
+        self.set_message_status(contact_id, new_nonce, MessageStatus::Pending);
+        self.persist_outbox();
+        self.deliver_outbox();
+    }
+
+    fn discard_message(&mut self) {
+        let nonce = self.discard_message_nonce.take().unwrap();
+        self.outbox.retain(|e| e.message.nonce != nonce);
+        for contact in self.contacts.iter_mut() {
+            contact.messages = std::mem::take(&mut contact.messages)
+                .into_iter()
+                .filter(|m| m.nonce != Some(nonce))
+                .collect();
+        }
+
+        self.persist_outbox();
+    }
+
+    fn handle_messages(&mut self, ctx: &egui::Context) {
+        // maybe decrypt actions should be outside main thread?
+        let message_ui_receiver = self.message_ui_receiver.get().unwrap();
+        if let Ok(msg) = message_ui_receiver.try_recv() {
+            let parsed_msg: Value = serde_json::from_str(&msg).unwrap();
+            if parsed_msg["type"] == MessageType::Content.as_str() {
+                self.handle_content_message(ctx, msg);
+            } else if parsed_msg["type"] == MessageType::Invite.as_str() {
+                self.handle_invite_message(msg);
+            } else if parsed_msg["type"] == MessageType::InviteAccepted.as_str() {
+                self.handle_accept_invite_message(msg);
+            } else if parsed_msg["type"] == MessageType::KeyRotated.as_str() {
+                self.handle_key_rotated_message(msg);
+            }
+        }
+    }
+
+    fn handle_content_message(&mut self, ctx: &egui::Context, msg: String) {
+        let Ok(received_message) = serde_json::from_str::<ContentMessageWrapper>(&msg) else {
+            return;
+        };
+        let received_message = received_message.message;
+        let Some(contact) = self.contacts.iter_mut().find(|c| c.contact.contact_id == received_message.user_id) else {
+            return;
+        };
+        // after a reconnect the catch-up sync may already have brought this message in
+        if contact.messages.iter().any(|m| m._id == Some(received_message.id)) {
+            return;
+        }
+
+        let message = open_server_message(
+            contact,
+            received_message.id,
+            received_message.user_id,
+            &received_message.content,
+            &received_message.nonce,
+            // it just arrived, so that's close enough when the server doesn't say
+            received_message.created_at.or_else(|| Some(Utc::now()))
+        );
+        // ordered by id like fetched messages, older missed ones may still be syncing
+        let mut messages: Vec<Message> = std::mem::take(&mut contact.messages).into_iter().collect();
+        insert_by_id(&mut messages, message);
+        contact.messages = messages.into_iter().collect();
+        self.persist_sessions();
+        self.persist_messages();
+        ctx.request_repaint();
+    }
+
+    fn handle_invite_message(&mut self, msg: String) {
+        self.received_invites.push(serde_json::from_str(&msg).unwrap());
+    }
+
+    fn handle_accept_invite_message(&mut self, msg: String) {
+        let parsed_msg: AcceptInvite = serde_json::from_str(&msg).unwrap();
+
+        self.sent_invites.retain(|i| i.id != parsed_msg.contact.id);
+        self.add_contact(parsed_msg.contact);
+    }
+
+    // anyone can relay this with any sender, so it only prompts a look at the key the server has
+    fn handle_key_rotated_message(&mut self, msg: String) {
+        let Ok(parsed_msg) = serde_json::from_str::<KeyRotatedMessage>(&msg) else {
+            return;
+        };
+        let Some(contact) = self.contacts.iter().find(|c| c.contact.contact_id == parsed_msg.sender_id) else {
+            return;
+        };
+        if contact.contact.contact_public_key == parsed_msg.public_key
+            || self.pending_key_checks.contains(&parsed_msg.sender_id) {
+            return;
+        }
+
+        self.pending_key_checks.push(parsed_msg.sender_id);
+    }
+
+    fn get_mut_selected_contact(&mut self) -> Option<&mut ContactInfo> {
+        self.contacts.iter_mut()
+            .find(
+                |contact_info|
+                contact_info.contact.contact_id == self.current_selected_id
+            )
+    }
+
+    fn get_selected_contact(&self) -> Option<&ContactInfo> {
+        self.contacts.iter()
+            .find(
+                |contact_info|
+                contact_info.contact.contact_id == self.current_selected_id
+            )
     }
 
     fn send_content_message(&mut self) {
         let typed_message = self.typed_message.clone().replace("\n", "");
-        let user_id = self.user_id.clone();
+        let user_id = self.user_id;
         let contact = self.get_mut_selected_contact().unwrap();
 
+        let (encrypted_content, nonce) = contact.encrypt(&typed_message);
+        let receiver_id = contact.contact.contact_id;
+        let chat_id = contact.contact.chat_id;
+        let receiver_email = contact.contact.contact_email.clone();
+
+        // a restart must never pick up a sending chain from before this message, that would
+        // seal the next one with the same key
+        if let Err(e) = self.store_now(StoreData::Sessions(self.all_sessions())) {
+            self.modal_error = format!("Couldn't save encryption sessions, the message wasn't sent: {}", e);
+            return;
+        }
+        let queued_at = Utc::now();
+
+        let contact = self.get_mut_selected_contact().unwrap();
         contact.messages.push_back(
             Message {
                 _id: None,
                 content: typed_message.clone(),
-                sender_id: user_id
+                sender_id: user_id,
+                status: MessageStatus::Pending,
+                nonce: Some(nonce),
+                decrypt_error: None,
+                sent_at: Some(queued_at),
+                is_imported: false,
+                is_stored: false
             }
         );
 
-        let (encrypted_content, nonce) = encrypt_plain_text(&contact.cipher, typed_message);
-
         // i dont know if its safe to send bytes serialized to JSON format
         // but it is what i am doing for now
-        let receiver_id = contact.contact.contact_id.clone();
         let ws_content_message = WsContentMessage {
             sender_id: user_id,
             receiver_id,
             target_id: receiver_id,
-            chat_id: contact.contact.chat_id,
-            receiver_email: contact.contact.contact_email.clone(),
+            chat_id,
+            receiver_email,
             r#type: MessageType::Content,
             content: encrypted_content,
             nonce
         };
-        let ws_content_message_json = serde_json::to_string(&ws_content_message).unwrap();
 
-        let message_thread_sender = self.message_thread_sender.get().unwrap();
-        message_thread_sender.send(ws_content_message_json).unwrap();
+        self.outbox.push(OutboxEntry::new(ws_content_message, typed_message, queued_at));
+        self.persist_outbox();
+        self.deliver_outbox();
 
         self.typed_message.clear();
     }
 
     pub fn connect_websocket(&mut self, ctx: &egui::Context) -> Result<(), std::io::Error> {
-        match init_websocket(self.token.clone()) {
-            Ok(socket) => {
-                self.websocket_thread(socket, ctx.to_owned());
-                Ok(())
-            },
-            Err(e) => Err(e)
-        }
-    }
+        let mut socket = init_websocket(&self.endpoints, self.token.clone())?;
+        set_nonblocking(&mut socket)?;
 
-    fn websocket_thread(&mut self, mut socket: WebSocket<MaybeTlsStream<TcpStream>>, ctx: egui::Context) {
-        let _ = match socket.get_mut() {
-            tungstenite::stream::MaybeTlsStream::Plain(stream) => stream.set_nonblocking(true),
-            _ => unimplemented!()
-        };
+        self.websocket_thread(socket, ctx.to_owned());
+        Ok(())
+    }
 
-        let (message_thread_sender, message_ui_receiver) = init_websocket_thread(socket, ctx);
+    fn websocket_thread(&mut self, socket: WebSocket<MaybeTlsStream<TcpStream>>, ctx: egui::Context) {
+        let (message_thread_sender, message_ui_receiver, ws_event_receiver) = init_websocket_thread(
+            socket,
+            self.endpoints.clone(),
+            self.token.clone(),
+            ctx
+        );
 
         self.message_thread_sender.set(message_thread_sender).unwrap();
         self.message_ui_receiver.set(message_ui_receiver).unwrap();
-    }
+        self.ws_event_receiver.set(ws_event_receiver).unwrap();
 
-    fn handle_task_error(&mut self, response: String) {
-        let parsed_error: GenericResultError = serde_json::from_str(&response).unwrap();
-        self.modal_error = parsed_error.message;
-        return;
+        self.deliver_outbox();
     }
 
-    pub fn handle_task_search_user(&mut self, task_result: TaskResult) {
+    pub fn handle_task_search_user(&mut self, result: Result<Vec<SearchUserResponse>, TaskError>) {
         self.is_search_loading = false;
+        self.search_cancellation = None;
+
+        match result {
+            Ok(users) => self.searched_users = users,
+            Err(e) => self.handle_task_failure(e, RetryAction::SearchUser)
+        }
+    }
+
+    pub fn handle_task_send_invite_contact(&mut self, receiver_id: u64, receiver_email: String, result: Result<InviteMessage, TaskError>) {
+        self.sending_invite_ids.retain(|id| *id != receiver_id);
+
+        match result {
+            Ok(invite) => self.sent_invites.push(invite),
+            Err(e) => self.handle_task_failure(e, RetryAction::SendInvite(receiver_id, receiver_email))
+        }
+    }
 
-        if task_result.status_code >= 400 {
-            self.handle_task_error(task_result.response);
+    pub fn handle_task_accept_invite_contact(&mut self, contact_id: u64, result: Result<AcceptInvite, TaskError>, ctx: &egui::Context) {
+        self.accepting_invite_ids.retain(|id| *id != contact_id);
+
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => return self.handle_task_failure(e, RetryAction::AcceptInvite(contact_id))
+        };
+
+        self.received_invites.retain(|i| i.id != response.contact.id);
+        self.add_contact(response.contact);
+        ctx.request_repaint();
+    }
+
+    pub fn handle_task_fetch_public_key(&mut self, contact_id: u64, result: Result<PublicKeyResponse, TaskError>) {
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => return self.handle_task_failure(e, RetryAction::FetchPublicKey(contact_id))
+        };
+        let private_key = self.private_key.clone().unwrap();
+        let Some(contact) = self.contacts.iter_mut().find(|c| c.contact.contact_id == contact_id) else {
+            return;
+        };
+        if contact.contact.contact_public_key == response.public_key {
             return;
         }
 
-        let users: Vec<SearchUserResponse> = serde_json::from_str(&task_result.response).unwrap();
-        self.searched_users = users;
+        if let Err(e) = contact.rotate_public_key(response.public_key.clone(), private_key) {
+            self.modal_error = format!("Couldn't use the key the server has for {}: {}", contact.contact.contact_email, e);
+            return;
+        }
+        contact.key_changed = true;
+        let known_keys = self.contact_keys.entry(contact_id).or_default();
+        if !known_keys.contains(&response.public_key) {
+            known_keys.push(response.public_key);
+        }
+        if verification_status(&self.verified_contacts, contact) == VerificationStatus::KeyChanged {
+            self.show_key_changed_modal = true;
+        }
+        self.persist_contact_keys();
+        self.persist_sessions();
     }
 
-    pub fn handle_task_send_invite_contact(&mut self, task_result: TaskResult) {
-        self.is_send_invite_loading = false;
+    pub fn handle_task_fetch_chat_messages(&mut self, chat_id: u64, result: Result<Vec<FetchMessage>, TaskError>) {
+        self.is_fetching_messages = false;
+
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                self.fetch_messages_error = true;
+                return self.handle_task_failure(e, RetryAction::FetchMessages);
+            }
+        };
 
-        if task_result.status_code >= 400 {
-            self.handle_task_error(task_result.response);
-            return
+        let Some(contact) = self.contacts.iter_mut().find(|c| c.contact.chat_id == chat_id) else {
+            return;
+        };
+        if response.is_empty() {
+            contact.should_fetch_messages = false;
+            return;
         }
 
-        self.sent_invites.push(serde_json::from_str(&task_result.response).unwrap());
+        let confirmed_nonces = merge_fetched_messages(contact, response);
+        self.confirm_outbox(&confirmed_nonces);
+        self.persist_sessions();
+        self.persist_messages();
+
+        if self.get_selected_contact().is_some_and(|c| c.contact.chat_id == chat_id) {
+            self.should_scroll_down = true;
+        }
     }
 
-    pub fn handle_task_accept_invite_contact(&mut self, task_result: TaskResult, ctx: &egui::Context) {
-        self.is_loading_accept_invite = false;
+    pub fn handle_task_sync_chat_messages(
+        &mut self,
+        chat_id: u64,
+        offset: u64,
+        result: Result<Vec<FetchMessage>, TaskError>
+    ) {
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => return self.handle_task_failure(e, RetryAction::SyncMessages)
+        };
+
+        let Some(contact) = self.contacts.iter_mut().find(|c| c.contact.chat_id == chat_id) else {
+            return;
+        };
+
+        // the server returns the newest messages first, so the last one tells whether
+        // this page already reached what was known; chats with nothing stored only sync one page
+        let reached_known = response.last()
+            .is_none_or(|m| contact.sync_after_id.is_none_or(|id| m.id <= id));
+        let finished_sync_started_at = if reached_known {
+            contact.sync_started_at.take()
+        } else {
+            self.pending_sync_pages.push((chat_id, offset + response.len() as u64));
+            None
+        };
+        let contact_id = contact.contact.contact_id;
 
-        if task_result.status_code >= 400 {
-            self.handle_task_error(task_result.response);
-            return
+        let confirmed_nonces = merge_fetched_messages(contact, response);
+        self.confirm_outbox(&confirmed_nonces);
+        if let Some(sync_started_at) = finished_sync_started_at {
+            self.flag_unconfirmed(contact_id, sync_started_at);
         }
+        self.persist_sessions();
+        self.persist_messages();
+    }
+}
 
-        let response: AcceptInvite = serde_json::from_str(&task_result.response).unwrap();
-        let private_key = self.private_key.clone().unwrap();
+// only messages that are new or changed since they were last stored are written;
+// the ones without an id are pending and live in the outbox
+fn unstored_messages(contacts: &mut [ContactInfo]) -> MessageStore {
+    let mut new_messages = MessageStore::new();
+    for contact in contacts.iter_mut() {
+        let messages: Vec<StoredMessage> = contact.messages.iter_mut()
+            .filter(|m| !m.is_stored)
+            .filter_map(|m| {
+                let stored_message = stored_message(m)?;
+                m.is_stored = true;
+                Some(stored_message)
+            })
+            .collect();
+        if !messages.is_empty() {
+            new_messages.insert(contact.contact.chat_id, messages);
+        }
+    }
 
-        let contact_public_key_bytes: [u8; 32] = BASE64_STANDARD
-            .decode(response.contact.contact_public_key.clone())
-            .unwrap()
-            .try_into()
-            .unwrap();
-        let contact_public_key = PublicKey::from(contact_public_key_bytes);
-        let cipher = generate_cipher(contact_public_key, private_key);
+    new_messages
+}
 
-        self.received_invites.retain(|i| i.id != response.contact.id);
-        self.contacts.push(
-            ContactInfo {
-                contact: response.contact,
-                cipher,
-                should_fetch_messages: true,
-                messages: LinkedList::new(),
+fn stored_message(message: &Message) -> Option<StoredMessage> {
+    Some(StoredMessage {
+        id: message._id?,
+        sender_id: message.sender_id,
+        content: message.content.clone(),
+        decrypt_error: message.decrypt_error.clone(),
+        sent_at: message.sent_at,
+        is_imported: message.is_imported
+    })
+}
+
+fn verification_status(verified_contacts: &VerifiedContacts, contact: &ContactInfo) -> VerificationStatus {
+    match verified_contacts.get(&contact.contact.contact_id) {
+        None => VerificationStatus::Unverified,
+        Some(public_key) if *public_key == contact.contact.contact_public_key => VerificationStatus::Verified,
+        Some(_) => VerificationStatus::KeyChanged
+    }
+}
+
+// messages are kept ordered by id; own messages sent from this device have no id until
+// the server returns them, they're matched by nonce and keep their place.
+// returns the nonces of those, they're confirmed as delivered
+fn merge_fetched_messages(contact: &mut ContactInfo, fetched_messages: Vec<FetchMessage>) -> Vec<[u8; 24]> {
+    let mut messages: Vec<Message> = std::mem::take(&mut contact.messages).into_iter().collect();
+    let mut confirmed_nonces = Vec::new();
+
+    for fetched_message in fetched_messages {
+        // messages stored before the server sent times pick them up here
+        if let Some(known_message) = messages.iter_mut().find(|m| m._id == Some(fetched_message.id)) {
+            if known_message.is_imported || (known_message.sent_at.is_none() && fetched_message.created_at.is_some()) {
+                known_message.sent_at = known_message.sent_at.or(fetched_message.created_at);
+                known_message.is_imported = false;
+                known_message.is_stored = false;
             }
-        );
-        ctx.request_repaint();
+            continue;
+        }
+        let own_message = messages.iter_mut().find(|m| {
+            m._id.is_none() && m.nonce.is_some_and(|nonce| BASE64_STANDARD.encode(nonce) == fetched_message.nonce)
+        });
+        if let Some(own_message) = own_message {
+            own_message._id = Some(fetched_message.id);
+            own_message.sent_at = fetched_message.created_at.or(own_message.sent_at);
+            own_message.status = MessageStatus::Sent;
+            confirmed_nonces.extend(own_message.nonce);
+            continue;
+        }
+
+        let message = decrypt_fetched_message(contact, fetched_message);
+        insert_by_id(&mut messages, message);
     }
 
-    pub fn handle_task_fetch_chat_messages(&mut self, task_result: TaskResult) {
-        if task_result.status_code >= 400 {
-            self.handle_task_error(task_result.response);
-            return;
+    contact.messages = messages.into_iter().collect();
+    confirmed_nonces
+}
+
+// an export only goes back into the chat it came from, with messages of its two members only
+fn export_contact_index(contacts: &[ContactInfo], user_id: u64, chat_export: &ChatExport) -> Result<usize, &'static str> {
+    if chat_export.user_id != user_id {
+        return Err("This export belongs to another account");
+    }
+    let contact_index = contacts.iter()
+        .position(|c| c.contact.contact_id == chat_export.contact_id && c.contact.chat_id == chat_export.chat_id)
+        .ok_or("This export is from a chat that isn't in your contacts")?;
+    if chat_export.messages.iter().any(|m| m.sender_id != user_id && m.sender_id != chat_export.contact_id) {
+        return Err("This export has messages from someone outside the chat");
+    }
+
+    Ok(contact_index)
+}
+
+// entries without content have nothing to restore, the server copy is opened again instead;
+// returns how many messages were added or recovered
+fn merge_imported_messages(contact: &mut ContactInfo, exported_messages: Vec<ExportedMessage>) -> usize {
+    let mut messages: Vec<Message> = std::mem::take(&mut contact.messages).into_iter().collect();
+    let mut imported_count = 0;
+
+    for exported_message in exported_messages {
+        let Some(content) = exported_message.content else {
+            continue;
+        };
+
+        match messages.iter_mut().find(|m| m._id == Some(exported_message.id)) {
+            Some(message) if message.decrypt_error.is_some() => {
+                message.content = content;
+                message.decrypt_error = None;
+                message.sent_at = message.sent_at.or(exported_message.sent_at);
+                message.is_stored = false;
+                imported_count += 1;
+            },
+            Some(_) => {},
+            None => {
+                insert_by_id(&mut messages, Message {
+                    _id: Some(exported_message.id),
+                    content,
+                    sender_id: exported_message.sender_id,
+                    status: MessageStatus::Sent,
+                    nonce: None,
+                    decrypt_error: None,
+                    sent_at: exported_message.sent_at,
+                    is_imported: true,
+                    is_stored: false
+                });
+                imported_count += 1;
+            }
         }
+    }
 
-        let contact = self.get_mut_selected_contact().unwrap();
-        let response: Vec<FetchMessage> = serde_json::from_str(&task_result.response).unwrap();
+    contact.messages = messages.into_iter().collect();
+    imported_count
+}
 
-        if response.len() == 0 {
-            contact.should_fetch_messages = false;
-            self.is_fetching_messages = false;
-            return;
+fn insert_by_id(messages: &mut Vec<Message>, message: Message) {
+    let position = messages.iter()
+        .position(|m| m._id.is_some_and(|id| message._id.is_some_and(|new_id| id > new_id)))
+        .unwrap_or(messages.len());
+    messages.insert(position, message);
+}
+
+fn decrypt_fetched_message(contact: &mut ContactInfo, fetched_message: FetchMessage) -> Message {
+    open_server_message(
+        contact,
+        fetched_message.id,
+        fetched_message.user_id,
+        &fetched_message.content,
+        &fetched_message.nonce,
+        fetched_message.created_at
+    )
+}
+
+// a message that can't be opened must not take the whole chat down, it's kept with its error
+fn open_server_message(
+    contact: &mut ContactInfo,
+    id: u64,
+    sender_id: u64,
+    content: &str,
+    nonce: &str,
+    sent_at: Option<DateTime<Utc>>
+) -> Message {
+    let decrypted_message = decode_and_decrypt(content, nonce, |cipher_text, nonce| {
+        contact.decrypt(cipher_text, nonce, sender_id, Some(id))
+    });
+
+    let (content, decrypt_error) = match decrypted_message {
+        Ok(content) => (content, None),
+        Err(e) => (String::new(), Some(e))
+    };
+
+    Message {
+        _id: Some(id),
+        sender_id,
+        content,
+        status: MessageStatus::Sent,
+        nonce: None,
+        decrypt_error,
+        sent_at,
+        is_imported: false,
+        is_stored: false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::encryption::DecryptError;
+
+    const CHAT_ID: u64 = 7;
+    const OWN_ID: u64 = 1;
+    const CONTACT_ID: u64 = 2;
+
+    fn contact() -> ContactInfo {
+        let own_private_key = StaticSecret::random_from_rng(rand::thread_rng());
+        let contact_public_key = PublicKey::from(&StaticSecret::random_from_rng(rand::thread_rng()));
+        let contact = ContactInfoJSON {
+            id: 1,
+            contact_id: CONTACT_ID,
+            chat_id: CHAT_ID,
+            contact_email: "contact@example.com".to_owned(),
+            contact_public_key: BASE64_STANDARD.encode(contact_public_key.as_bytes())
+        };
+
+        ContactInfo::new(contact, contact_public_key, OWN_ID, &[own_private_key], &[], None)
+    }
+
+    fn message(id: Option<u64>, content: &str) -> Message {
+        Message {
+            _id: id,
+            content: content.to_owned(),
+            sender_id: CONTACT_ID,
+            status: MessageStatus::Sent,
+            nonce: None,
+            decrypt_error: None,
+            sent_at: None,
+            is_imported: false,
+            is_stored: true
         }
+    }
 
-        for fetched_message in response {
-            let raw_nonce: [u8; 24] = BASE64_STANDARD
-                .decode(fetched_message.nonce)
-                .unwrap()
-                .try_into()
-                .unwrap();
-            let content: Vec<u8> = BASE64_STANDARD
-                .decode(fetched_message.content)
-                .unwrap()
-                .try_into()
-                .unwrap();
+    fn fetched(id: u64, nonce: [u8; 24]) -> FetchMessage {
+        FetchMessage {
+            id,
+            user_id: CONTACT_ID,
+            chat_id: CHAT_ID,
+            content: BASE64_STANDARD.encode(b"sealed"),
+            nonce: BASE64_STANDARD.encode(nonce),
+            created_at: Some(Utc::now())
+        }
+    }
 
-            let nonce: XNonce = *GenericArray::from_slice(&raw_nonce);
-            let decrypted_message_bytes = contact.cipher.decrypt(&nonce, content.as_ref()).unwrap();
+    fn ids(contact: &ContactInfo) -> Vec<Option<u64>> {
+        contact.messages.iter().map(|m| m._id).collect()
+    }
 
-            contact.messages.push_front(
-                Message {
-                    _id: Some(fetched_message.id),
-                    sender_id: fetched_message.user_id,
-                    content: String::from_utf8(decrypted_message_bytes).unwrap()
-                }
-            );
+    #[test]
+    fn fetched_copy_of_a_stored_message_isnt_added_again() {
+        let mut contact = contact();
+        let mut stored_message = message(Some(5), "stored");
+        stored_message.sent_at = Some(Utc::now());
+        contact.messages.push_back(stored_message);
+
+        merge_fetched_messages(&mut contact, vec![fetched(6, [6; 24]), fetched(5, [5; 24]), fetched(4, [4; 24])]);
+
+        assert_eq!(ids(&contact), [Some(4), Some(5), Some(6)]);
+        let stored_message = contact.messages.iter().find(|m| m._id == Some(5)).unwrap();
+        assert_eq!(stored_message.content, "stored");
+        assert!(stored_message.is_stored);
+    }
+
+    #[test]
+    fn own_message_is_matched_by_nonce_and_confirmed() {
+        let mut contact = contact();
+        contact.messages.push_back(message(Some(5), "earlier"));
+        let mut own_message = message(None, "pending");
+        own_message.sender_id = OWN_ID;
+        own_message.status = MessageStatus::Pending;
+        own_message.nonce = Some([9; 24]);
+        own_message.is_stored = false;
+        contact.messages.push_back(own_message);
+
+        let mut fetched_message = fetched(6, [9; 24]);
+        fetched_message.user_id = OWN_ID;
+        let confirmed_nonces = merge_fetched_messages(&mut contact, vec![fetched_message]);
+
+        assert_eq!(confirmed_nonces, [[9; 24]]);
+        assert_eq!(ids(&contact), [Some(5), Some(6)]);
+        let own_message = contact.messages.back().unwrap();
+        assert_eq!(own_message.content, "pending");
+        assert!(own_message.status == MessageStatus::Sent);
+    }
+
+    #[test]
+    fn paging_offset_leaves_out_pending_and_imported_messages() {
+        let mut contact = contact();
+        contact.messages.push_back(message(Some(5), "fetched"));
+        let mut imported_message = message(Some(6), "imported");
+        imported_message.is_imported = true;
+        contact.messages.push_back(imported_message);
+        contact.messages.push_back(message(None, "pending"));
+
+        assert_eq!(contact.server_offset(), 1);
+
+        // once the server returns the imported message it counts like any other
+        merge_fetched_messages(&mut contact, vec![fetched(6, [6; 24])]);
+
+        assert_eq!(contact.server_offset(), 2);
+        assert_eq!(ids(&contact), [Some(5), Some(6), None]);
+    }
+
+    #[test]
+    fn only_new_or_changed_messages_are_stored_again() {
+        let mut contacts = vec![contact()];
+        contacts[0].messages.push_back(message(Some(5), "without a time"));
+        let mut new_message = message(Some(6), "new");
+        new_message.sent_at = Some(Utc::now());
+        new_message.is_stored = false;
+        contacts[0].messages.push_back(new_message);
+        let mut pending_message = message(None, "pending");
+        pending_message.is_stored = false;
+        contacts[0].messages.push_back(pending_message);
+
+        let stored_ids = |message_store: &MessageStore| -> Vec<u64> {
+            message_store.get(&CHAT_ID).map(|m| m.iter().map(|m| m.id).collect()).unwrap_or_default()
+        };
+        assert_eq!(stored_ids(&unstored_messages(&mut contacts)), [6]);
+        assert!(unstored_messages(&mut contacts).is_empty());
+
+        // the server's time for a stored message is written over the stored copy
+        merge_fetched_messages(&mut contacts[0], vec![fetched(5, [5; 24]), fetched(6, [6; 24])]);
+
+        let message_store = unstored_messages(&mut contacts);
+        assert_eq!(stored_ids(&message_store), [5]);
+        assert!(message_store[&CHAT_ID][0].sent_at.is_some());
+    }
+
+    fn chat_export(user_id: u64, contact_id: u64, messages: Vec<ExportedMessage>) -> ChatExport {
+        ChatExport {
+            chat_id: CHAT_ID,
+            user_id,
+            user_email: "me@example.com".to_owned(),
+            contact_id,
+            contact_email: "contact@example.com".to_owned(),
+            messages
+        }
+    }
+
+    fn exported(id: u64, sender_id: u64, content: Option<&str>) -> ExportedMessage {
+        ExportedMessage {
+            id,
+            sender_id,
+            sender_email: String::new(),
+            content: content.map(str::to_owned),
+            decrypt_error: None,
+            sent_at: Some(Utc::now())
         }
+    }
 
-        self.should_scroll_down = true;
-        self.is_fetching_messages = false;
+    #[test]
+    fn export_goes_back_only_into_its_own_chat() {
+        let contacts = vec![contact()];
+        let messages = || vec![exported(1, OWN_ID, Some("mine")), exported(2, CONTACT_ID, Some("theirs"))];
+
+        assert_eq!(export_contact_index(&contacts, OWN_ID, &chat_export(OWN_ID, CONTACT_ID, messages())), Ok(0));
+        assert!(export_contact_index(&contacts, OWN_ID, &chat_export(3, CONTACT_ID, messages())).is_err());
+        assert!(export_contact_index(&contacts, OWN_ID, &chat_export(OWN_ID, 3, messages())).is_err());
+
+        let mut outsider_messages = messages();
+        outsider_messages.push(exported(3, 3, Some("injected")));
+        assert!(export_contact_index(&contacts, OWN_ID, &chat_export(OWN_ID, CONTACT_ID, outsider_messages)).is_err());
+    }
+
+    #[test]
+    fn imported_messages_fill_in_by_id_and_only_replace_undecrypted_ones() {
+        let mut contact = contact();
+        contact.messages.push_back(message(Some(2), "opened"));
+        let mut undecrypted_message = message(Some(4), "");
+        undecrypted_message.decrypt_error = Some(DecryptError::NoMatchingKey);
+        contact.messages.push_back(undecrypted_message);
+
+        let imported_count = merge_imported_messages(&mut contact, vec![
+            exported(5, CONTACT_ID, Some("newer")),
+            exported(4, CONTACT_ID, Some("recovered")),
+            exported(3, OWN_ID, None),
+            exported(2, CONTACT_ID, Some("tampered")),
+            exported(1, OWN_ID, Some("older"))
+        ]);
+
+        assert_eq!(imported_count, 3);
+        assert_eq!(ids(&contact), [Some(1), Some(2), Some(4), Some(5)]);
+        let contents: Vec<&str> = contact.messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["older", "opened", "recovered", "newer"]);
+        assert!(contact.messages.iter().all(|m| m.decrypt_error.is_none()));
+        assert!(contact.messages.iter().filter(|m| m.content != "opened").all(|m| !m.is_stored));
+    }
+
+    #[test]
+    fn imported_messages_dont_move_the_paging_offset() {
+        let mut contact = contact();
+        contact.messages.push_back(message(Some(5), "fetched"));
+
+        merge_imported_messages(&mut contact, vec![exported(3, CONTACT_ID, Some("old")), exported(4, CONTACT_ID, Some("older page"))]);
+        assert_eq!(contact.server_offset(), 1);
+
+        // it counts once history paging reaches it, keeping the restored content
+        merge_fetched_messages(&mut contact, vec![fetched(4, [4; 24])]);
+        assert_eq!(contact.server_offset(), 2);
+        assert_eq!(contact.messages.iter().find(|m| m._id == Some(4)).unwrap().content, "older page");
     }
 }
@@ -1,10 +1,9 @@
 use crate::egui;
 use crate::thread::http_thread::TaskWrapper;
-use crate::task::create_account_task::CreateAccountTask;
-use crate::task::TaskResult;
+use crate::task::create_account_task::{CreateAccountTask, CreateAccountResponse};
+use crate::task::{TaskResult, TaskError};
 use crate::state::Page;
-use crate::util::keyring_handler::save_private_key;
-use serde::{Deserialize, Serialize};
+use crate::util::keyring_handler::{save_private_key, KeyStoreKind, PrivateKeySet, MIN_PASSPHRASE_LENGTH};
 use egui::{
     RichText,
     TextEdit,
@@ -12,31 +11,18 @@ use egui::{
 };
 use std::sync::mpsc::{Sender, Receiver};
 
-#[derive(Serialize, Deserialize)]
-struct HttpResponse {
-    message: String
-}
-
+#[derive(Default)]
 pub struct CreateAccountState {
     pub email: String,
     pub password: String,
     pub confirm_password: String,
+    pub passphrase: String,
+    pub confirm_passphrase: String,
+    pub pending_passphrase: Option<String>,
     pub success: String,
     pub error: String,
-    pub is_loading: bool
-}
-
-impl Default for CreateAccountState {
-    fn default() -> Self {
-        Self {
-            email: String::new(),
-            password: String::new(),
-            confirm_password: String::new(),
-            success: String::new(),
-            error: String::new(),
-            is_loading: false
-        }
-    }
+    pub is_loading: bool,
+    pub can_retry: bool
 }
 
 impl CreateAccountState {
@@ -70,6 +56,19 @@ impl CreateAccountState {
                             .password(true)
                     );
 
+                    ui.add_space(10.);
+                    ui.label(RichText::new("Protects the private key stored on this device").weak());
+                    ui.add(
+                        TextEdit::singleline(&mut self.passphrase)
+                            .hint_text("Key passphrase")
+                            .password(true)
+                    );
+                    ui.add(
+                        TextEdit::singleline(&mut self.confirm_passphrase)
+                            .hint_text("Confirm key passphrase")
+                            .password(true)
+                    );
+
                     ui.add_space(15.);
 
                     if self.is_loading {
@@ -85,24 +84,7 @@ impl CreateAccountState {
 
                             let login_button = egui::Button::new("Create");
                             if columns[1].add(login_button).clicked() {
-                                // TODO: wrap this into a function
-                                self.clear_messages();
-
-                                if self.password != self.confirm_password {
-                                    self.error = "Your passwords are not equal".to_owned();
-                                    return;
-                                }
-
-                                self.is_loading = true;
-
-                                let create_account_task = CreateAccountTask::new(
-                                    self.email.to_owned(),
-                                    self.password.to_owned()
-                                );
-                                let (task_wrapper, task_channel_receiver) = TaskWrapper::new(Box::new(create_account_task));
-
-                                http_thread.send(task_wrapper).unwrap();
-                                result_queue.push(task_channel_receiver);
+                                self.create_account(http_thread, result_queue);
                             }
                         });
                     }
@@ -115,27 +97,69 @@ impl CreateAccountState {
                         RichText::new(self.error.to_owned())
                             .color(Color32::RED)
                     );
+                    if self.can_retry && !self.is_loading && ui.button("Retry").clicked() {
+                        self.create_account(http_thread, result_queue);
+                    }
                 });
             });
         });
     }
 
-    pub fn handle_task_create_account(&mut self, task_result: TaskResult) {
-        self.is_loading = false;
+    fn create_account(
+        &mut self,
+        http_thread: &Sender<TaskWrapper>,
+        result_queue: &mut Vec<Receiver<TaskResult>>
+    ) {
+        self.clear_messages();
+        self.can_retry = false;
 
-        let parsed_response: HttpResponse = serde_json::from_str(task_result.response.as_ref()).unwrap();
-        if task_result.status_code >= 400 {
-            self.error = parsed_response.message;
+        if self.password != self.confirm_password {
+            self.error = "Your passwords are not equal".to_owned();
+            return;
+        }
+        if self.passphrase.len() < MIN_PASSPHRASE_LENGTH {
+            self.error = format!("Your key passphrase must have at least {} characters", MIN_PASSPHRASE_LENGTH);
             return;
         }
+        if self.passphrase != self.confirm_passphrase {
+            self.error = "Your key passphrases are not equal".to_owned();
+            return;
+        }
+
+        self.is_loading = true;
+        self.pending_passphrase = Some(self.passphrase.to_owned());
+
+        let create_account_task = CreateAccountTask::new(
+            self.email.to_owned(),
+            self.password.to_owned()
+        );
+        let (task_wrapper, task_channel_receiver) = TaskWrapper::new(Box::new(create_account_task));
+
+        http_thread.send(task_wrapper).unwrap();
+        result_queue.push(task_channel_receiver);
+    }
+
+    pub fn handle_task_create_account(&mut self, result: Result<CreateAccountResponse, TaskError>, key_store: KeyStoreKind) {
+        self.is_loading = false;
+        let passphrase = self.pending_passphrase.take().unwrap();
 
-        let private_key_params = task_result.private_key_params.expect("Private key params not defined");
-        let private_key_bytes = private_key_params.private_key.as_bytes();
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                self.can_retry = e.is_retryable();
+                self.error = e.to_string();
+                return;
+            }
+        };
 
-        save_private_key(private_key_params.email, private_key_bytes);
+        let private_key_params = response.private_key_params;
+        let private_keys = PrivateKeySet::new(private_key_params.private_key.to_bytes());
 
         self.clear_fields();
-        self.success = parsed_response.message;
+        match save_private_key(&*key_store.open(), &private_key_params.email, &private_keys, &passphrase) {
+            Ok(()) => self.success = response.message,
+            Err(e) => self.error = format!("Account created, but the private key couldn't be saved: {}", e)
+        }
     }
 
     fn clear_messages(&mut self) {
@@ -147,5 +171,7 @@ impl CreateAccountState {
         self.email.clear();
         self.password.clear();
         self.confirm_password.clear();
+        self.passphrase.clear();
+        self.confirm_passphrase.clear();
     }
 }
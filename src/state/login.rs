@@ -1,14 +1,26 @@
 use crate::egui;
 use crate::thread::http_thread::TaskWrapper;
-use crate::task::login_task::LoginTask;
-use crate::task::TaskResult;
+use crate::thread::key_store_thread::KeyStoreJob;
+use crate::task::login_task::{LoginTask, LoginResponse};
+use crate::task::update_public_key_task::UpdatePublicKeyTask;
+use crate::task::{TaskResult, TaskError, GenericResultError};
 use crate::state::Page;
-use crate::util::keyring_handler::get_private_key;
-use crate::util::encryption::generate_cipher;
-use super::ContactInfoJSON;
-use super::ContactInfo;
+use crate::util::keyring_handler::{
+    unseal_private_key,
+    save_private_key,
+    read_private_key,
+    import_private_key,
+    key_file_status,
+    KeyFileStatus,
+    KeyStoreKind,
+    KeyFileError,
+    PrivateKeySet,
+    MIN_PASSPHRASE_LENGTH,
+    DEFAULT_BACKUP_FILE_NAME
+};
+use crate::util::encryption::generate_assymetric_keypair;
+use crate::util::settings::{Settings, SettingsOverrides, ServerEndpoints, home_folder_path};
 use super::chat::ChatState;
-use crate::thread::websocket_thread::InviteMessage;
 use base64::prelude::*;
 use x25519_dalek::{StaticSecret, PublicKey};
 use egui::{
@@ -16,50 +28,82 @@ use egui::{
     TextEdit,
     Color32
 };
-use serde::{Deserialize, Serialize};
 use std::sync::mpsc::{Sender, Receiver};
-use std::collections::LinkedList;
-
-#[derive(Serialize, Deserialize)]
-struct HttpResponseError {
-    message: String
-}
-
-#[derive(Serialize, Deserialize)]
-struct HttpResponseSuccess {
-    token: String,
-    user_id: u64,
-    contacts: Vec<ContactInfoJSON>,
-    pending_sent_invites: Vec<InviteMessage>,
-    pending_received_invites: Vec<InviteMessage>
-}
+use std::path::Path;
 
+#[derive(Default)]
 pub struct LoginState {
     pub email: String,
     pub password: String,
     pub error: String,
-    pub is_loading: bool
-}
+    pub success: String,
+    pub is_loading: bool,
+    pub can_retry: bool,
 
-impl Default for LoginState {
-    fn default() -> Self {
-        Self {
-            email: String::new(),
-            password: String::new(),
-            error: String::new(),
-            is_loading: false
-        }
-    }
+    pub pending_login: Option<LoginResponse>,
+    pub should_read_private_key: bool,
+    // the backend a missing key is looked for in, only on the user's request
+    pub migrate_key_from: Option<KeyStoreKind>,
+    pub pending_raw_key: Option<Vec<u8>>,
+    pub show_unlock_modal: bool,
+    pub is_legacy_key: bool,
+    pub passphrase: String,
+    pub confirm_passphrase: String,
+    pub unlock_error: String,
+    pub is_unlocking: bool,
+    // keys being written by the key store thread and what goes on once they're stored
+    pub pending_key_save: Option<(PrivateKeySet, KeySave)>,
+
+    pub show_restore_modal: bool,
+    pub restore_path: String,
+    pub restore_passphrase: String,
+    pub restore_error: String,
+    pub is_restoring: bool,
+
+    pub show_key_problem_modal: bool,
+    pub key_problem: String,
+    pub new_key_passphrase: String,
+    pub new_key_confirm_passphrase: String,
+    pub new_key_error: String,
+    pub is_rotating_key: bool,
+    pub unlocked_private_keys: Option<PrivateKeySet>,
+    pub rotated_private_keys: Option<PrivateKeySet>,
+
+    pub show_server_modal: bool,
+    // the settings in use, which are the saved ones with the environment applied over them
+    pub server_settings: Settings,
+    pub saved_settings: Settings,
+    pub settings_overrides: SettingsOverrides,
+    pub server_settings_input: Settings,
+    pub server_error: String,
+    pub applied_endpoints: Option<ServerEndpoints>
 }
 
 impl LoginState {
     pub fn show_login_page(
         &mut self,
         http_thread: &Sender<TaskWrapper>,
+        key_store_thread: &Sender<KeyStoreJob>,
         result_queue: &mut Vec<Receiver<TaskResult>>,
         current_page: &mut Page,
         ctx: &egui::Context
     ) {
+        if self.should_read_private_key {
+            self.read_private_key(key_store_thread, result_queue);
+        }
+        if self.show_server_modal {
+            self.show_server_modal(ctx);
+        }
+        if self.show_unlock_modal {
+            self.show_unlock_modal(key_store_thread, result_queue, ctx);
+        }
+        if self.show_restore_modal {
+            self.show_restore_modal(key_store_thread, result_queue, ctx);
+        }
+        if self.show_key_problem_modal {
+            self.show_key_problem_modal(key_store_thread, result_queue, ctx);
+        }
+
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.vertical_centered(|ui| {
                 ui.add_space(50.);
@@ -92,69 +136,667 @@ impl LoginState {
 
                             let login_button = egui::Button::new("Login");
                             if columns[1].add_enabled(!self.is_loading, login_button).clicked() {
-                                self.error.clear();
-                                self.is_loading = true;
-
-                                let login_task = LoginTask::new(self.email.to_owned(), self.password.to_owned());
-                                let (task_wrapper, task_channel_receiver) = TaskWrapper::new(Box::new(login_task));
-
-                                http_thread.send(task_wrapper).unwrap();
-                                result_queue.push(task_channel_receiver);
+                                self.login(http_thread, result_queue);
                             }
                         });
                     }
 
+                    ui.label(
+                        RichText::new(self.success.to_owned())
+                            .color(Color32::GREEN)
+                    );
                     ui.label(
                         RichText::new(self.error.to_owned())
                             .color(Color32::RED)
                     );
+                    if self.can_retry && !self.is_loading && ui.button("Retry").clicked() {
+                        self.login(http_thread, result_queue);
+                    }
+
+                    ui.add_space(15.);
+                    if ui.add_enabled(!self.is_loading, egui::Button::new("Server")).clicked() {
+                        self.server_error.clear();
+                        self.server_settings_input = self.saved_settings.clone();
+                        self.show_server_modal = true;
+                    }
+                    ui.label(RichText::new(self.server_settings.http_url.to_owned()).weak());
+                    if ui.add_enabled(!self.is_loading, egui::Button::new("Restore key")).clicked() {
+                        self.open_restore_modal();
+                    }
                 });
             });
         });
     }
 
-    pub fn handle_task_login(
+    fn login(
         &mut self,
-        task_result: TaskResult,
+        http_thread: &Sender<TaskWrapper>,
+        result_queue: &mut Vec<Receiver<TaskResult>>
+    ) {
+        self.error.clear();
+        self.success.clear();
+        self.can_retry = false;
+        self.is_loading = true;
+
+        let login_task = LoginTask::new(self.email.to_owned(), self.password.to_owned());
+        let (task_wrapper, task_channel_receiver) = TaskWrapper::new(Box::new(login_task));
+
+        http_thread.send(task_wrapper).unwrap();
+        result_queue.push(task_channel_receiver);
+    }
+
+    fn show_server_modal(&mut self, ctx: &egui::Context) {
+        egui::Modal::new(egui::Id::new("modal_server")).show(ctx, |ui| {
+            ui.set_width(300.);
+            ui.label(RichText::new("Server").size(16.));
+            ui.add_space(8.);
+
+            ui.label("HTTP URL");
+            ui.add(
+                TextEdit::singleline(&mut self.server_settings_input.http_url)
+                    .hint_text("https://example.com/")
+                    .desired_width(f32::INFINITY)
+            );
+            ui.label("WebSocket URL");
+            ui.add(
+                TextEdit::singleline(&mut self.server_settings_input.ws_url)
+                    .hint_text("wss://example.com/ws_server")
+                    .desired_width(f32::INFINITY)
+            );
+            ui.label("CA certificate (optional)");
+            ui.add(
+                TextEdit::singleline(&mut self.server_settings_input.ca_cert_path)
+                    .hint_text("/path/to/ca.pem")
+                    .desired_width(f32::INFINITY)
+            );
+
+            ui.label("Private key storage");
+            egui::ComboBox::from_id_salt("key_store")
+                .selected_text(self.server_settings_input.key_store.label())
+                .show_ui(ui, |ui| {
+                    for key_store in KeyStoreKind::ALL {
+                        ui.selectable_value(&mut self.server_settings_input.key_store, key_store, key_store.label());
+                    }
+                });
+            if self.server_settings_input.key_store != self.saved_settings.key_store {
+                ui.label(
+                    RichText::new("A key kept in the other storage can be moved here when the login doesn't find one.").weak()
+                );
+            }
+
+            let overridden_variables = self.settings_overrides.variable_names();
+            if !overridden_variables.is_empty() {
+                ui.label(
+                    RichText::new(format!(
+                        "Set by the environment, not saved: {}",
+                        overridden_variables.join(", ")
+                    )).weak()
+                );
+            }
+
+            ui.label(
+                RichText::new(self.server_error.to_owned())
+                    .color(Color32::RED)
+            );
+            ui.separator();
+
+            ui.horizontal(|ui| {
+                if ui.button("Save").clicked() {
+                    let settings = self.server_settings_input.with_overrides(&self.settings_overrides);
+                    match settings.validate() {
+                        Ok(endpoints) => match self.server_settings_input.save() {
+                            Ok(()) => {
+                                self.saved_settings = self.server_settings_input.clone();
+                                self.server_settings = settings;
+                                self.applied_endpoints = Some(endpoints);
+                                self.show_server_modal = false;
+                            },
+                            Err(e) => self.server_error = format!("Couldn't save settings: {}", e)
+                        },
+                        Err(e) => self.server_error = e.to_string()
+                    }
+                }
+                if ui.button("Reset").clicked() {
+                    self.server_settings_input = Settings::default();
+                }
+                if ui.button("Cancel").clicked() {
+                    self.show_server_modal = false;
+                }
+            });
+        });
+    }
+
+    fn show_unlock_modal(
+        &mut self,
+        key_store_thread: &Sender<KeyStoreJob>,
+        result_queue: &mut Vec<Receiver<TaskResult>>,
+        ctx: &egui::Context
+    ) {
+        egui::Modal::new(egui::Id::new("modal_unlock")).show(ctx, |ui| {
+            ui.set_width(250.);
+            ui.label(RichText::new("Unlock private key").size(16.));
+            ui.add_space(8.);
+
+            if self.is_legacy_key {
+                ui.label("Your private key isn't protected yet. Choose a passphrase to encrypt it.");
+            }
+            let response = ui.add(
+                TextEdit::singleline(&mut self.passphrase)
+                    .hint_text("Passphrase")
+                    .password(true)
+            );
+            let mut submitted = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+            if self.is_legacy_key {
+                let response = ui.add(
+                    TextEdit::singleline(&mut self.confirm_passphrase)
+                        .hint_text("Confirm passphrase")
+                        .password(true)
+                );
+                submitted = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+            }
+
+            ui.label(
+                RichText::new(self.unlock_error.to_owned())
+                    .color(Color32::RED)
+            );
+            ui.separator();
+
+            if self.is_unlocking {
+                ui.spinner();
+                return;
+            }
+            ui.horizontal(|ui| {
+                if (ui.button("Unlock").clicked() || submitted) && !self.passphrase.is_empty() {
+                    self.unlock(key_store_thread, result_queue);
+                }
+                if ui.button("Cancel").clicked() {
+                    self.pending_login = None;
+                    self.pending_raw_key = None;
+                    self.passphrase.clear();
+                    self.confirm_passphrase.clear();
+                    self.show_unlock_modal = false;
+                }
+            });
+        });
+    }
+
+    fn unlock(&mut self, key_store_thread: &Sender<KeyStoreJob>, result_queue: &mut Vec<Receiver<TaskResult>>) {
+        // the legacy key gets sealed with what's typed here, so it's checked like a new passphrase
+        if self.is_legacy_key {
+            if self.passphrase.len() < MIN_PASSPHRASE_LENGTH {
+                self.unlock_error = format!("The passphrase must have at least {} characters", MIN_PASSPHRASE_LENGTH);
+                return;
+            }
+            if self.passphrase != self.confirm_passphrase {
+                self.unlock_error = "The passphrases are not equal".to_owned();
+                return;
+            }
+        }
+
+        let Some(raw_key) = self.pending_raw_key.clone() else {
+            return;
+        };
+        self.unlock_error.clear();
+        self.is_unlocking = true;
+
+        let key_store = self.server_settings.key_store;
+        let email = self.email.clone();
+        let passphrase = self.passphrase.clone();
+        let (key_store_job, job_channel_receiver) = KeyStoreJob::new(Box::new(move || {
+            TaskResult::UnsealPrivateKey(unseal_private_key(&*key_store.open(), &email, &raw_key, &passphrase))
+        }));
+
+        key_store_thread.send(key_store_job).unwrap();
+        result_queue.push(job_channel_receiver);
+    }
+
+    // a retired key can be the registered one when uploading a rotated key failed,
+    // in which case it's made current again and stored before the login goes on
+    pub fn handle_task_unseal_private_key(
+        &mut self,
+        result: Result<PrivateKeySet, KeyFileError>,
+        key_store_thread: &Sender<KeyStoreJob>,
+        result_queue: &mut Vec<Receiver<TaskResult>>,
         chat_state: &mut ChatState,
         page_state: &mut Page,
         ctx: &egui::Context
     ) {
-        self.is_loading = false;
+        self.is_unlocking = false;
+
+        let mut private_keys = match result {
+            Ok(private_keys) => private_keys,
+            Err(e) => {
+                self.unlock_error = e.to_string();
+                return;
+            }
+        };
+
+        let passphrase = std::mem::take(&mut self.passphrase);
+        self.pending_raw_key = None;
+        self.confirm_passphrase.clear();
+        self.unlock_error.clear();
+        self.show_unlock_modal = false;
+
+        let Some(login_response) = &self.pending_login else {
+            return;
+        };
+        let mut is_key_unchecked = false;
+        match find_registered_key(&private_keys, login_response) {
+            RegisteredKey::Current => {},
+            RegisteredKey::Previous(index) => {
+                private_keys.promote(index);
+                self.is_loading = true;
+                self.save_private_key(private_keys, passphrase, KeySave::Unlock, key_store_thread, result_queue);
+                return;
+            },
+            RegisteredKey::Unreported => is_key_unchecked = true,
+            RegisteredKey::Missing => {
+                self.unlocked_private_keys = Some(private_keys);
+                self.open_key_problem_modal(
+                    "The private key on this device doesn't match the one registered for this account, so your messages can't be decrypted with it."
+                );
+                return;
+            }
+        }
+
+        let login_response = self.pending_login.take().unwrap();
+        self.finish_login(login_response, private_keys, chat_state, page_state, ctx);
+        if is_key_unchecked {
+            chat_state.modal_error = UNCHECKED_KEY_WARNING.to_owned();
+        }
+    }
+
+    fn save_private_key(
+        &mut self,
+        private_keys: PrivateKeySet,
+        passphrase: String,
+        key_save: KeySave,
+        key_store_thread: &Sender<KeyStoreJob>,
+        result_queue: &mut Vec<Receiver<TaskResult>>
+    ) {
+        let key_store = self.server_settings.key_store;
+        let email = self.email.clone();
+        let sealed_keys = private_keys.clone();
+        let (key_store_job, job_channel_receiver) = KeyStoreJob::new(Box::new(move || {
+            TaskResult::SavePrivateKey(save_private_key(&*key_store.open(), &email, &sealed_keys, &passphrase))
+        }));
+        self.pending_key_save = Some((private_keys, key_save));
+
+        key_store_thread.send(key_store_job).unwrap();
+        result_queue.push(job_channel_receiver);
+    }
+
+    pub fn handle_task_save_private_key(
+        &mut self,
+        result: Result<(), std::io::Error>,
+        http_thread: &Sender<TaskWrapper>,
+        result_queue: &mut Vec<Receiver<TaskResult>>,
+        chat_state: &mut ChatState,
+        page_state: &mut Page,
+        ctx: &egui::Context
+    ) {
+        let Some((private_keys, key_save)) = self.pending_key_save.take() else {
+            return;
+        };
+
+        match key_save {
+            KeySave::Unlock => {
+                self.is_loading = false;
+                if let Err(e) = result {
+                    self.pending_login = None;
+                    self.error = format!("Couldn't store private key: {}", e);
+                    return;
+                }
+
+                let login_response = self.pending_login.take().unwrap();
+                self.finish_login(login_response, private_keys, chat_state, page_state, ctx);
+            },
+            KeySave::Restore { is_key_unchecked } => {
+                self.is_restoring = false;
+                if let Err(e) = result {
+                    self.restore_error = format!("Couldn't store restored key: {}", e);
+                    return;
+                }
+
+                self.restore_passphrase.clear();
+                self.restore_error.clear();
+                self.show_restore_modal = false;
+                self.unlocked_private_keys = None;
+
+                match self.pending_login.take() {
+                    Some(login_response) => {
+                        self.finish_login(login_response, private_keys, chat_state, page_state, ctx);
+                        if is_key_unchecked {
+                            chat_state.modal_error = UNCHECKED_KEY_WARNING.to_owned();
+                        }
+                    },
+                    None => self.success = "Private key restored".to_owned()
+                }
+            },
+            KeySave::Rotate { public_key } => {
+                if let Err(e) = result {
+                    self.is_rotating_key = false;
+                    self.unlocked_private_keys = Some(private_keys);
+                    self.new_key_error = format!("Couldn't store the new key: {}", e);
+                    return;
+                }
 
-        if task_result.status_code >= 400 {
-            let parsed_error: HttpResponseError = serde_json::from_str(task_result.response.as_ref()).unwrap();
-            self.error = parsed_error.message;
+                self.rotated_private_keys = Some(private_keys);
+
+                let token = self.pending_login.as_ref().unwrap().token.clone();
+                let update_public_key_task = UpdatePublicKeyTask::new(public_key, token);
+                let (task_wrapper, task_channel_receiver) = TaskWrapper::new(Box::new(update_public_key_task));
+
+                http_thread.send(task_wrapper).unwrap();
+                result_queue.push(task_channel_receiver);
+            }
+        }
+    }
+
+    fn open_key_problem_modal(&mut self, key_problem: &str) {
+        self.key_problem = key_problem.to_owned();
+        self.new_key_error.clear();
+        self.show_key_problem_modal = true;
+    }
+
+    fn show_key_problem_modal(
+        &mut self,
+        key_store_thread: &Sender<KeyStoreJob>,
+        result_queue: &mut Vec<Receiver<TaskResult>>,
+        ctx: &egui::Context
+    ) {
+        egui::Modal::new(egui::Id::new("modal_key_problem")).show(ctx, |ui| {
+            ui.set_width(300.);
+            ui.label(RichText::new("Private key problem").size(16.));
+            ui.add_space(8.);
+
+            ui.label(self.key_problem.to_owned());
+            ui.add_space(5.);
+            ui.label("Restore the key from a backup, or generate a new one. Messages encrypted for the old key can't be read with a new key.");
+            ui.add_space(5.);
+
+            if ui.add_enabled(!self.is_rotating_key, egui::Button::new("Restore from backup")).clicked() {
+                self.show_key_problem_modal = false;
+                self.open_restore_modal();
+            }
+            // the key may still sit in the storage used before a switch
+            if self.unlocked_private_keys.is_none() {
+                for key_store in KeyStoreKind::ALL {
+                    if key_store == self.server_settings.key_store {
+                        continue;
+                    }
+                    let button = egui::Button::new(format!("Move key from {}", key_store.label().to_lowercase()));
+                    if ui.add_enabled(!self.is_rotating_key, button).clicked() {
+                        self.show_key_problem_modal = false;
+                        self.migrate_key_from = Some(key_store);
+                        self.should_read_private_key = true;
+                        self.is_loading = true;
+                    }
+                }
+            }
+            ui.separator();
+
+            ui.add(
+                TextEdit::singleline(&mut self.new_key_passphrase)
+                    .hint_text("New key passphrase")
+                    .password(true)
+            );
+            ui.add(
+                TextEdit::singleline(&mut self.new_key_confirm_passphrase)
+                    .hint_text("Confirm new key passphrase")
+                    .password(true)
+            );
+            ui.label(
+                RichText::new(self.new_key_error.to_owned())
+                    .color(Color32::RED)
+            );
+            ui.separator();
+
+            if self.is_rotating_key {
+                ui.spinner();
+                return;
+            }
+            ui.horizontal(|ui| {
+                if ui.button("Generate new key").clicked() {
+                    self.rotate_key(key_store_thread, result_queue);
+                }
+                if ui.button("Cancel").clicked() {
+                    self.pending_login = None;
+                    self.unlocked_private_keys = None;
+                    self.new_key_passphrase.clear();
+                    self.new_key_confirm_passphrase.clear();
+                    self.show_key_problem_modal = false;
+                }
+            });
+        });
+    }
+
+    // the new key is stored before it's uploaded, so a failed upload can simply be tried again;
+    // a mismatched key that was unlocked is kept as a retired one
+    fn rotate_key(
+        &mut self,
+        key_store_thread: &Sender<KeyStoreJob>,
+        result_queue: &mut Vec<Receiver<TaskResult>>
+    ) {
+        self.new_key_error.clear();
+
+        if self.new_key_passphrase.len() < MIN_PASSPHRASE_LENGTH {
+            self.new_key_error = format!("The passphrase must have at least {} characters", MIN_PASSPHRASE_LENGTH);
+            return;
+        }
+        if self.new_key_passphrase != self.new_key_confirm_passphrase {
+            self.new_key_error = "The passphrases are not equal".to_owned();
             return;
         }
 
-        let private_key_bytes: [u8; 32] = get_private_key(self.email.clone()).try_into().unwrap();
-        let private_key = StaticSecret::from(private_key_bytes);
+        let (private_key, public_key) = generate_assymetric_keypair();
+        let private_keys = match self.unlocked_private_keys.take() {
+            Some(mut private_keys) => {
+                private_keys.rotate(private_key.to_bytes());
+                private_keys
+            },
+            None => PrivateKeySet::new(private_key.to_bytes())
+        };
 
-        let parsed_success: HttpResponseSuccess = serde_json::from_str(task_result.response.as_ref()).unwrap();
-        let parsed_contacts = parsed_success.contacts.into_iter().map(|contact| {
-            let contact_public_key_bytes: [u8; 32] = BASE64_STANDARD
-                .decode(contact.contact_public_key.clone())
-                .unwrap()
-                .try_into()
-                .unwrap();
-            let contact_public_key = PublicKey::from(contact_public_key_bytes);
-            let cipher = generate_cipher(contact_public_key, private_key.clone());
+        self.is_rotating_key = true;
+        let key_save = KeySave::Rotate { public_key: *public_key.as_bytes() };
+        self.save_private_key(private_keys, self.new_key_passphrase.clone(), key_save, key_store_thread, result_queue);
+    }
 
-            ContactInfo {
-                contact,
-                cipher,
-                should_fetch_messages: true,
-                messages: LinkedList::new(),
+    pub fn handle_task_update_public_key(
+        &mut self,
+        result: Result<GenericResultError, TaskError>,
+        chat_state: &mut ChatState,
+        page_state: &mut Page,
+        ctx: &egui::Context
+    ) {
+        self.is_rotating_key = false;
+        let private_keys = self.rotated_private_keys.take().unwrap();
+
+        if let Err(e) = result {
+            self.unlocked_private_keys = Some(private_keys);
+            self.new_key_error = e.to_string();
+            return;
+        }
+
+        self.new_key_passphrase.clear();
+        self.new_key_confirm_passphrase.clear();
+        self.show_key_problem_modal = false;
+
+        let login_response = self.pending_login.take().unwrap();
+        self.finish_login(login_response, private_keys, chat_state, page_state, ctx);
+    }
+
+    fn open_restore_modal(&mut self) {
+        self.restore_path = home_folder_path().join(DEFAULT_BACKUP_FILE_NAME).display().to_string();
+        self.restore_error.clear();
+        self.show_restore_modal = true;
+    }
+
+    fn show_restore_modal(
+        &mut self,
+        key_store_thread: &Sender<KeyStoreJob>,
+        result_queue: &mut Vec<Receiver<TaskResult>>,
+        ctx: &egui::Context
+    ) {
+        egui::Modal::new(egui::Id::new("modal_restore")).show(ctx, |ui| {
+            ui.set_width(300.);
+            ui.label(RichText::new("Restore private key").size(16.));
+            ui.add_space(8.);
+
+            if self.pending_login.is_some() {
+                ui.label("Restore the key registered for this account to continue.");
             }
-        }).collect();
+            ui.label("Backup file");
+            ui.add(
+                TextEdit::singleline(&mut self.restore_path)
+                    .desired_width(f32::INFINITY)
+            );
+            ui.add(
+                TextEdit::singleline(&mut self.restore_passphrase)
+                    .hint_text("Backup passphrase")
+                    .password(true)
+            );
+            ui.label(RichText::new("The restored key will be protected with the same passphrase.").weak());
 
-        chat_state.contacts = parsed_contacts;
-        chat_state.token = parsed_success.token;
-        chat_state.user_id = parsed_success.user_id;
-        chat_state.sent_invites = parsed_success.pending_sent_invites;
-        chat_state.received_invites = parsed_success.pending_received_invites;
-        chat_state.private_key = Some(private_key);
+            ui.label(
+                RichText::new(self.restore_error.to_owned())
+                    .color(Color32::RED)
+            );
+            ui.separator();
+
+            if self.is_restoring {
+                ui.spinner();
+                return;
+            }
+            ui.horizontal(|ui| {
+                if ui.button("Restore").clicked() {
+                    self.restore(key_store_thread, result_queue);
+                }
+                if ui.button("Cancel").clicked() {
+                    self.restore_passphrase.clear();
+                    self.show_restore_modal = false;
+                    // back to the choice between restoring and generating a new key
+                    self.show_key_problem_modal = self.pending_login.is_some();
+                }
+            });
+        });
+    }
+
+    fn restore(&mut self, key_store_thread: &Sender<KeyStoreJob>, result_queue: &mut Vec<Receiver<TaskResult>>) {
+        if self.email.is_empty() {
+            self.restore_error = "Type the account's e-mail on the login page first".to_owned();
+            return;
+        }
+
+        let mut private_keys = match import_private_key(Path::new(self.restore_path.trim()), &self.restore_passphrase) {
+            Ok(private_keys) => private_keys,
+            Err(e) => {
+                self.restore_error = e.to_string();
+                return;
+            }
+        };
+        // keys unlocked on this device may have sealed the local stores, they're kept as retired ones
+        if let Some(unlocked_private_keys) = &self.unlocked_private_keys {
+            private_keys.merge(unlocked_private_keys);
+        }
+
+        let mut is_key_unchecked = false;
+        if let Some(login_response) = &self.pending_login {
+            match find_registered_key(&private_keys, login_response) {
+                RegisteredKey::Current => {},
+                RegisteredKey::Previous(index) => private_keys.promote(index),
+                RegisteredKey::Unreported => is_key_unchecked = true,
+                RegisteredKey::Missing => {
+                    self.restore_error = "This backup holds a different key than the one registered for this account".to_owned();
+                    return;
+                }
+            }
+        }
+
+        self.restore_error.clear();
+        self.is_restoring = true;
+        let key_save = KeySave::Restore { is_key_unchecked };
+        self.save_private_key(private_keys, self.restore_passphrase.clone(), key_save, key_store_thread, result_queue);
+    }
+
+    pub fn handle_task_login(
+        &mut self,
+        result: Result<LoginResponse, TaskError>
+    ) {
+        let login_response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                self.is_loading = false;
+                self.can_retry = e.is_retryable();
+                self.error = e.to_string();
+                return;
+            }
+        };
+
+        // still loading until the key is read
+        self.pending_login = Some(login_response);
+        self.should_read_private_key = true;
+    }
+
+    fn read_private_key(
+        &mut self,
+        key_store_thread: &Sender<KeyStoreJob>,
+        result_queue: &mut Vec<Receiver<TaskResult>>
+    ) {
+        self.should_read_private_key = false;
+
+        let key_store = self.server_settings.key_store;
+        let migrate_from = self.migrate_key_from.take();
+        let email = self.email.clone();
+        let (key_store_job, job_channel_receiver) = KeyStoreJob::new(Box::new(move || {
+            TaskResult::ReadPrivateKey(read_private_key(key_store, migrate_from, &email))
+        }));
+
+        key_store_thread.send(key_store_job).unwrap();
+        result_queue.push(job_channel_receiver);
+    }
+
+    pub fn handle_task_read_private_key(&mut self, result: Result<Option<Vec<u8>>, std::io::Error>) {
+        self.is_loading = false;
+
+        let raw_key = match result {
+            Ok(Some(raw_key)) => raw_key,
+            Ok(None) => {
+                self.open_key_problem_modal("No private key was found for this account on this device.");
+                return;
+            },
+            Err(e) => {
+                self.pending_login = None;
+                self.error = format!("Couldn't access private key store: {}", e);
+                return;
+            }
+        };
+
+        self.is_legacy_key = matches!(key_file_status(&raw_key), KeyFileStatus::Legacy);
+        self.pending_raw_key = Some(raw_key);
+        self.unlock_error.clear();
+        self.show_unlock_modal = true;
+    }
+
+    fn finish_login(
+        &mut self,
+        login_response: LoginResponse,
+        private_keys: PrivateKeySet,
+        chat_state: &mut ChatState,
+        page_state: &mut Page,
+        ctx: &egui::Context
+    ) {
+        chat_state.token = login_response.token;
+        chat_state.user_id = login_response.user_id;
+        chat_state.email = self.email.clone();
+        chat_state.key_store = self.server_settings.key_store;
+        chat_state.sent_invites = login_response.pending_sent_invites;
+        chat_state.received_invites = login_response.pending_received_invites;
+        chat_state.private_key = Some(StaticSecret::from(private_keys.current));
+        chat_state.previous_private_keys = private_keys.previous.into_iter().map(StaticSecret::from).collect();
+        chat_state.set_contacts(login_response.contacts);
+        chat_state.load_outbox();
 
         match chat_state.connect_websocket(ctx) {
             Ok(()) => *page_state = Page::Chat,
@@ -162,3 +804,37 @@ impl LoginState {
         }
     }
 }
+
+pub enum KeySave {
+    Unlock,
+    Restore { is_key_unchecked: bool },
+    Rotate { public_key: [u8; 32] }
+}
+
+const UNCHECKED_KEY_WARNING: &str = "The server didn't report which key is registered for this account, so the private key on this device couldn't be checked against it. If your contacts can't read your messages, restore a backup of the registered key.";
+
+enum RegisteredKey {
+    Current,
+    Previous(usize),
+    // the server didn't send the registered key, so nothing could be compared
+    Unreported,
+    Missing
+}
+
+fn find_registered_key(private_keys: &PrivateKeySet, login_response: &LoginResponse) -> RegisteredKey {
+    let Some(public_key) = login_response.public_key.as_ref()
+        .and_then(|public_key| BASE64_STANDARD.decode(public_key).ok()) else {
+        return RegisteredKey::Unreported;
+    };
+    let is_registered = |private_key: &[u8; 32]| {
+        PublicKey::from(&StaticSecret::from(*private_key)).as_bytes()[..] == public_key[..]
+    };
+
+    if is_registered(&private_keys.current) {
+        return RegisteredKey::Current;
+    }
+    match private_keys.previous.iter().position(is_registered) {
+        Some(index) => RegisteredKey::Previous(index),
+        None => RegisteredKey::Missing
+    }
+}
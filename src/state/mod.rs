@@ -1,5 +1,12 @@
+use crate::util::encryption::{generate_cipher, decrypt_cipher_text, DecryptError};
+use crate::util::ratchet::RatchetSession;
+use crate::util::message_store::StoredMessage;
+use crate::util::time_format::deserialize_timestamp;
 use serde::{Deserialize, Serialize};
 use chacha20poly1305::XChaCha20Poly1305;
+use base64::prelude::*;
+use chrono::{DateTime, Utc};
+use x25519_dalek::{StaticSecret, PublicKey};
 use std::collections::LinkedList;
 
 pub enum Page {
@@ -17,10 +24,43 @@ pub struct ContactInfoJSON {
     pub contact_public_key: String
 }
 
+#[derive(Clone, Copy, PartialEq)]
+pub enum MessageStatus {
+    Pending,
+    Sent,
+    Failed
+}
+
 pub struct Message {
     pub _id: Option<u64>,
     pub content: String,
-    pub sender_id: u64
+    pub sender_id: u64,
+    pub status: MessageStatus,
+    pub nonce: Option<[u8; 24]>,
+    // set when the content couldn't be opened, the view shows a placeholder instead
+    pub decrypt_error: Option<DecryptError>,
+    // the server's time once it has the message, the local send time until then
+    pub sent_at: Option<DateTime<Utc>>,
+    // restored from an export and not seen on the server yet, so it isn't counted in the paging offset
+    pub is_imported: bool,
+    // already in the local store as it is now
+    pub is_stored: bool
+}
+
+impl From<StoredMessage> for Message {
+    fn from(stored_message: StoredMessage) -> Self {
+        Self {
+            _id: Some(stored_message.id),
+            content: stored_message.content,
+            sender_id: stored_message.sender_id,
+            status: MessageStatus::Sent,
+            nonce: None,
+            decrypt_error: stored_message.decrypt_error,
+            sent_at: stored_message.sent_at,
+            is_imported: stored_message.is_imported,
+            is_stored: true
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -29,16 +69,144 @@ pub struct FetchMessage {
     pub user_id: u64,
     pub chat_id: u64,
     pub content: String,
-    pub nonce: String
+    pub nonce: String,
+    #[serde(default, deserialize_with = "deserialize_timestamp")]
+    pub created_at: Option<DateTime<Utc>>
 }
 
 pub struct ContactInfo {
     pub contact: ContactInfoJSON,
+    // contact_public_key decoded, it's checked once when it comes from the server
+    pub public_key: PublicKey,
+    // own current key, a contact who lost their session starts a new one from it
+    pub private_key: StaticSecret,
     pub cipher: XChaCha20Poly1305,
+    // pairs of retired keys on either side, only used to open older messages
+    pub legacy_ciphers: Vec<XChaCha20Poly1305>,
+    pub session: RatchetSession,
     pub should_fetch_messages: bool,
+    // newest message id known when the running sync started, it pages back until reaching it
+    pub sync_after_id: Option<u64>,
+    // when the running catch-up sync started, messages written before that it doesn't find are sent again
+    pub sync_started_at: Option<DateTime<Utc>>,
+    // the registered key differs from the last one seen, shown until the contact is verified
+    pub key_changed: bool,
     pub messages: LinkedList<Message>
 }
 
+impl ContactInfo {
+    // private_keys holds the current key first; every pair but (current, current) is legacy
+    pub fn new(
+        contact: ContactInfoJSON,
+        public_key: PublicKey,
+        user_id: u64,
+        private_keys: &[StaticSecret],
+        previous_public_keys: &[PublicKey],
+        session: Option<RatchetSession>
+    ) -> Self {
+        let session = match session {
+            Some(mut session) => {
+                if !session.is_for(&private_keys[0], &public_key) {
+                    session.restart(&private_keys[0], &public_key);
+                }
+                session
+            },
+            None => RatchetSession::new(&private_keys[0], user_id, &public_key, contact.contact_id)
+        };
+        let public_keys: Vec<PublicKey> = std::iter::once(public_key)
+            .chain(previous_public_keys.iter().copied())
+            .collect();
+
+        let mut ciphers = private_keys.iter().flat_map(|private_key| {
+            public_keys.iter().map(|public_key| generate_cipher(*public_key, private_key.clone()))
+        });
+
+        Self {
+            contact,
+            public_key,
+            private_key: private_keys[0].clone(),
+            cipher: ciphers.next().unwrap(),
+            legacy_ciphers: ciphers.collect(),
+            session,
+            should_fetch_messages: true,
+            sync_after_id: None,
+            sync_started_at: None,
+            key_changed: false,
+            messages: LinkedList::new()
+        }
+    }
+
+    // the server pages history newest first, so the offset is how many of its messages are
+    // already here; pending ones and imports it hasn't returned yet don't count
+    pub fn server_offset(&self) -> u64 {
+        self.messages.iter()
+            .filter(|m| m._id.is_some() && !m.is_imported)
+            .count() as u64
+    }
+
+    pub fn rotate_public_key(&mut self, public_key: String, private_key: StaticSecret) -> Result<(), std::io::Error> {
+        let decoded_public_key = decode_public_key(&public_key)?;
+        self.session.restart(&private_key, &decoded_public_key);
+        let cipher = generate_cipher(decoded_public_key, private_key);
+        self.contact.contact_public_key = public_key;
+        self.public_key = decoded_public_key;
+        self.replace_cipher(cipher);
+        Ok(())
+    }
+
+    pub fn rotate_private_key(&mut self, private_key: StaticSecret) {
+        self.session.restart(&private_key, &self.public_key);
+        self.private_key = private_key.clone();
+        let cipher = generate_cipher(self.public_key, private_key);
+        self.replace_cipher(cipher);
+    }
+
+    fn replace_cipher(&mut self, cipher: XChaCha20Poly1305) {
+        let old_cipher = std::mem::replace(&mut self.cipher, cipher);
+        self.legacy_ciphers.insert(0, old_cipher);
+    }
+
+    pub fn encrypt(&mut self, plain_text: &str) -> (Vec<u8>, [u8; 24]) {
+        self.session.encrypt(plain_text, self.contact.chat_id)
+    }
+
+    // anything the session can't open is tried as a message sealed with the static keys,
+    // as long as the session still takes one with this id
+    pub fn decrypt(
+        &mut self,
+        cipher_text: &[u8],
+        nonce: &[u8; 24],
+        sender_id: u64,
+        message_id: Option<u64>
+    ) -> Result<String, DecryptError> {
+        match self.session.decrypt(&self.private_key, cipher_text, nonce, self.contact.chat_id, sender_id, message_id) {
+            Err(DecryptError::NoMatchingKey) => {},
+            result => return result
+        }
+
+        self.session.check_static_message(nonce, message_id)?;
+        let plain_text = std::iter::once(&self.cipher)
+            .chain(self.legacy_ciphers.iter())
+            .map(|cipher| decrypt_cipher_text(cipher, cipher_text, nonce))
+            .find(|result| *result != Err(DecryptError::NoMatchingKey))
+            .unwrap_or(Err(DecryptError::NoMatchingKey))?;
+
+        self.session.record_static_message(nonce, message_id);
+        Ok(plain_text)
+    }
+}
+
+// keys come from the server, anything but 32 bytes of base64 is refused
+pub fn decode_public_key(public_key: &str) -> Result<PublicKey, std::io::Error> {
+    let public_key_bytes: [u8; 32] = BASE64_STANDARD
+        .decode(public_key)
+        .ok()
+        .and_then(|public_key_bytes| public_key_bytes.try_into().ok())
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "The public key isn't 32 bytes of base64"))?;
+
+    Ok(PublicKey::from(public_key_bytes))
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ChatInfoJSON {
     pub id: u64
@@ -47,3 +215,69 @@ pub struct ChatInfoJSON {
 pub mod login;
 pub mod create_account;
 pub mod chat;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chacha20poly1305::{AeadCore, aead::Aead};
+
+    const CHAT_ID: u64 = 7;
+    const ALICE_ID: u64 = 1;
+    const BOB_ID: u64 = 2;
+
+    // bob's view of alice, and alice's private key to write to him with
+    fn alice_for_bob() -> (ContactInfo, StaticSecret, PublicKey) {
+        let alice_private_key = StaticSecret::random_from_rng(rand::thread_rng());
+        let bob_private_key = StaticSecret::random_from_rng(rand::thread_rng());
+        let alice_public_key = PublicKey::from(&alice_private_key);
+        let contact = ContactInfoJSON {
+            id: 1,
+            contact_id: ALICE_ID,
+            chat_id: CHAT_ID,
+            contact_email: "alice@example.com".to_owned(),
+            contact_public_key: BASE64_STANDARD.encode(alice_public_key.as_bytes())
+        };
+
+        let bob_public_key = PublicKey::from(&bob_private_key);
+        let alice = ContactInfo::new(contact, alice_public_key, BOB_ID, &[bob_private_key], &[], None);
+        (alice, alice_private_key, bob_public_key)
+    }
+
+    fn seal_static(alice_private_key: &StaticSecret, bob_public_key: PublicKey, plain_text: &str) -> (Vec<u8>, [u8; 24]) {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut rand::thread_rng());
+        let cipher_text = generate_cipher(bob_public_key, alice_private_key.clone())
+            .encrypt(&nonce, plain_text.as_bytes())
+            .unwrap();
+        (cipher_text, *nonce.as_ref())
+    }
+
+    #[test]
+    fn static_message_under_another_id_is_a_replay() {
+        let (mut alice, alice_private_key, bob_public_key) = alice_for_bob();
+        let (cipher_text, nonce) = seal_static(&alice_private_key, bob_public_key, "old");
+
+        assert_eq!(alice.decrypt(&cipher_text, &nonce, ALICE_ID, Some(5)).unwrap(), "old");
+        assert_eq!(alice.decrypt(&cipher_text, &nonce, ALICE_ID, Some(6)), Err(DecryptError::Replayed));
+    }
+
+    #[test]
+    fn static_message_newer_than_the_first_ratchet_one_is_refused() {
+        let (mut alice, alice_private_key, bob_public_key) = alice_for_bob();
+        let mut alice_session = RatchetSession::new(&alice_private_key, ALICE_ID, &bob_public_key, BOB_ID);
+        let (content, nonce) = alice_session.encrypt("new", CHAT_ID);
+        alice.decrypt(&content, &nonce, ALICE_ID, Some(10)).unwrap();
+
+        let (cipher_text, nonce) = seal_static(&alice_private_key, bob_public_key, "injected");
+        assert_eq!(alice.decrypt(&cipher_text, &nonce, ALICE_ID, Some(11)), Err(DecryptError::NoMatchingKey));
+        assert_eq!(alice.decrypt(&cipher_text, &nonce, ALICE_ID, Some(9)).unwrap(), "injected");
+    }
+
+    #[test]
+    fn malformed_public_key_is_refused() {
+        let public_key = PublicKey::from(&StaticSecret::random_from_rng(rand::thread_rng()));
+
+        assert!(decode_public_key(&BASE64_STANDARD.encode(public_key.as_bytes())).is_ok_and(|k| k == public_key));
+        assert!(decode_public_key("not base64!").is_err());
+        assert!(decode_public_key(&BASE64_STANDARD.encode([1; 31])).is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use super::{Task, TaskResult, TaskType};
+use super::{Task, TaskResult, TaskType, decode_response};
 use reqwest::header::{HeaderMap, HeaderValue};
 
 pub struct AcceptInviteContactTask {
@@ -13,23 +13,16 @@ impl AcceptInviteContactTask {
 }
 
 impl Task for AcceptInviteContactTask {
-    fn exec(&self, http_client: &crate::http::HttpClient) -> Result<TaskResult, std::io::Error> {
+    fn task_type(&self) -> TaskType {
+        TaskType::AcceptInviteContact
+    }
+
+    fn exec(&self, http_client: &crate::http::HttpClient) -> TaskResult {
         let mut headers = HeaderMap::new();
         headers.insert("authToken", HeaderValue::from_str(&self.token).unwrap());
 
         let path = format!("user_api/contact/accept-invite/{}", self.contact_id);
         let response = http_client.post(&path, None, Some(headers));
-        match response {
-            Ok(r) => {
-                let result = TaskResult::new(
-                    r.status().as_u16(),
-                    r.text().unwrap(),
-                    None,
-                    TaskType::AcceptInviteContact
-                );
-                Ok(result)
-            },
-            Err(_) => Err(std::io::Error::new(std::io::ErrorKind::HostUnreachable, "Couldn't send request"))
-        }
+        TaskResult::AcceptInviteContact(self.contact_id, decode_response(response))
     }
 }
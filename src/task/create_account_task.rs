@@ -1,4 +1,4 @@
-use super::{Task, TaskResult, TaskType};
+use super::{Task, TaskResult, TaskType, GenericResultError, decode_response};
 use crate::util::encryption::generate_assymetric_keypair;
 use serde_json::json;
 use x25519_dalek::StaticSecret;
@@ -25,8 +25,17 @@ impl PrivateKeyParams {
     }
 }
 
+pub struct CreateAccountResponse {
+    pub message: String,
+    pub private_key_params: PrivateKeyParams
+}
+
 impl Task for CreateAccountTask {
-    fn exec(&self, http_client: &crate::http::HttpClient) -> Result<TaskResult, std::io::Error> {
+    fn task_type(&self) -> TaskType {
+        TaskType::CreateAccount
+    }
+
+    fn exec(&self, http_client: &crate::http::HttpClient) -> TaskResult {
         let (private_key, public_key) = generate_assymetric_keypair();
         let public_key_bytes = public_key.as_bytes();
 
@@ -37,18 +46,13 @@ impl Task for CreateAccountTask {
         });
 
         let response = http_client.post("user_api/user/create", Some(body), None);
-        match response {
-            Ok(r) => {
-                let private_key_params = PrivateKeyParams::new(self.email.clone(), private_key);
-                let result = TaskResult::new(
-                    r.status().as_u16(),
-                    r.text().unwrap(),
-                    Some(private_key_params),
-                    TaskType::CreateAccount
-                );
-                Ok(result)
-            },
-            Err(_) => Err(std::io::Error::new(std::io::ErrorKind::HostUnreachable, "Couldn't send request"))
-        }
+        let result = decode_response::<GenericResultError>(response).map(|success| {
+            CreateAccountResponse {
+                message: success.message,
+                private_key_params: PrivateKeyParams::new(self.email.clone(), private_key)
+            }
+        });
+
+        TaskResult::CreateAccount(result)
     }
 }
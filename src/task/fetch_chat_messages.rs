@@ -1,10 +1,11 @@
-use super::{Task, TaskResult, TaskType};
+use super::{DedupKey, Task, TaskResult, TaskType, decode_response};
 use reqwest::header::{HeaderMap, HeaderValue};
 
 pub struct FetchChatMessagesTask {
     chat_id: u64,
     offset: u64,
-    token: String
+    token: String,
+    is_sync: bool
 }
 
 impl FetchChatMessagesTask {
@@ -12,29 +13,49 @@ impl FetchChatMessagesTask {
         Self {
             chat_id,
             offset,
-            token
+            token,
+            is_sync: false
+        }
+    }
+
+    // pages back from the latest message so messages missed while offline can be merged in
+    pub fn sync(chat_id: u64, offset: u64, token: String) -> Self {
+        Self {
+            chat_id,
+            offset,
+            token,
+            is_sync: true
         }
     }
 }
 
 impl Task for FetchChatMessagesTask {
-    fn exec(&self, http_client: &crate::http::HttpClient) -> Result<TaskResult, std::io::Error> {
+    fn task_type(&self) -> TaskType {
+        if self.is_sync { TaskType::SyncChatMessages } else { TaskType::FetchChatMessages }
+    }
+
+    fn fairness_key(&self) -> u64 {
+        self.chat_id
+    }
+
+    fn dedup_key(&self) -> Option<DedupKey> {
+        if self.is_sync {
+            Some(DedupKey::Coalesce(format!("sync_chat_messages/{}/{}", self.chat_id, self.offset)))
+        } else {
+            Some(DedupKey::Coalesce(format!("fetch_chat_messages/{}/{}", self.chat_id, self.offset)))
+        }
+    }
+
+    fn exec(&self, http_client: &crate::http::HttpClient) -> TaskResult {
         let mut headers = HeaderMap::new();
         headers.insert("authToken", HeaderValue::from_str(&self.token).unwrap());
 
         let path = format!("chat_api/message/{}/{}", self.chat_id, self.offset);
         let response = http_client.get(&path, None, Some(headers));
-        match response {
-            Ok(r) => {
-                let result = TaskResult::new(
-                    r.status().as_u16(),
-                    r.text().unwrap(),
-                    None,
-                    TaskType::FetchChatMessages
-                );
-                Ok(result)
-            },
-            Err(_) => Err(std::io::Error::new(std::io::ErrorKind::HostUnreachable, "Couldn't send request"))
+        if self.is_sync {
+            TaskResult::SyncChatMessages(self.chat_id, self.offset, decode_response(response))
+        } else {
+            TaskResult::FetchChatMessages(self.chat_id, decode_response(response))
         }
     }
 }
@@ -0,0 +1,39 @@
+use super::{DedupKey, Task, TaskResult, TaskType, decode_response};
+use reqwest::header::{HeaderMap, HeaderValue};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+pub struct PublicKeyResponse {
+    // base64, like contact_public_key
+    pub public_key: String
+}
+
+pub struct FetchPublicKeyTask {
+    user_id: u64,
+    token: String
+}
+
+impl FetchPublicKeyTask {
+    pub fn new(user_id: u64, token: String) -> Self {
+        Self { user_id, token }
+    }
+}
+
+impl Task for FetchPublicKeyTask {
+    fn task_type(&self) -> TaskType {
+        TaskType::FetchPublicKey
+    }
+
+    fn dedup_key(&self) -> Option<DedupKey> {
+        Some(DedupKey::Coalesce(format!("fetch_public_key/{}", self.user_id)))
+    }
+
+    fn exec(&self, http_client: &crate::http::HttpClient) -> TaskResult {
+        let mut headers = HeaderMap::new();
+        headers.insert("authToken", HeaderValue::from_str(&self.token).unwrap());
+
+        let path = format!("user_api/user/public-key/{}", self.user_id);
+        let response = http_client.get(&path, None, Some(headers));
+        TaskResult::FetchPublicKey(self.user_id, decode_response(response))
+    }
+}
@@ -1,6 +1,21 @@
-use super::{Task, TaskResult, TaskType};
+use super::{Task, TaskResult, TaskType, decode_response};
+use crate::state::ContactInfoJSON;
+use crate::thread::websocket_thread::InviteMessage;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 
+#[derive(Serialize, Deserialize)]
+pub struct LoginResponse {
+    pub token: String,
+    pub user_id: u64,
+    pub contacts: Vec<ContactInfoJSON>,
+    pub pending_sent_invites: Vec<InviteMessage>,
+    pub pending_received_invites: Vec<InviteMessage>,
+    // base64, the copy registered with the server; older servers don't send it
+    #[serde(default)]
+    pub public_key: Option<String>
+}
+
 pub struct LoginTask {
     email: String,
     password: String
@@ -13,24 +28,17 @@ impl LoginTask {
 }
 
 impl Task for LoginTask {
-    fn exec(&self, http_client: &crate::http::HttpClient) -> Result<TaskResult, std::io::Error> {
+    fn task_type(&self) -> TaskType {
+        TaskType::Login
+    }
+
+    fn exec(&self, http_client: &crate::http::HttpClient) -> TaskResult {
         let body = json!({
             "email": self.email,
             "password": self.password
         });
 
         let response = http_client.post("user_api/user/login", Some(body), None);
-        match response {
-            Ok(r) => {
-                let result = TaskResult::new(
-                    r.status().as_u16(),
-                    r.text().unwrap(),
-                    None,
-                    TaskType::Login
-                );
-                Ok(result)
-            },
-            Err(_) => Err(std::io::Error::new(std::io::ErrorKind::HostUnreachable, "Couldn't send request"))
-        }
+        TaskResult::Login(decode_response(response))
     }
 }
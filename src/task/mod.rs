@@ -1,21 +1,69 @@
 use crate::http::HttpClient;
-use create_account_task::PrivateKeyParams;
-use serde::{Deserialize, Serialize};
+use crate::state::FetchMessage;
+use crate::thread::websocket_thread::{AcceptInvite, InviteMessage};
+use crate::util::keyring_handler::{PrivateKeySet, KeyFileError};
+use login_task::LoginResponse;
+use create_account_task::CreateAccountResponse;
+use search_user_task::SearchUserResponse;
+use fetch_public_key_task::PublicKeyResponse;
+use reqwest::blocking::Response;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
+#[derive(Clone, Copy, PartialEq)]
 pub enum TaskType {
     Login,
     CreateAccount,
     SearchUser,
     SendInviteContact,
     AcceptInviteContact,
-    FetchChatMessages
+    FetchChatMessages,
+    SyncChatMessages,
+    UpdatePublicKey,
+    FetchPublicKey
 }
 
-pub struct TaskResult {
-    pub status_code: u16,
-    pub response: String,
-    pub task_type: TaskType,
-    pub private_key_params: Option<PrivateKeyParams>
+impl TaskType {
+    // lower runs first: user actions, then history paging, then background syncs
+    pub fn priority(&self) -> usize {
+        match self {
+            Self::Login
+            | Self::CreateAccount
+            | Self::SearchUser
+            | Self::SendInviteContact
+            | Self::AcceptInviteContact
+            | Self::UpdatePublicKey => 0,
+            Self::FetchChatMessages
+            | Self::FetchPublicKey => 1,
+            Self::SyncChatMessages => 2
+        }
+    }
+}
+
+pub enum TaskError {
+    Network(String),
+    Api { status_code: u16, message: String },
+    Decode(String)
+}
+
+impl TaskError {
+    // client errors won't change by sending the same request again
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Network(_) => true,
+            Self::Api { status_code, .. } => *status_code >= 500,
+            Self::Decode(_) => false
+        }
+    }
+}
+
+impl std::fmt::Display for TaskError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Network(message) => write!(f, "Couldn't reach the server: {}", message),
+            Self::Api { message, .. } => write!(f, "{}", message),
+            Self::Decode(message) => write!(f, "Unexpected response from the server: {}", message)
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -23,19 +71,69 @@ pub struct GenericResultError {
     pub message: String
 }
 
-impl TaskResult {
-    pub fn new(
-        status_code: u16,
-        response: String,
-        private_key_params: Option<PrivateKeyParams>,
-        task_type: TaskType
-    ) -> Self {
-        Self { status_code, response, private_key_params, task_type }
+pub enum TaskResult {
+    Login(Result<LoginResponse, TaskError>),
+    CreateAccount(Result<CreateAccountResponse, TaskError>),
+    SearchUser(Result<Vec<SearchUserResponse>, TaskError>),
+    SendInviteContact(u64, String, Result<InviteMessage, TaskError>),
+    AcceptInviteContact(u64, Result<AcceptInvite, TaskError>),
+    FetchChatMessages(u64, Result<Vec<FetchMessage>, TaskError>),
+    SyncChatMessages(u64, u64, Result<Vec<FetchMessage>, TaskError>),
+    UpdatePublicKey(Result<GenericResultError, TaskError>),
+    // results of the key store thread, they come back through the same queue
+    ReadPrivateKey(Result<Option<Vec<u8>>, std::io::Error>),
+    UnsealPrivateKey(Result<PrivateKeySet, KeyFileError>),
+    SavePrivateKey(Result<(), std::io::Error>),
+    FetchPublicKey(u64, Result<PublicKeyResponse, TaskError>)
+}
+
+// decoding happens on the worker so a malformed body never reaches the UI thread
+pub fn decode_response<T: DeserializeOwned>(response: Result<Response, reqwest::Error>) -> Result<T, TaskError> {
+    let response = response.map_err(|e| TaskError::Network(e.to_string()))?;
+    let status_code = response.status().as_u16();
+    let body = response.text().map_err(|e| TaskError::Network(e.to_string()))?;
+
+    if status_code >= 400 {
+        let message = serde_json::from_str::<GenericResultError>(&body)
+            .map(|e| e.message)
+            .unwrap_or_else(|_| format!("Request failed with status {}", status_code));
+        return Err(TaskError::Api { status_code, message });
+    }
+
+    serde_json::from_str(&body).map_err(|e| TaskError::Decode(e.to_string()))
+}
+
+pub const TASK_PRIORITY_LEVELS: usize = 3;
+
+// decides what happens when a task shares its key with one that is still queued or running
+pub enum DedupKey {
+    // the newer task wins, the older one is cancelled and its result dropped
+    Supersede(String),
+    // the older task wins, the newer one is dropped before being queued
+    Coalesce(String)
+}
+
+impl DedupKey {
+    pub fn key(&self) -> &str {
+        match self {
+            Self::Supersede(key) | Self::Coalesce(key) => key
+        }
     }
 }
 
 pub trait Task: Send {
-    fn exec(&self, http_client: &HttpClient) -> Result<TaskResult, std::io::Error>;
+    fn task_type(&self) -> TaskType;
+
+    // tasks sharing a key are served round-robin against other keys of the same priority
+    fn fairness_key(&self) -> u64 {
+        0
+    }
+
+    fn dedup_key(&self) -> Option<DedupKey> {
+        None
+    }
+
+    fn exec(&self, http_client: &HttpClient) -> TaskResult;
 }
 
 pub mod login_task;
@@ -44,3 +142,5 @@ pub mod search_user_task;
 pub mod send_invite_contact_task;
 pub mod accept_invite_contact_task;
 pub mod fetch_chat_messages;
+pub mod update_public_key_task;
+pub mod fetch_public_key_task;
@@ -1,4 +1,4 @@
-use super::{Task, TaskResult, TaskType};
+use super::{DedupKey, Task, TaskResult, TaskType, decode_response};
 use reqwest::header::{HeaderMap, HeaderValue};
 use serde::{Deserialize, Serialize};
 
@@ -20,24 +20,22 @@ impl SearchUserTask {
 }
 
 impl Task for SearchUserTask {
-    fn exec(&self, http_client: &crate::http::HttpClient) -> Result<super::TaskResult, std::io::Error> {
+    fn task_type(&self) -> TaskType {
+        TaskType::SearchUser
+    }
+
+    // only the latest search matters, older ones would overwrite its results
+    fn dedup_key(&self) -> Option<DedupKey> {
+        Some(DedupKey::Supersede("search_user".to_owned()))
+    }
+
+    fn exec(&self, http_client: &crate::http::HttpClient) -> TaskResult {
         let mut headers = HeaderMap::new();
         headers.insert("authToken", HeaderValue::from_str(&self.token).unwrap());
 
         let query = vec![format!("email={}", self.search_param)];
 
         let response = http_client.get("user_api/user/search", Some(query), Some(headers));
-        match response {
-            Ok(r) => {
-                let result = TaskResult::new(
-                    r.status().as_u16(),
-                    r.text().unwrap(),
-                    None,
-                    TaskType::SearchUser
-                );
-                Ok(result)
-            },
-            Err(_) => Err(std::io::Error::new(std::io::ErrorKind::HostUnreachable, "Couldn't send request"))
-        }
+        TaskResult::SearchUser(decode_response(response))
     }
 }
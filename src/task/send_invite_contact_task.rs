@@ -1,4 +1,4 @@
-use super::{Task, TaskResult, TaskType};
+use super::{Task, TaskResult, TaskType, decode_response};
 use reqwest::header::{HeaderMap, HeaderValue};
 use serde_json::json;
 
@@ -16,7 +16,11 @@ impl SendInviteContactTask {
 }
 
 impl Task for SendInviteContactTask {
-    fn exec(&self, http_client: &crate::http::HttpClient) -> Result<TaskResult, std::io::Error> {
+    fn task_type(&self) -> TaskType {
+        TaskType::SendInviteContact
+    }
+
+    fn exec(&self, http_client: &crate::http::HttpClient) -> TaskResult {
         let mut headers = HeaderMap::new();
         headers.insert("authToken", HeaderValue::from_str(&self.token).unwrap());
 
@@ -27,17 +31,6 @@ impl Task for SendInviteContactTask {
         });
 
         let response = http_client.post("user_api/contact/create", Some(body), Some(headers));
-        match response {
-            Ok(r) => {
-                let result = TaskResult::new(
-                    r.status().as_u16(),
-                    r.text().unwrap(),
-                    None,
-                    TaskType::SendInviteContact
-                );
-                Ok(result)
-            },
-            Err(_) => Err(std::io::Error::new(std::io::ErrorKind::HostUnreachable, "Couldn't send request"))
-        }
+        TaskResult::SendInviteContact(self.receiver_id, self.receiver_email.clone(), decode_response(response))
     }
 }
@@ -0,0 +1,32 @@
+use super::{Task, TaskResult, TaskType, GenericResultError, decode_response};
+use reqwest::header::{HeaderMap, HeaderValue};
+use serde_json::json;
+
+pub struct UpdatePublicKeyTask {
+    public_key: [u8; 32],
+    token: String
+}
+
+impl UpdatePublicKeyTask {
+    pub fn new(public_key: [u8; 32], token: String) -> Self {
+        Self { public_key, token }
+    }
+}
+
+impl Task for UpdatePublicKeyTask {
+    fn task_type(&self) -> TaskType {
+        TaskType::UpdatePublicKey
+    }
+
+    fn exec(&self, http_client: &crate::http::HttpClient) -> TaskResult {
+        let mut headers = HeaderMap::new();
+        headers.insert("authToken", HeaderValue::from_str(&self.token).unwrap());
+
+        let body = json!({
+            "public_key": self.public_key
+        });
+
+        let response = http_client.post("user_api/user/public-key", Some(body), Some(headers));
+        TaskResult::UpdatePublicKey(decode_response::<GenericResultError>(response))
+    }
+}
@@ -1,41 +1,352 @@
 use crate::http::HttpClient;
-use crate::task::{Task, TaskResult};
+use crate::task::{DedupKey, Task, TaskResult, TASK_PRIORITY_LEVELS};
 use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{self, Sender, Receiver};
+use std::thread;
+
+#[derive(Clone, Default)]
+pub struct CancellationHandle(Arc<AtomicBool>);
+
+impl CancellationHandle {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn is_same(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
 
 pub struct TaskWrapper {
     task: Box<dyn Task>,
-    result_channel: Sender<TaskResult>
+    result_channel: Sender<TaskResult>,
+    cancellation: CancellationHandle,
+    dedup_key: Option<DedupKey>
 }
 
 impl TaskWrapper {
     pub fn new(task: Box<dyn Task>) -> (Self, Receiver<TaskResult>) {
         let (task_channel_sender, task_channel_receiver) = mpsc::channel();
-        (Self { task, result_channel: task_channel_sender }, task_channel_receiver)
+        let dedup_key = task.dedup_key();
+
+        (
+            Self {
+                task,
+                result_channel: task_channel_sender,
+                cancellation: CancellationHandle::default(),
+                dedup_key
+            },
+            task_channel_receiver
+        )
+    }
+
+    pub fn cancellation_handle(&self) -> CancellationHandle {
+        self.cancellation.clone()
+    }
+}
+
+// one ring of (fairness key, tasks) per priority level; serving a key moves it
+// to the back of its ring, so a single chat can't starve the others
+struct TaskScheduler {
+    levels: Vec<VecDeque<(u64, VecDeque<TaskWrapper>)>>,
+    // dedup keys of the tasks that are queued or running
+    live_keys: Vec<(String, CancellationHandle)>,
+    is_closed: bool
+}
+
+impl TaskScheduler {
+    fn new() -> Self {
+        Self {
+            levels: (0..TASK_PRIORITY_LEVELS).map(|_| VecDeque::new()).collect(),
+            live_keys: Vec::new(),
+            is_closed: false
+        }
     }
+
+    fn push(&mut self, task_wrapper: TaskWrapper) {
+        if let Some(dedup_key) = &task_wrapper.dedup_key {
+            let mut live_tasks = self.live_keys.iter()
+                .filter(|(key, handle)| key == dedup_key.key() && !handle.is_cancelled());
+
+            match dedup_key {
+                DedupKey::Supersede(_) => live_tasks.for_each(|(_, handle)| handle.cancel()),
+                DedupKey::Coalesce(_) => if live_tasks.next().is_some() {
+                    return;
+                }
+            }
+
+            self.live_keys.push((dedup_key.key().to_owned(), task_wrapper.cancellation_handle()));
+        }
+
+        let level = task_wrapper.task.task_type().priority().min(TASK_PRIORITY_LEVELS - 1);
+        let key = task_wrapper.task.fairness_key();
+        let ring = &mut self.levels[level];
+
+        match ring.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, tasks)) => tasks.push_back(task_wrapper),
+            None => ring.push_back((key, VecDeque::from([task_wrapper])))
+        }
+    }
+
+    // cancelled tasks are thrown away here so they never reach a worker
+    fn pop(&mut self) -> Option<TaskWrapper> {
+        while let Some(task_wrapper) = self.pop_next() {
+            if !task_wrapper.cancellation.is_cancelled() {
+                return Some(task_wrapper);
+            }
+            self.release(&task_wrapper);
+        }
+
+        None
+    }
+
+    fn pop_next(&mut self) -> Option<TaskWrapper> {
+        for ring in self.levels.iter_mut() {
+            if let Some((key, mut tasks)) = ring.pop_front() {
+                let task_wrapper = tasks.pop_front();
+                if !tasks.is_empty() {
+                    ring.push_back((key, tasks));
+                }
+
+                return task_wrapper;
+            }
+        }
+
+        None
+    }
+
+    fn release(&mut self, task_wrapper: &TaskWrapper) {
+        if task_wrapper.dedup_key.is_some() {
+            self.live_keys.retain(|(_, handle)| !handle.is_same(&task_wrapper.cancellation));
+        }
+    }
+}
+
+type SharedScheduler = Arc<(Mutex<TaskScheduler>, Condvar)>;
+
+pub fn init_http_thread(
+    http_client: HttpClient,
+    http_thread_receiver: Receiver<TaskWrapper>,
+    worker_count: usize
+) {
+    let http_client = Arc::new(http_client);
+    let scheduler: SharedScheduler = Arc::new((Mutex::new(TaskScheduler::new()), Condvar::new()));
+
+    for _ in 0..worker_count.max(1) {
+        let http_client = http_client.clone();
+        let scheduler = scheduler.clone();
+        thread::spawn(move || http_worker(http_client, scheduler));
+    }
+
+    // recv blocks while there is nothing to do and fails once the App drops its sender
+    while let Ok(task_wrapper) = http_thread_receiver.recv() {
+        let (queue, condvar) = &*scheduler;
+        queue.lock().unwrap().push(task_wrapper);
+        condvar.notify_one();
+    }
+
+    let (queue, condvar) = &*scheduler;
+    queue.lock().unwrap().is_closed = true;
+    condvar.notify_all();
 }
 
-pub fn init_http_thread(http_thread_receiver: Receiver<TaskWrapper>) {
-    let http_client = HttpClient::new("http://eduardodev.app.br/");
-    let mut task_queue: VecDeque<TaskWrapper> = VecDeque::new();
+fn http_worker(http_client: Arc<HttpClient>, scheduler: SharedScheduler) {
+    let (queue, condvar) = &*scheduler;
 
     loop {
-        if !task_queue.is_empty() {
-            if let Some(task_wrapper) = task_queue.pop_front() {
-                match task_wrapper.task.exec(&http_client) {
-                    Ok(result) => {
-                        task_wrapper.result_channel.send(result).unwrap();
-                    },
-                    Err(_e) => {}
+        let task_wrapper = {
+            let mut queue = queue.lock().unwrap();
+            loop {
+                if let Some(task_wrapper) = queue.pop() {
+                    break task_wrapper;
                 }
+                if queue.is_closed {
+                    return;
+                }
+                queue = condvar.wait(queue).unwrap();
             }
+        };
+
+        let task_result = task_wrapper.task.exec(&http_client);
+        queue.lock().unwrap().release(&task_wrapper);
+
+        // a cancelled task drops its sender, so the UI just sees the channel disconnect
+        if !task_wrapper.cancellation.is_cancelled() {
+            let _ = task_wrapper.result_channel.send(task_result);
         }
+    }
+}
 
-        match http_thread_receiver.try_recv() {
-            Ok(task_wrapper) => {
-                task_queue.push_back(task_wrapper);
-            },
-            Err(_e) => {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::TaskType;
+
+    struct StandInTask {
+        task_type: TaskType,
+        fairness_key: u64,
+        dedup_key: Option<DedupKey>
+    }
+
+    impl Task for StandInTask {
+        fn task_type(&self) -> TaskType {
+            self.task_type
+        }
+
+        fn fairness_key(&self) -> u64 {
+            self.fairness_key
         }
+
+        fn dedup_key(&self) -> Option<DedupKey> {
+            match &self.dedup_key {
+                Some(DedupKey::Supersede(key)) => Some(DedupKey::Supersede(key.clone())),
+                Some(DedupKey::Coalesce(key)) => Some(DedupKey::Coalesce(key.clone())),
+                None => None
+            }
+        }
+
+        fn exec(&self, _: &HttpClient) -> TaskResult {
+            unreachable!("the scheduler never runs tasks")
+        }
+    }
+
+    // queues the tasks in order and tells which of them come out, by their position
+    fn served_order(scheduler: &mut TaskScheduler, tasks: Vec<StandInTask>) -> Vec<usize> {
+        let receivers: Vec<Receiver<TaskResult>> = tasks.into_iter()
+            .map(|task| {
+                let (task_wrapper, task_channel_receiver) = TaskWrapper::new(Box::new(task));
+                scheduler.push(task_wrapper);
+                task_channel_receiver
+            })
+            .collect();
+
+        let mut order = Vec::new();
+        while let Some(task_wrapper) = scheduler.pop() {
+            let _ = task_wrapper.result_channel.send(TaskResult::SavePrivateKey(Ok(())));
+            scheduler.release(&task_wrapper);
+            order.push(receivers.iter().position(|r| r.try_recv().is_ok()).unwrap());
+        }
+
+        order
+    }
+
+    fn task(task_type: TaskType, fairness_key: u64) -> StandInTask {
+        StandInTask { task_type, fairness_key, dedup_key: None }
+    }
+
+    fn deduped_task(dedup_key: DedupKey) -> StandInTask {
+        StandInTask { task_type: TaskType::SearchUser, fairness_key: 0, dedup_key: Some(dedup_key) }
+    }
+
+    #[test]
+    fn user_actions_go_before_paging_and_syncs() {
+        let mut scheduler = TaskScheduler::new();
+        let tasks = vec![
+            task(TaskType::SyncChatMessages, 1),
+            task(TaskType::FetchChatMessages, 1),
+            task(TaskType::SearchUser, 0),
+            task(TaskType::SyncChatMessages, 2),
+            task(TaskType::Login, 0)
+        ];
+
+        assert_eq!(served_order(&mut scheduler, tasks), vec![2, 4, 1, 0, 3]);
+    }
+
+    #[test]
+    fn chats_take_turns_at_the_same_priority() {
+        let mut scheduler = TaskScheduler::new();
+        let tasks = vec![
+            task(TaskType::FetchChatMessages, 1),
+            task(TaskType::FetchChatMessages, 1),
+            task(TaskType::FetchChatMessages, 1),
+            task(TaskType::FetchChatMessages, 2),
+            task(TaskType::FetchPublicKey, 3)
+        ];
+
+        assert_eq!(served_order(&mut scheduler, tasks), vec![0, 3, 4, 1, 2]);
+    }
+
+    #[test]
+    fn chat_queued_later_joins_the_rotation() {
+        let mut scheduler = TaskScheduler::new();
+        let (first, _first_receiver) = TaskWrapper::new(Box::new(task(TaskType::FetchChatMessages, 1)));
+        let (second, _second_receiver) = TaskWrapper::new(Box::new(task(TaskType::FetchChatMessages, 1)));
+        scheduler.push(first);
+        scheduler.push(second);
+
+        assert_eq!(scheduler.pop().unwrap().task.fairness_key(), 1);
+        let (other, _other_receiver) = TaskWrapper::new(Box::new(task(TaskType::FetchChatMessages, 2)));
+        scheduler.push(other);
+
+        assert_eq!(scheduler.pop().unwrap().task.fairness_key(), 1);
+        assert_eq!(scheduler.pop().unwrap().task.fairness_key(), 2);
+        assert!(scheduler.pop().is_none());
+    }
+
+    #[test]
+    fn superseded_task_never_reaches_a_worker() {
+        let mut scheduler = TaskScheduler::new();
+        let tasks = vec![
+            deduped_task(DedupKey::Supersede("search".to_owned())),
+            task(TaskType::Login, 0),
+            deduped_task(DedupKey::Supersede("search".to_owned()))
+        ];
+
+        assert_eq!(served_order(&mut scheduler, tasks), vec![1, 2]);
+    }
+
+    #[test]
+    fn superseding_a_running_task_cancels_it() {
+        let mut scheduler = TaskScheduler::new();
+        let (first, _first_receiver) = TaskWrapper::new(Box::new(deduped_task(DedupKey::Supersede("search".to_owned()))));
+        scheduler.push(first);
+        let running = scheduler.pop().unwrap();
+
+        let (second, _second_receiver) = TaskWrapper::new(Box::new(deduped_task(DedupKey::Supersede("search".to_owned()))));
+        let second_handle = second.cancellation_handle();
+        scheduler.push(second);
+
+        assert!(running.cancellation.is_cancelled());
+        assert!(scheduler.pop().unwrap().cancellation.is_same(&second_handle));
+    }
+
+    #[test]
+    fn coalesced_task_is_dropped_while_the_first_one_is_live() {
+        let mut scheduler = TaskScheduler::new();
+        let tasks = vec![
+            deduped_task(DedupKey::Coalesce("sync 1".to_owned())),
+            deduped_task(DedupKey::Coalesce("sync 1".to_owned())),
+            deduped_task(DedupKey::Coalesce("sync 2".to_owned()))
+        ];
+
+        assert_eq!(served_order(&mut scheduler, tasks), vec![0, 2]);
+
+        // once the first one finished, the same key is queued again
+        let tasks = vec![deduped_task(DedupKey::Coalesce("sync 1".to_owned()))];
+        assert_eq!(served_order(&mut scheduler, tasks), vec![0]);
+    }
+
+    #[test]
+    fn cancelled_task_never_reaches_a_worker() {
+        let mut scheduler = TaskScheduler::new();
+        let (cancelled, cancelled_receiver) = TaskWrapper::new(Box::new(deduped_task(DedupKey::Coalesce("sync 1".to_owned()))));
+        cancelled.cancellation_handle().cancel();
+        scheduler.push(cancelled);
+        let (other, _other_receiver) = TaskWrapper::new(Box::new(task(TaskType::FetchChatMessages, 1)));
+        scheduler.push(other);
+
+        assert_eq!(scheduler.pop().unwrap().task.fairness_key(), 1);
+        assert!(scheduler.pop().is_none());
+        assert!(cancelled_receiver.recv().is_err());
+
+        // the thrown away task doesn't keep its key taken
+        let tasks = vec![deduped_task(DedupKey::Coalesce("sync 1".to_owned()))];
+        assert_eq!(served_order(&mut scheduler, tasks), vec![0]);
     }
 }
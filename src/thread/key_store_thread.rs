@@ -0,0 +1,30 @@
+use crate::task::TaskResult;
+use std::sync::mpsc::{self, Sender, Receiver};
+use std::thread;
+
+// the system keyring is reached over D-Bus and a call can wait on an unlock prompt, so every
+// key store access runs here, one after the other, instead of on the UI or the http workers
+pub struct KeyStoreJob {
+    run: Box<dyn FnOnce() -> TaskResult + Send>,
+    result_channel: Sender<TaskResult>
+}
+
+impl KeyStoreJob {
+    pub fn new(run: Box<dyn FnOnce() -> TaskResult + Send>) -> (Self, Receiver<TaskResult>) {
+        let (job_channel_sender, job_channel_receiver) = mpsc::channel();
+
+        (Self { run, result_channel: job_channel_sender }, job_channel_receiver)
+    }
+}
+
+pub fn init_key_store_thread() -> Sender<KeyStoreJob> {
+    let (key_store_thread_sender, key_store_thread_receiver): (Sender<KeyStoreJob>, Receiver<KeyStoreJob>) = mpsc::channel();
+
+    thread::spawn(move || {
+        while let Ok(key_store_job) = key_store_thread_receiver.recv() {
+            let _ = key_store_job.result_channel.send((key_store_job.run)());
+        }
+    });
+
+    key_store_thread_sender
+}
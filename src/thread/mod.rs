@@ -1,2 +1,4 @@
 pub mod http_thread;
 pub mod websocket_thread;
+pub mod store_thread;
+pub mod key_store_thread;
@@ -0,0 +1,79 @@
+use crate::util::message_store::{MessageStore, append_messages, rewrite_message_store};
+use crate::util::ratchet::{RatchetSession, save_sessions};
+use crate::util::search_index::{SearchIndex, append_search_entries, rewrite_search_index};
+use x25519_dalek::StaticSecret;
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Sender, Receiver};
+use std::thread;
+
+pub enum StoreData {
+    Sessions(HashMap<u64, RatchetSession>),
+    NewMessages(MessageStore),
+    MessageStore(MessageStore),
+    NewSearchEntries(SearchIndex),
+    SearchIndex(SearchIndex)
+}
+
+pub struct StoreWrite {
+    pub user_id: u64,
+    pub private_key: StaticSecret,
+    pub data: StoreData,
+    // set when the caller waits for the write, the result goes here instead of the error channel
+    pub saved_sender: Option<Sender<Result<(), String>>>
+}
+
+// sealing and writing happen here so the UI never waits on the disk; failures come back as messages
+pub fn init_store_thread() -> (Sender<StoreWrite>, Receiver<String>) {
+    let (store_thread_sender, store_thread_receiver): (Sender<StoreWrite>, Receiver<StoreWrite>) = mpsc::channel();
+    let (error_ui_sender, error_ui_receiver): (Sender<String>, Receiver<String>) = mpsc::channel();
+
+    thread::spawn(move || {
+        while let Ok(store_write) = store_thread_receiver.recv() {
+            let mut store_writes = vec![store_write];
+            store_writes.extend(store_thread_receiver.try_iter());
+
+            // every sessions write holds all of them, only the newest queued one matters
+            // unless someone waits for it
+            let newest_sessions = store_writes.iter()
+                .rposition(|w| matches!(w.data, StoreData::Sessions(_)));
+            let store_writes = store_writes.into_iter().enumerate()
+                .filter(|(i, w)| {
+                    !matches!(w.data, StoreData::Sessions(_)) || Some(*i) == newest_sessions || w.saved_sender.is_some()
+                })
+                .map(|(_, w)| w);
+
+            for store_write in store_writes {
+                let result = write(&store_write);
+                match &store_write.saved_sender {
+                    Some(saved_sender) => {
+                        let _ = saved_sender.send(result);
+                    },
+                    None => {
+                        if let Err(e) = result {
+                            let _ = error_ui_sender.send(e);
+                        }
+                    }
+                }
+            }
+        }
+    });
+
+    (store_thread_sender, error_ui_receiver)
+}
+
+fn write(store_write: &StoreWrite) -> Result<(), String> {
+    let StoreWrite { user_id, private_key, data, .. } = store_write;
+
+    match data {
+        StoreData::Sessions(sessions) => save_sessions(*user_id, private_key, sessions)
+            .map_err(|e| format!("Couldn't save encryption sessions: {}", e)),
+        StoreData::NewMessages(messages) => append_messages(*user_id, private_key, messages)
+            .map_err(|e| format!("Couldn't save messages: {}", e)),
+        StoreData::MessageStore(message_store) => rewrite_message_store(*user_id, private_key, message_store)
+            .map_err(|e| format!("Couldn't save messages: {}", e)),
+        StoreData::NewSearchEntries(new_entries) => append_search_entries(*user_id, private_key, new_entries)
+            .map_err(|e| format!("Couldn't save search index: {}", e)),
+        StoreData::SearchIndex(search_index) => rewrite_search_index(*user_id, private_key, search_index)
+            .map_err(|e| format!("Couldn't save search index: {}", e))
+    }
+}
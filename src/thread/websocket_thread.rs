@@ -1,11 +1,19 @@
 use crate::state::{ChatInfoJSON, ContactInfoJSON};
-use http::Uri;
-use std::net::TcpStream;
-use std::sync::mpsc::{self, Sender, Receiver};
+use crate::util::settings::ServerEndpoints;
+use crate::util::time_format::deserialize_timestamp;
+use chrono::{DateTime, Utc};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Sender, Receiver, TryRecvError};
 use std::thread;
+use std::time::Duration;
+use rand::Rng;
+use polling::{Event, Events, Poller};
 use tungstenite::{
-    connect,
+    client_tls_with_config,
     ClientRequestBuilder,
+    Connector,
+    HandshakeError,
     Message,
     WebSocket,
     stream::MaybeTlsStream
@@ -13,6 +21,26 @@ use tungstenite::{
 use serde::{Deserialize, Serialize};
 use eframe::egui;
 
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const SOCKET_KEY: usize = 0;
+
+#[derive(Clone, PartialEq)]
+pub enum ConnectionStatus {
+    Connected,
+    Reconnecting { attempt: u32, retry_in: Duration },
+    // the server turned the session down, trying again won't change that
+    Refused(String)
+}
+
+pub enum WsEvent {
+    Status(ConnectionStatus),
+    // only handed to the socket, whether the server got it is checked with a sync
+    Written(String),
+    Undelivered(String)
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct WsContentMessage {
     pub sender_id: u64,
@@ -31,7 +59,9 @@ pub struct ContentMessage {
     pub user_id: u64,
     pub chat_id: u64,
     pub content: String,
-    pub nonce: String
+    pub nonce: String,
+    #[serde(default, deserialize_with = "deserialize_timestamp")]
+    pub created_at: Option<DateTime<Utc>>
 }
 
 #[derive(Serialize, Deserialize)]
@@ -54,66 +84,520 @@ pub struct AcceptInvite {
     pub contact: ContactInfoJSON
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct KeyRotatedMessage {
+    pub sender_id: u64,
+    pub receiver_id: u64,
+    pub target_id: u64,
+    pub receiver_email: String,
+    pub r#type: MessageType,
+    pub public_key: String
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum MessageType {
     Content,
     Invite,
-    InviteAccepted
+    InviteAccepted,
+    KeyRotated
 }
 
 impl MessageType {
-    pub fn as_str(self) -> String {
+    pub fn as_str(&self) -> String {
         match self {
             Self::Content => "Content".to_string(),
             Self::Invite => "Invite".to_string(),
-            Self::InviteAccepted => "InviteAccepted".to_string()
+            Self::InviteAccepted => "InviteAccepted".to_string(),
+            Self::KeyRotated => "KeyRotated".to_string()
         }
     }
 }
 
-pub fn init_websocket(jwt_token: String) -> Result<WebSocket<MaybeTlsStream<TcpStream>>, std::io::Error> {
-    let uri: Uri = "ws://eduardodev.app.br/ws_server".parse().unwrap();
-    let request = ClientRequestBuilder::new(uri)
+pub fn init_websocket(endpoints: &ServerEndpoints, jwt_token: String) -> Result<WebSocket<MaybeTlsStream<TcpStream>>, std::io::Error> {
+    let ws_url = endpoints.ws_url.clone();
+    let is_tls = ws_url.scheme_str() == Some("wss");
+    let host = ws_url.host()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "WebSocket URL has no host"))?
+        .to_owned();
+    let port = ws_url.port_u16().unwrap_or(if is_tls { 443 } else { 80 });
+
+    let stream = connect_stream(&host, port, endpoints.connect_timeout)?;
+    stream.set_nodelay(true)?;
+    // only bounds the handshake, the socket is switched to non-blocking afterwards
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+
+    let connector = if is_tls {
+        Some(Connector::NativeTls(tls_connector(endpoints.ca_certificate.as_deref())?))
+    } else {
+        None
+    };
+
+    let request = ClientRequestBuilder::new(ws_url)
         .with_header("authToken", jwt_token);
 
-    match connect(request) {
+    match client_tls_with_config(request, stream, None, connector) {
         Ok((socket, response)) => {
             if response.status() != 101 {
-                Err(std::io::Error::new(std::io::ErrorKind::Other, "Server denied connection upgrade"))
+                Err(std::io::Error::other("Server denied connection upgrade"))
             } else {
                 Ok(socket)
             }
         },
-        Err(_) => Err(std::io::Error::new(std::io::ErrorKind::Other, "Couldn't send websocket handshake"))
+        Err(HandshakeError::Failure(tungstenite::Error::Http(response))) if matches!(response.status().as_u16(), 401 | 403) => Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("The server refused the session ({})", response.status())
+        )),
+        Err(e) => Err(std::io::Error::other(format!("Couldn't send websocket handshake: {}", e)))
+    }
+}
+
+// every address the host resolves to is tried, each one bounded by the timeout
+fn connect_stream(host: &str, port: u16, connect_timeout: Duration) -> Result<TcpStream, std::io::Error> {
+    let mut last_error = std::io::Error::new(std::io::ErrorKind::NotFound, format!("Couldn't resolve {}", host));
+    for address in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, connect_timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e
+        }
+    }
+
+    Err(last_error)
+}
+
+fn tls_connector(ca_certificate: Option<&[u8]>) -> Result<native_tls::TlsConnector, std::io::Error> {
+    let mut builder = native_tls::TlsConnector::builder();
+    if let Some(pem) = ca_certificate {
+        let certificate = native_tls::Certificate::from_pem(pem)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        builder.add_root_certificate(certificate);
+    }
+
+    builder.build().map_err(std::io::Error::other)
+}
+
+// MaybeTlsStream is non-exhaustive, a TLS backend that isn't enabled here can't be polled
+fn tcp_stream(socket: &WebSocket<MaybeTlsStream<TcpStream>>) -> Result<&TcpStream, std::io::Error> {
+    match socket.get_ref() {
+        MaybeTlsStream::Plain(stream) => Ok(stream),
+        MaybeTlsStream::NativeTls(stream) => Ok(stream.get_ref()),
+        _ => Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Unsupported websocket stream"))
+    }
+}
+
+pub fn set_nonblocking(socket: &mut WebSocket<MaybeTlsStream<TcpStream>>) -> Result<(), std::io::Error> {
+    tcp_stream(socket)?.set_nonblocking(true)
+}
+
+// the socket is always unwatched before being dropped
+fn watch_socket(poller: &Poller, socket: &WebSocket<MaybeTlsStream<TcpStream>>) -> Result<(), std::io::Error> {
+    unsafe { poller.add(tcp_stream(socket)?, socket_interest(false)) }
+}
+
+fn unwatch_socket(poller: &Poller, socket: &WebSocket<MaybeTlsStream<TcpStream>>) {
+    if let Ok(stream) = tcp_stream(socket) {
+        let _ = poller.delete(stream);
+    }
+}
+
+fn socket_interest(is_flush_pending: bool) -> Event {
+    if is_flush_pending {
+        Event::all(SOCKET_KEY)
+    } else {
+        Event::readable(SOCKET_KEY)
     }
 }
 
 pub fn init_websocket_thread(
     mut socket: WebSocket<MaybeTlsStream<TcpStream>>,
+    endpoints: ServerEndpoints,
+    jwt_token: String,
     ctx: egui::Context
-) -> (Sender<String>, Receiver<String>)
+) -> (Sender<String>, Receiver<String>, Receiver<WsEvent>)
 {
     let (message_thread_sender, message_thread_receiver)
         :(Sender<String>, Receiver<String>) = mpsc::channel();
     let (message_ui_sender, message_ui_receiver)
         :(Sender<String>, Receiver<String>) = mpsc::channel();
+    let (event_ui_sender, event_ui_receiver)
+        :(Sender<WsEvent>, Receiver<WsEvent>) = mpsc::channel();
+    let (outgoing_sender, outgoing_receiver)
+        :(Sender<String>, Receiver<String>) = mpsc::channel();
+
+    let poller = Arc::new(Poller::new().expect("Couldn't create websocket poller"));
+
+    // mpsc channels can't be polled, so this thread blocks on the UI channel
+    // and wakes the socket thread up whenever there is something to send
+    let forward_poller = poller.clone();
+    thread::spawn(move || {
+        for msg in message_thread_receiver.iter() {
+            if outgoing_sender.send(msg).is_err() {
+                return;
+            }
+            let _ = forward_poller.notify();
+        }
+
+        drop(outgoing_sender);
+        let _ = forward_poller.notify();
+    });
 
     thread::spawn(move || {
+        let mut events = Events::new();
+        let mut is_flush_pending = false;
+        // a socket the poller can't watch is handled like a lost connection
+        let mut is_watched = watch_socket(&poller, &socket).is_ok();
+
         loop {
-            match socket.read() {
-                Ok(msg) => {
-                    message_ui_sender.send(msg.to_string()).unwrap();
-                    ctx.request_repaint();
-                },
-                Err(_e) => {
+            let mut is_connection_lost = !is_watched;
+
+            events.clear();
+            if !is_connection_lost
+                && let Err(e) = poller.wait(&mut events, None)
+                && e.kind() != std::io::ErrorKind::Interrupted
+            {
+                is_connection_lost = true;
+            }
+
+            while !is_connection_lost {
+                match socket.read() {
+                    Ok(Message::Text(msg)) => {
+                        if message_ui_sender.send(msg.to_string()).is_err() {
+                            return;
+                        }
+                        ctx.request_repaint();
+                    },
+                    Ok(Message::Close(_)) => is_connection_lost = true,
+                    Ok(_) => {},
+                    Err(tungstenite::Error::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                    Err(_e) => is_connection_lost = true
+                }
+            }
+
+            if !is_connection_lost && is_flush_pending {
+                match socket.flush() {
+                    Ok(()) => is_flush_pending = false,
+                    Err(tungstenite::Error::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock => {},
+                    Err(_e) => is_connection_lost = true
                 }
             }
 
-            if let Ok(msg) = message_thread_receiver.try_recv() {
-                socket.send(Message::Text(msg.into())).unwrap();
+            while !is_connection_lost {
+                let msg = match outgoing_receiver.try_recv() {
+                    Ok(msg) => msg,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        unwatch_socket(&poller, &socket);
+                        return;
+                    }
+                };
+
+                let event = match socket.send(Message::Text(msg.clone().into())) {
+                    Ok(()) => WsEvent::Written(msg),
+                    // tungstenite keeps a WouldBlock write buffered and flushes it once the socket is writable
+                    Err(tungstenite::Error::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                        is_flush_pending = true;
+                        WsEvent::Written(msg)
+                    },
+                    Err(_e) => {
+                        is_connection_lost = true;
+                        WsEvent::Undelivered(msg)
+                    }
+                };
+
+                if event_ui_sender.send(event).is_err() {
+                    unwatch_socket(&poller, &socket);
+                    return;
+                }
+                ctx.request_repaint();
+            }
+
+            if !is_connection_lost
+                && let Ok(stream) = tcp_stream(&socket)
+                && poller.modify(stream, socket_interest(is_flush_pending)).is_ok()
+            {
+                continue;
+            }
+
+            if is_watched {
+                unwatch_socket(&poller, &socket);
             }
+            let _ = socket.flush();
+            drop(socket);
+            is_flush_pending = false;
+
+            match reconnect(&endpoints, &jwt_token, &event_ui_sender, &ctx) {
+                Some(new_socket) => socket = new_socket,
+                None => return
+            }
+            is_watched = watch_socket(&poller, &socket).is_ok();
         }
     });
 
-    (message_thread_sender, message_ui_receiver)
+    (message_thread_sender, message_ui_receiver, event_ui_receiver)
+}
+
+// returns None once the UI side is gone or the server refused the session, so the thread can stop
+fn reconnect(
+    endpoints: &ServerEndpoints,
+    jwt_token: &str,
+    event_ui_sender: &Sender<WsEvent>,
+    ctx: &egui::Context
+) -> Option<WebSocket<MaybeTlsStream<TcpStream>>> {
+    let mut attempt = 0;
+
+    loop {
+        attempt += 1;
+        let retry_in = backoff_delay(attempt);
+
+        let status = ConnectionStatus::Reconnecting { attempt, retry_in };
+        event_ui_sender.send(WsEvent::Status(status)).ok()?;
+        ctx.request_repaint();
+        thread::sleep(retry_in);
+
+        let connection = init_websocket(endpoints, jwt_token.to_owned())
+            .and_then(|mut socket| set_nonblocking(&mut socket).map(|_| socket));
+        match connection {
+            Ok(socket) => {
+                event_ui_sender.send(WsEvent::Status(ConnectionStatus::Connected)).ok()?;
+                ctx.request_repaint();
+                return Some(socket);
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                let _ = event_ui_sender.send(WsEvent::Status(ConnectionStatus::Refused(e.to_string())));
+                ctx.request_repaint();
+                return None;
+            },
+            Err(_) => {}
+        }
+    }
+}
+
+fn backoff_delay(attempt: u32) -> Duration {
+    let exponential = INITIAL_BACKOFF.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+    let delay = exponential.min(MAX_BACKOFF);
+    let jitter = rand::thread_rng().gen_range(0.8..1.2);
+
+    delay.mul_f64(jitter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use tungstenite::handshake::server::{Request, Response};
+    use crate::http::{HttpClient, HttpClientOptions};
+    use crate::util::settings::Settings;
+
+    fn endpoints_for(listener: &TcpListener) -> ServerEndpoints {
+        let port = listener.local_addr().unwrap().port();
+        ServerEndpoints {
+            http_url: format!("http://127.0.0.1:{}/", port).parse().unwrap(),
+            ws_url: format!("ws://127.0.0.1:{}/ws_server", port).parse().unwrap(),
+            ca_certificate: None,
+            connect_timeout: Duration::from_secs(1)
+        }
+    }
+
+    // the handshake callback's error type is set by tungstenite
+    #[allow(clippy::result_large_err)]
+    #[test]
+    fn connects_with_token_and_exchanges_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoints = endpoints_for(&listener);
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut auth_token = None;
+            let mut socket = tungstenite::accept_hdr(stream, |request: &Request, response: Response| {
+                auth_token = request.headers().get("authToken").map(|v| v.to_str().unwrap().to_owned());
+                Ok(response)
+            }).unwrap();
+
+            let msg = socket.read().unwrap();
+            socket.send(msg).unwrap();
+            auth_token
+        });
+
+        let mut socket = init_websocket(&endpoints, "jwt".to_owned()).unwrap();
+
+        // nothing is sent before the ping, so a non-blocking read has to come back empty
+        set_nonblocking(&mut socket).unwrap();
+        assert!(matches!(
+            socket.read(),
+            Err(tungstenite::Error::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock
+        ));
+
+        tcp_stream(&socket).unwrap().set_nonblocking(false).unwrap();
+        socket.send(Message::Text("ping".into())).unwrap();
+        assert_eq!(socket.read().unwrap(), Message::Text("ping".into()));
+        assert_eq!(server.join().unwrap().as_deref(), Some("jwt"));
+    }
+
+    #[test]
+    fn fails_when_the_server_refuses_the_upgrade() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoints = endpoints_for(&listener);
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = [0u8; 1024];
+            let _ = stream.read(&mut buffer).unwrap();
+            stream.write_all(b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n").unwrap();
+        });
+
+        let error = init_websocket(&endpoints, "bad".to_owned()).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn reconnecting_stops_once_the_server_refuses_the_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoints = endpoints_for(&listener);
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = [0u8; 1024];
+            let _ = stream.read(&mut buffer).unwrap();
+            stream.write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n").unwrap();
+        });
+
+        let (event_ui_sender, event_ui_receiver) = mpsc::channel();
+        assert!(reconnect(&endpoints, "expired", &event_ui_sender, &egui::Context::default()).is_none());
+        server.join().unwrap();
+
+        let statuses: Vec<ConnectionStatus> = event_ui_receiver.try_iter()
+            .filter_map(|event| match event {
+                WsEvent::Status(status) => Some(status),
+                _ => None
+            })
+            .collect();
+        assert!(matches!(statuses[..], [ConnectionStatus::Reconnecting { attempt: 1, .. }, ConnectionStatus::Refused(_)]));
+    }
+
+    #[test]
+    fn connecting_gives_up_after_the_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut endpoints = endpoints_for(&listener);
+        // nothing answers on this address, the connection attempt just hangs
+        endpoints.ws_url = "ws://10.255.255.1:9/ws_server".parse().unwrap();
+
+        let started_at = std::time::Instant::now();
+        assert!(init_websocket(&endpoints, "jwt".to_owned()).is_err());
+        assert!(started_at.elapsed() < endpoints.connect_timeout + Duration::from_secs(2));
+    }
+
+    #[test]
+    fn fails_when_nothing_listens() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoints = endpoints_for(&listener);
+        drop(listener);
+
+        assert!(init_websocket(&endpoints, "jwt".to_owned()).is_err());
+    }
+
+    #[test]
+    fn backoff_grows_and_stays_capped() {
+        assert!(backoff_delay(1) < Duration::from_secs(2));
+        assert!(backoff_delay(3) >= Duration::from_millis(3200));
+        assert!(backoff_delay(20) <= MAX_BACKOFF.mul_f64(1.2));
+    }
+
+    // a CA of the test's own and a certificate it signed for localhost
+    fn test_ca() -> (String, native_tls::TlsAcceptor) {
+        let ca_key = rcgen::KeyPair::generate().unwrap();
+        let mut ca_params = rcgen::CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        ca_params.distinguished_name.push(rcgen::DnType::CommonName, "NossoChat test CA");
+        let ca_certificate = ca_params.self_signed(&ca_key).unwrap();
+
+        let server_key = rcgen::KeyPair::generate().unwrap();
+        let server_params = rcgen::CertificateParams::new(vec!["localhost".to_owned()]).unwrap();
+        let server_certificate = server_params.signed_by(&server_key, &ca_certificate, &ca_key).unwrap();
+        let identity = native_tls::Identity::from_pkcs8(
+            server_certificate.pem().as_bytes(),
+            server_key.serialize_pem().as_bytes()
+        ).unwrap();
+
+        (ca_certificate.pem(), native_tls::TlsAcceptor::new(identity).unwrap())
+    }
+
+    fn no_retries() -> HttpClientOptions {
+        HttpClientOptions { max_retries: 0, ..Default::default() }
+    }
+
+    #[test]
+    fn custom_ca_is_trusted_by_both_transports() {
+        let (ca_pem, acceptor) = test_ca();
+        let acceptor = Arc::new(acceptor);
+        let http_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let ws_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let ca_path = std::env::temp_dir().join(format!("nossochat-test-ca-{}.pem", std::process::id()));
+        std::fs::write(&ca_path, &ca_pem).unwrap();
+        let settings = Settings {
+            http_url: format!("https://localhost:{}/", http_listener.local_addr().unwrap().port()),
+            ws_url: format!("wss://localhost:{}/ws_server", ws_listener.local_addr().unwrap().port()),
+            ca_cert_path: ca_path.display().to_string(),
+            ..Default::default()
+        };
+        let endpoints = settings.validate();
+        std::fs::remove_file(&ca_path).unwrap();
+        let endpoints = endpoints.unwrap();
+
+        let http_acceptor = acceptor.clone();
+        let http_server = thread::spawn(move || {
+            let (stream, _) = http_listener.accept().unwrap();
+            let mut stream = http_acceptor.accept(stream).unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let read = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..read]);
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}").unwrap();
+        });
+        let ws_server = thread::spawn(move || {
+            let (stream, _) = ws_listener.accept().unwrap();
+            let mut socket = tungstenite::accept(acceptor.accept(stream).unwrap()).unwrap();
+            let msg = socket.read().unwrap();
+            socket.send(msg).unwrap();
+        });
+
+        let http_client = HttpClient::new(endpoints.http_url.clone(), endpoints.ca_certificate.as_deref(), no_retries());
+        assert_eq!(http_client.get("chat_api/user", None, None).unwrap().status(), 200);
+
+        let mut socket = init_websocket(&endpoints, "jwt".to_owned()).unwrap();
+        socket.send(Message::Text("ping".into())).unwrap();
+        assert_eq!(socket.read().unwrap(), Message::Text("ping".into()));
+
+        http_server.join().unwrap();
+        ws_server.join().unwrap();
+    }
+
+    #[test]
+    fn server_signed_by_an_unknown_ca_is_refused() {
+        let (_, acceptor) = test_ca();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let endpoints = ServerEndpoints {
+            http_url: format!("https://localhost:{}/", port).parse().unwrap(),
+            ws_url: format!("wss://localhost:{}/ws_server", port).parse().unwrap(),
+            ca_certificate: None,
+            connect_timeout: Duration::from_secs(1)
+        };
+
+        let server = thread::spawn(move || {
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().unwrap();
+                assert!(acceptor.accept(stream).is_err());
+            }
+        });
+
+        let http_client = HttpClient::new(endpoints.http_url.clone(), None, no_retries());
+        assert!(http_client.get("chat_api/user", None, None).is_err());
+        assert!(init_websocket(&endpoints, "jwt".to_owned()).is_err());
+        server.join().unwrap();
+    }
 }
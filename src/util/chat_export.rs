@@ -0,0 +1,227 @@
+use super::keyring_handler::write_private_file;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Json,
+    Text,
+    Html
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Text => "txt",
+            ExportFormat::Html => "html"
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportedMessage {
+    pub id: u64,
+    pub sender_id: u64,
+    pub sender_email: String,
+    // messages that couldn't be opened keep their id with the reason instead of the content
+    pub content: Option<String>,
+    pub decrypt_error: Option<String>,
+    #[serde(default)]
+    pub sent_at: Option<DateTime<Utc>>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChatExport {
+    pub chat_id: u64,
+    pub user_id: u64,
+    pub user_email: String,
+    pub contact_id: u64,
+    pub contact_email: String,
+    pub messages: Vec<ExportedMessage>
+}
+
+// the transcript holds decrypted messages, so it's written readable only by the user
+pub fn write_chat_export(file_path: &Path, format: ExportFormat, chat_export: &ChatExport) -> Result<(), std::io::Error> {
+    let contents = match format {
+        ExportFormat::Json => serde_json::to_string_pretty(chat_export)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?,
+        ExportFormat::Text => text_transcript(chat_export),
+        ExportFormat::Html => html_transcript(chat_export)
+    };
+
+    write_private_file(file_path, contents.as_bytes())
+}
+
+// only JSON exports can be read back
+pub fn read_chat_export(file_path: &Path) -> Result<ChatExport, std::io::Error> {
+    let raw_export = fs::read_to_string(file_path)?;
+    serde_json::from_str(&raw_export)
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "Not a JSON chat export"))
+}
+
+// UTC, so a transcript reads the same wherever it's opened
+fn message_time(message: &ExportedMessage) -> String {
+    message.sent_at
+        .map(|sent_at| sent_at.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_else(|| "unknown time".to_owned())
+}
+
+fn message_text(message: &ExportedMessage) -> String {
+    match (&message.content, &message.decrypt_error) {
+        (Some(content), _) => content.clone(),
+        (None, Some(e)) => format!("[unable to decrypt: {}]", e),
+        (None, None) => String::new()
+    }
+}
+
+fn text_transcript(chat_export: &ChatExport) -> String {
+    let mut transcript = format!(
+        "Chat between {} and {}\n\n",
+        chat_export.user_email,
+        chat_export.contact_email
+    );
+    for message in chat_export.messages.iter() {
+        transcript.push_str(&format!("[{}] {} ({}):\n", message.id, message.sender_email, message_time(message)));
+        for line in message_text(message).lines() {
+            transcript.push_str(&format!("    {}\n", line));
+        }
+        transcript.push('\n');
+    }
+
+    transcript
+}
+
+fn escape_html(text: &str) -> String {
+    text.chars().fold(String::with_capacity(text.len()), |mut escaped, c| {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c)
+        }
+        escaped
+    })
+}
+
+// no scripts or external resources, the file opens the same anywhere
+fn html_transcript(chat_export: &ChatExport) -> String {
+    let title = escape_html(&format!(
+        "Chat between {} and {}",
+        chat_export.user_email,
+        chat_export.contact_email
+    ));
+
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n<style>\n\
+        body {{ font-family: sans-serif; background: #1b1b1b; color: #e6e6e6; max-width: 720px; margin: 24px auto; }}\n\
+        .message {{ padding: 8px; margin: 6px 0; border-radius: 2px; max-width: 60%; white-space: pre-wrap; }}\n\
+        .sent {{ background: #005d80; margin-left: auto; }}\n\
+        .received {{ background: #121312; }}\n\
+        .meta {{ font-size: 0.75em; opacity: 0.6; }}\n\
+        .undecryptable {{ font-style: italic; opacity: 0.6; }}\n\
+        </style>\n</head>\n<body>\n<h1>{title}</h1>\n"
+    );
+    for message in chat_export.messages.iter() {
+        let side = if message.sender_id == chat_export.user_id { "sent" } else { "received" };
+        let content_class = if message.content.is_some() { "" } else { " undecryptable" };
+        html.push_str(&format!(
+            "<div class=\"message {}\">\n<div class=\"meta\">{} · {} · #{}</div>\n<div class=\"content{}\">{}</div>\n</div>\n",
+            side,
+            escape_html(&message.sender_email),
+            message_time(message),
+            message.id,
+            content_class,
+            escape_html(&message_text(message))
+        ));
+    }
+    html.push_str("</body>\n</html>\n");
+
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn chat_export(messages: Vec<ExportedMessage>) -> ChatExport {
+        ChatExport {
+            chat_id: 7,
+            user_id: 1,
+            user_email: "me@example.com".to_owned(),
+            contact_id: 2,
+            contact_email: "<b>them</b>@example.com".to_owned(),
+            messages
+        }
+    }
+
+    fn exported(id: u64, sender_id: u64, content: Option<&str>, decrypt_error: Option<&str>) -> ExportedMessage {
+        ExportedMessage {
+            id,
+            sender_id,
+            sender_email: if sender_id == 1 { "me@example.com" } else { "them@example.com" }.to_owned(),
+            content: content.map(str::to_owned),
+            decrypt_error: decrypt_error.map(str::to_owned),
+            sent_at: Some(Utc.with_ymd_and_hms(2024, 3, 9, 14, 5, 0).unwrap())
+        }
+    }
+
+    #[test]
+    fn html_special_characters_are_escaped() {
+        assert_eq!(
+            escape_html(r#"<a href="x" onclick='y'>&amp;</a>"#),
+            "&lt;a href=&quot;x&quot; onclick=&#39;y&#39;&gt;&amp;amp;&lt;/a&gt;"
+        );
+        assert_eq!(escape_html("plain text, olá"), "plain text, olá");
+    }
+
+    #[test]
+    fn message_content_cant_inject_html() {
+        let html = html_transcript(&chat_export(vec![
+            exported(1, 2, Some("<script>alert('x')</script>"), None),
+            exported(2, 2, Some("</div><img src=x onerror=alert(1)>"), None)
+        ]));
+
+        assert!(!html.contains("<script>"));
+        assert!(!html.contains("<img"));
+        assert!(html.contains("&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt;"));
+        assert!(html.contains("<title>Chat between me@example.com and &lt;b&gt;them&lt;/b&gt;@example.com</title>"));
+    }
+
+    #[test]
+    fn html_transcript_puts_own_messages_on_the_sent_side() {
+        let html = html_transcript(&chat_export(vec![
+            exported(1, 1, Some("mine"), None),
+            exported(2, 2, None, Some("no matching key"))
+        ]));
+
+        assert!(html.contains(
+            "<div class=\"message sent\">\n<div class=\"meta\">me@example.com · 2024-03-09 14:05:00 UTC · #1</div>\n<div class=\"content\">mine</div>"
+        ));
+        assert!(html.contains(
+            "<div class=\"message received\">\n<div class=\"meta\">them@example.com · 2024-03-09 14:05:00 UTC · #2</div>\n<div class=\"content undecryptable\">[unable to decrypt: no matching key]</div>"
+        ));
+    }
+
+    #[test]
+    fn text_transcript_indents_every_line_of_a_message() {
+        let mut unknown_time = exported(2, 2, Some("no time"), None);
+        unknown_time.sent_at = None;
+        let text = text_transcript(&chat_export(vec![
+            exported(1, 1, Some("first line\nsecond line"), None),
+            unknown_time
+        ]));
+
+        assert_eq!(
+            text,
+            "Chat between me@example.com and <b>them</b>@example.com\n\n\
+            [1] me@example.com (2024-03-09 14:05:00 UTC):\n    first line\n    second line\n\n\
+            [2] them@example.com (unknown time):\n    no time\n\n"
+        );
+    }
+}
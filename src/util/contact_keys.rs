@@ -0,0 +1,14 @@
+use super::local_store::{save_json, load_json};
+use std::collections::HashMap;
+
+// every public key seen for each contact (base64, oldest first), so history sealed
+// before a contact rotated their key can still be opened after the next login
+pub type ContactKeys = HashMap<u64, Vec<String>>;
+
+pub fn save_contact_keys(user_id: u64, contact_keys: &ContactKeys) -> Result<(), std::io::Error> {
+    save_json(user_id, "contact_keys.json", contact_keys)
+}
+
+pub fn load_contact_keys(user_id: u64) -> Result<ContactKeys, std::io::Error> {
+    load_json(user_id, "contact_keys.json")
+}
@@ -1,11 +1,40 @@
 use x25519_dalek::{StaticSecret, PublicKey};
+use base64::prelude::*;
 use hkdf::Hkdf;
 use sha2::Sha256;
 use chacha20poly1305::{
     XChaCha20Poly1305,
+    XNonce,
     KeyInit,
-    aead::{Aead, AeadCore}
+    aead::Aead
 };
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DecryptError {
+    InvalidEncoding,
+    InvalidNonce,
+    NoMatchingKey,
+    Replayed,
+    KeyDeleted,
+    InvalidPadding,
+    InvalidUtf8
+}
+
+impl fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::InvalidEncoding => write!(f, "The message isn't valid base64"),
+            Self::InvalidNonce => write!(f, "The message has a malformed nonce"),
+            Self::NoMatchingKey => write!(f, "None of your keys opens this message, or it was tampered with"),
+            Self::Replayed => write!(f, "The server already delivered this message under another id"),
+            Self::KeyDeleted => write!(f, "This message was already opened once and its key was deleted"),
+            Self::InvalidPadding => write!(f, "The message padding is corrupted"),
+            Self::InvalidUtf8 => write!(f, "The message isn't valid text")
+        }
+    }
+}
 
 pub fn generate_assymetric_keypair() -> (StaticSecret, PublicKey) {
     let private_key = StaticSecret::random_from_rng(rand::thread_rng());
@@ -23,9 +52,80 @@ pub fn generate_cipher(public_key: PublicKey, private_key: StaticSecret) -> XCha
     XChaCha20Poly1305::new_from_slice(&hash_buffer).unwrap()
 }
 
-pub fn encrypt_plain_text(cipher: &XChaCha20Poly1305, plain_text: String) -> (Vec<u8>, [u8; 24]) {
-    let nonce = XChaCha20Poly1305::generate_nonce(&mut rand::thread_rng());
-    let cipher_text = cipher.encrypt(&nonce, plain_text.as_ref()).unwrap();
+pub fn decrypt_cipher_text(cipher: &XChaCha20Poly1305, cipher_text: &[u8], nonce: &[u8; 24]) -> Result<String, DecryptError> {
+    let decrypted_bytes = cipher.decrypt(XNonce::from_slice(nonce), cipher_text)
+        .map_err(|_| DecryptError::NoMatchingKey)?;
+    String::from_utf8(decrypted_bytes).map_err(|_| DecryptError::InvalidUtf8)
+}
+
+// the server sends content and nonce as base64; decrypt gets them decoded and picks the keys
+pub fn decode_and_decrypt<F>(content: &str, nonce: &str, decrypt: F) -> Result<String, DecryptError>
+where
+    F: FnOnce(&[u8], &[u8; 24]) -> Result<String, DecryptError>
+{
+    let cipher_text = BASE64_STANDARD.decode(content).map_err(|_| DecryptError::InvalidEncoding)?;
+    let nonce: [u8; 24] = BASE64_STANDARD.decode(nonce)
+        .map_err(|_| DecryptError::InvalidEncoding)?
+        .try_into()
+        .map_err(|_| DecryptError::InvalidNonce)?;
+
+    decrypt(&cipher_text, &nonce)
+}
+
+const PADDING_BUCKETS: [usize; 7] = [64, 128, 256, 512, 1024, 2048, 4096];
+const LENGTH_PREFIX: usize = 4;
+
+// the plain text goes behind its length in a block of the next bucket size, so the
+// ciphertext only reveals the bucket; past the largest one it grows in steps of that size
+pub fn pad_plain_text(plain_text: &[u8]) -> Vec<u8> {
+    let length = LENGTH_PREFIX + plain_text.len();
+    let largest_bucket = PADDING_BUCKETS[PADDING_BUCKETS.len() - 1];
+    let bucket = PADDING_BUCKETS.iter()
+        .copied()
+        .find(|bucket| *bucket >= length)
+        .unwrap_or_else(|| length.div_ceil(largest_bucket) * largest_bucket);
+
+    let mut padded = Vec::with_capacity(bucket);
+    padded.extend_from_slice(&(plain_text.len() as u32).to_le_bytes());
+    padded.extend_from_slice(plain_text);
+    padded.resize(bucket, 0);
+    padded
+}
+
+pub fn unpad_plain_text(padded: &[u8]) -> Option<&[u8]> {
+    let length = u32::from_le_bytes(padded.get(..LENGTH_PREFIX)?.try_into().unwrap()) as usize;
+    padded.get(LENGTH_PREFIX..LENGTH_PREFIX.checked_add(length)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn padded_plain_text_comes_back_unchanged() {
+        for length in [0, 1, 59, 60, 61, 4092, 4093, 10_000] {
+            let plain_text = vec![b'a'; length];
+
+            let padded = pad_plain_text(&plain_text);
+
+            assert_eq!(unpad_plain_text(&padded), Some(plain_text.as_slice()));
+        }
+    }
+
+    #[test]
+    fn padding_only_reveals_the_bucket() {
+        assert_eq!(pad_plain_text(b"").len(), 64);
+        assert_eq!(pad_plain_text(&[b'a'; 60]).len(), 64);
+        assert_eq!(pad_plain_text(&[b'a'; 61]).len(), 128);
+        assert_eq!(pad_plain_text(&[b'a'; 4093]).len(), 8192);
+    }
+
+    #[test]
+    fn length_past_the_padded_block_is_rejected() {
+        let mut padded = pad_plain_text(b"hello");
+        padded[..LENGTH_PREFIX].copy_from_slice(&1000u32.to_le_bytes());
 
-    (cipher_text, *nonce.as_ref())
+        assert_eq!(unpad_plain_text(&padded), None);
+        assert_eq!(unpad_plain_text(&[1, 0]), None);
+    }
 }
@@ -1,29 +1,677 @@
-use std::fs::{self, File};
+use super::settings::app_folder_path;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    XChaCha20Poly1305,
+    XNonce,
+    KeyInit,
+    aead::{Aead, AeadCore}
+};
+use rand::RngCore;
+use secret_service::EncryptionType;
+use secret_service::blocking::{Collection, SecretService};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, OpenOptions};
 use std::io::prelude::*;
-use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use x25519_dalek::{PublicKey, StaticSecret};
 
-const SERVICE_NAME: &str = "nossochat-service";
+// file layout: magic | version | m_cost | t_cost | p_cost | salt | nonce | sealed keys
+// the sealed keys are the current one followed by the retired ones
+const KEY_FILE_MAGIC: &[u8; 4] = b"NCPK";
+const KEY_FILE_VERSION: u8 = 2;
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 24;
+const HEADER_LENGTH: usize = KEY_FILE_MAGIC.len() + 1 + 12 + SALT_LENGTH + NONCE_LENGTH;
+const LEGACY_KEY_FILE_LENGTH: usize = 32;
+const SECRET_SERVICE_APPLICATION: &str = "nossochat";
+// the parameters come from the file, a restored backup can't ask for more than this
+const MAX_M_COST: u32 = 256 * 1024;
+const MAX_T_COST: u32 = 16;
+const MAX_P_COST: u32 = 8;
 
-pub fn save_private_key(user_email: String, private_key_bytes: &[u8; 32]) {
-    let home_dir = env::var("HOME")
-        .map(PathBuf::from)
-        .expect("Couldn't determine home directory");
-    let folder_path = home_dir.join(format!(".{}/", SERVICE_NAME));
-    fs::create_dir_all(&folder_path).expect("Couldn't create app's folder");
+pub const MIN_PASSPHRASE_LENGTH: usize = 8;
+pub const DEFAULT_BACKUP_FILE_NAME: &str = "nossochat_key_backup.bin";
 
-    let file_path = folder_path.join(format!("{}_private.bin", user_email));
-    let mut file = File::create(file_path).expect("Couldn't create secret file");
-    file.write_all(private_key_bytes).expect("Couldn't write private key into file");
+#[derive(Clone)]
+pub struct PrivateKeySet {
+    pub current: [u8; 32],
+    // newest first, kept so history sealed under them still decrypts
+    pub previous: Vec<[u8; 32]>
 }
 
-pub fn get_private_key(user_email: String) -> Vec<u8> {
-    let home_dir = env::var("HOME")
-        .map(PathBuf::from)
-        .expect("Couldn't determine home directory");
-    let folder_path = home_dir.join(format!(".{}/", SERVICE_NAME));
-    fs::create_dir_all(&folder_path).expect("Couldn't create app's folder");
+impl PrivateKeySet {
+    pub fn new(current: [u8; 32]) -> Self {
+        Self { current, previous: Vec::new() }
+    }
 
-    let file_path = folder_path.join(format!("{}_private.bin", user_email));
-    fs::read(file_path).expect("Couldn't read private file")
+    pub fn rotate(&mut self, new_current: [u8; 32]) {
+        let old_current = std::mem::replace(&mut self.current, new_current);
+        self.previous.insert(0, old_current);
+    }
+
+    // used when a retired key turns out to be the one the server still has
+    pub fn promote(&mut self, index: usize) {
+        let promoted = self.previous.remove(index);
+        self.rotate(promoted);
+    }
+
+    // keeps every key of the other set as a retired one, unless this set already has it
+    pub fn merge(&mut self, other: &PrivateKeySet) {
+        for key in std::iter::once(&other.current).chain(other.previous.iter()) {
+            let public_key = public_key_of(key);
+            let is_known = std::iter::once(&self.current)
+                .chain(self.previous.iter())
+                .any(|known_key| public_key_of(known_key) == public_key);
+            if !is_known {
+                self.previous.push(*key);
+            }
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        std::iter::once(&self.current)
+            .chain(self.previous.iter())
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    fn from_bytes(raw_keys: &[u8]) -> Option<Self> {
+        if raw_keys.is_empty() || !raw_keys.len().is_multiple_of(32) {
+            return None;
+        }
+
+        let mut keys = raw_keys.chunks_exact(32).map(|key| key.try_into().unwrap());
+        Some(Self { current: keys.next()?, previous: keys.collect() })
+    }
+}
+
+fn public_key_of(private_key: &[u8; 32]) -> PublicKey {
+    PublicKey::from(&StaticSecret::from(*private_key))
+}
+
+pub enum KeyFileStatus {
+    // raw 32 byte secret written before the file was passphrase protected
+    Legacy,
+    Encrypted
+}
+
+pub enum KeyFileError {
+    NotFound,
+    WrongPassphrase,
+    Corrupted(String),
+    Io(std::io::Error)
+}
+
+impl fmt::Display for KeyFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "No private key was found for this account on this device"),
+            Self::WrongPassphrase => write!(f, "Wrong passphrase"),
+            Self::Corrupted(message) => write!(f, "Private key file is corrupted: {}", message),
+            Self::Io(e) => write!(f, "Couldn't access private key: {}", e)
+        }
+    }
+}
+
+impl From<std::io::Error> for KeyFileError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+// where the sealed key blob lives; sealing and unsealing are the same for every backend
+pub trait KeyStore {
+    fn read(&self, user_email: &str) -> Result<Option<Vec<u8>>, std::io::Error>;
+    fn write(&self, user_email: &str, raw_key: &[u8]) -> Result<(), std::io::Error>;
+    fn delete(&self, user_email: &str) -> Result<(), std::io::Error>;
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyStoreKind {
+    #[default]
+    File,
+    SecretService
+}
+
+impl KeyStoreKind {
+    pub const ALL: [KeyStoreKind; 2] = [Self::File, Self::SecretService];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::File => "File",
+            Self::SecretService => "System keyring"
+        }
+    }
+
+    pub fn open(&self) -> Box<dyn KeyStore> {
+        match self {
+            Self::File => Box::new(FileKeyStore),
+            Self::SecretService => Box::new(SecretServiceKeyStore(DbusCollection))
+        }
+    }
+}
+
+pub struct FileKeyStore;
+
+impl KeyStore for FileKeyStore {
+    fn read(&self, user_email: &str) -> Result<Option<Vec<u8>>, std::io::Error> {
+        let file_path = key_file_path(user_email);
+        match fs::read(&file_path) {
+            Ok(raw_key) => {
+                restrict_permissions(&file_path)?;
+                Ok(Some(raw_key))
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    fn write(&self, user_email: &str, raw_key: &[u8]) -> Result<(), std::io::Error> {
+        fs::create_dir_all(app_folder_path())?;
+        write_private_file(&key_file_path(user_email), raw_key)
+    }
+
+    fn delete(&self, user_email: &str) -> Result<(), std::io::Error> {
+        match fs::remove_file(key_file_path(user_email)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(())
+        }
+    }
+}
+
+// the few Secret Service calls the key store makes, the D-Bus side talks to whatever provider
+// owns the session bus
+pub trait SecretCollection {
+    fn find_secret(&self, attributes: HashMap<&str, &str>) -> Result<Option<Vec<u8>>, std::io::Error>;
+    fn replace_secret(&self, label: &str, attributes: HashMap<&str, &str>, secret: &[u8]) -> Result<(), std::io::Error>;
+    fn delete_secrets(&self, attributes: HashMap<&str, &str>) -> Result<(), std::io::Error>;
+}
+
+pub struct DbusCollection;
+
+impl DbusCollection {
+    fn with_default_collection<T>(f: impl FnOnce(&Collection) -> Result<T, secret_service::Error>) -> Result<T, std::io::Error> {
+        let secret_service = SecretService::connect(EncryptionType::Dh).map_err(secret_service_error)?;
+        let collection = secret_service.get_default_collection().map_err(secret_service_error)?;
+        collection.ensure_unlocked().map_err(secret_service_error)?;
+
+        f(&collection).map_err(secret_service_error)
+    }
+}
+
+impl SecretCollection for DbusCollection {
+    fn find_secret(&self, attributes: HashMap<&str, &str>) -> Result<Option<Vec<u8>>, std::io::Error> {
+        Self::with_default_collection(|collection| {
+            match collection.search_items(attributes)?.first() {
+                Some(item) => item.get_secret().map(Some),
+                None => Ok(None)
+            }
+        })
+    }
+
+    fn replace_secret(&self, label: &str, attributes: HashMap<&str, &str>, secret: &[u8]) -> Result<(), std::io::Error> {
+        Self::with_default_collection(|collection| {
+            collection.create_item(label, attributes, secret, true, "application/octet-stream")?;
+            Ok(())
+        })
+    }
+
+    fn delete_secrets(&self, attributes: HashMap<&str, &str>) -> Result<(), std::io::Error> {
+        Self::with_default_collection(|collection| {
+            for item in collection.search_items(attributes)? {
+                item.delete()?;
+            }
+            Ok(())
+        })
+    }
+}
+
+pub struct SecretServiceKeyStore<C: SecretCollection = DbusCollection>(pub C);
+
+impl<C: SecretCollection> SecretServiceKeyStore<C> {
+    fn attributes(user_email: &str) -> HashMap<&str, &str> {
+        HashMap::from([("application", SECRET_SERVICE_APPLICATION), ("email", user_email)])
+    }
+}
+
+impl<C: SecretCollection> KeyStore for SecretServiceKeyStore<C> {
+    fn read(&self, user_email: &str) -> Result<Option<Vec<u8>>, std::io::Error> {
+        self.0.find_secret(Self::attributes(user_email))
+    }
+
+    fn write(&self, user_email: &str, raw_key: &[u8]) -> Result<(), std::io::Error> {
+        self.0.replace_secret(
+            &format!("NossoChat private key ({})", user_email),
+            Self::attributes(user_email),
+            raw_key
+        )
+    }
+
+    fn delete(&self, user_email: &str) -> Result<(), std::io::Error> {
+        self.0.delete_secrets(Self::attributes(user_email))
+    }
+}
+
+fn secret_service_error(e: secret_service::Error) -> std::io::Error {
+    std::io::Error::other(format!("Secret Service: {}", e))
+}
+
+fn key_file_path(user_email: &str) -> PathBuf {
+    app_folder_path().join(format!("{}_private.bin", user_email))
+}
+
+pub fn key_file_status(raw_key: &[u8]) -> KeyFileStatus {
+    if !raw_key.starts_with(KEY_FILE_MAGIC) && raw_key.len() == LEGACY_KEY_FILE_LENGTH {
+        KeyFileStatus::Legacy
+    } else {
+        KeyFileStatus::Encrypted
+    }
+}
+
+// the sealed key is the same for every backend, so one left in the backend used before a switch
+// can be moved over as is; that one is only looked at when asked, reaching the system keyring
+// can bring up its unlock prompt
+pub fn read_private_key(
+    key_store: KeyStoreKind,
+    migrate_from: Option<KeyStoreKind>,
+    user_email: &str
+) -> Result<Option<Vec<u8>>, std::io::Error> {
+    let other_key_stores: Vec<Box<dyn KeyStore>> = migrate_from.iter()
+        .filter(|other| **other != key_store)
+        .map(|other| other.open())
+        .collect();
+
+    find_private_key(&*key_store.open(), &other_key_stores, user_email)
+}
+
+fn find_private_key(
+    key_store: &dyn KeyStore,
+    other_key_stores: &[Box<dyn KeyStore>],
+    user_email: &str
+) -> Result<Option<Vec<u8>>, std::io::Error> {
+    if let Some(raw_key) = key_store.read(user_email)? {
+        return Ok(Some(raw_key));
+    }
+
+    // a backend that can't be reached can't be holding the key either
+    for other_key_store in other_key_stores {
+        if let Ok(Some(raw_key)) = other_key_store.read(user_email) {
+            key_store.write(user_email, &raw_key)?;
+            // the copy is already in place, a leftover in the old backend only costs space
+            let _ = other_key_store.delete(user_email);
+            return Ok(Some(raw_key));
+        }
+    }
+
+    Ok(None)
+}
+
+pub fn save_private_key(
+    key_store: &dyn KeyStore,
+    user_email: &str,
+    private_keys: &PrivateKeySet,
+    passphrase: &str
+) -> Result<(), std::io::Error> {
+    key_store.write(user_email, &seal_private_key(private_keys, passphrase)?)
+}
+
+pub fn get_private_key(key_store: &dyn KeyStore, user_email: &str, passphrase: &str) -> Result<PrivateKeySet, KeyFileError> {
+    let raw_key = key_store.read(user_email)?.ok_or(KeyFileError::NotFound)?;
+
+    unseal_private_key(key_store, user_email, &raw_key, passphrase)
+}
+
+// a legacy plaintext key is accepted with any passphrase and rewritten sealed with it
+pub fn unseal_private_key(
+    key_store: &dyn KeyStore,
+    user_email: &str,
+    raw_key: &[u8],
+    passphrase: &str
+) -> Result<PrivateKeySet, KeyFileError> {
+    if let KeyFileStatus::Legacy = key_file_status(raw_key) {
+        let private_keys = PrivateKeySet::new(raw_key.try_into().unwrap());
+        save_private_key(key_store, user_email, &private_keys, passphrase)?;
+        return Ok(private_keys);
+    }
+
+    open_key_file(raw_key, passphrase)
+}
+
+// backups use the same sealed format as the stored key, so a copied key file restores too
+pub fn export_private_key(backup_path: &Path, private_keys: &PrivateKeySet, passphrase: &str) -> Result<(), std::io::Error> {
+    write_private_file(backup_path, &seal_private_key(private_keys, passphrase)?)
+}
+
+pub fn import_private_key(backup_path: &Path, passphrase: &str) -> Result<PrivateKeySet, KeyFileError> {
+    let raw_key = fs::read(backup_path)?;
+
+    open_key_file(&raw_key, passphrase)
+}
+
+fn seal_private_key(private_keys: &PrivateKeySet, passphrase: &str) -> Result<Vec<u8>, std::io::Error> {
+    let params = Params::default();
+    let mut salt = [0u8; SALT_LENGTH];
+    rand::thread_rng().fill_bytes(&mut salt);
+
+    let cipher = derive_cipher(passphrase, &salt, &params)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut rand::thread_rng());
+    let sealed_key = cipher.encrypt(&nonce, private_keys.to_bytes().as_ref())
+        .map_err(|_| std::io::Error::other("Couldn't encrypt private key"))?;
+
+    let mut raw_key = Vec::with_capacity(HEADER_LENGTH + sealed_key.len());
+    raw_key.extend_from_slice(KEY_FILE_MAGIC);
+    raw_key.push(KEY_FILE_VERSION);
+    raw_key.extend_from_slice(&params.m_cost().to_le_bytes());
+    raw_key.extend_from_slice(&params.t_cost().to_le_bytes());
+    raw_key.extend_from_slice(&params.p_cost().to_le_bytes());
+    raw_key.extend_from_slice(&salt);
+    raw_key.extend_from_slice(&nonce);
+    raw_key.extend_from_slice(&sealed_key);
+
+    Ok(raw_key)
+}
+
+fn open_key_file(raw_file: &[u8], passphrase: &str) -> Result<PrivateKeySet, KeyFileError> {
+    if raw_file.len() < HEADER_LENGTH || !raw_file.starts_with(KEY_FILE_MAGIC) {
+        return Err(KeyFileError::Corrupted("unknown format".to_owned()));
+    }
+
+    let (header, sealed_key) = raw_file.split_at(HEADER_LENGTH);
+    let version = header[KEY_FILE_MAGIC.len()];
+    if version != KEY_FILE_VERSION {
+        return Err(KeyFileError::Corrupted(format!("unsupported version {}", version)));
+    }
+
+    let read_u32 = |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap());
+    let params_offset = KEY_FILE_MAGIC.len() + 1;
+    let (m_cost, t_cost, p_cost) = (read_u32(params_offset), read_u32(params_offset + 4), read_u32(params_offset + 8));
+    if m_cost > MAX_M_COST || t_cost > MAX_T_COST || p_cost > MAX_P_COST {
+        return Err(KeyFileError::Corrupted("key derivation parameters are too high".to_owned()));
+    }
+    let params = Params::new(m_cost, t_cost, p_cost, Some(32))
+        .map_err(|e| KeyFileError::Corrupted(e.to_string()))?;
+
+    let salt_offset = params_offset + 12;
+    let salt = &header[salt_offset..salt_offset + SALT_LENGTH];
+    let nonce = XNonce::from_slice(&header[salt_offset + SALT_LENGTH..]);
+
+    let cipher = derive_cipher(passphrase, salt, &params)
+        .map_err(|e| KeyFileError::Corrupted(e.to_string()))?;
+    let private_key_bytes = cipher.decrypt(nonce, sealed_key)
+        .map_err(|_| KeyFileError::WrongPassphrase)?;
+
+    PrivateKeySet::from_bytes(&private_key_bytes)
+        .ok_or_else(|| KeyFileError::Corrupted("bad key length".to_owned()))
+}
+
+fn derive_cipher(passphrase: &str, salt: &[u8], params: &Params) -> Result<XChaCha20Poly1305, argon2::Error> {
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone());
+
+    let mut key = [0u8; 32];
+    argon2.hash_password_into(passphrase.as_bytes(), salt, &mut key)?;
+
+    Ok(XChaCha20Poly1305::new_from_slice(&key).unwrap())
+}
+
+// written next to the target and renamed over it, so a crash never leaves half a key behind
+pub fn write_private_file(file_path: &Path, contents: &[u8]) -> Result<(), std::io::Error> {
+    let temp_path = temp_file_path(file_path)?;
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let written = options.open(&temp_path).and_then(|mut file| {
+        file.write_all(contents)?;
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|_| fs::rename(&temp_path, file_path)) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+// a random hidden name, so the temp file never lands on a file the user already has there
+fn temp_file_path(file_path: &Path) -> Result<PathBuf, std::io::Error> {
+    let file_name = file_path.file_name()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Not a file path"))?;
+    let mut suffix = [0u8; 8];
+    rand::thread_rng().fill_bytes(&mut suffix);
+    let suffix: String = suffix.iter().map(|b| format!("{:02x}", b)).collect();
+
+    Ok(file_path.with_file_name(format!(".{}.{}.tmp", file_name.to_string_lossy(), suffix)))
+}
+
+fn restrict_permissions(file_path: &Path) -> Result<(), std::io::Error> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if fs::metadata(file_path)?.permissions().mode() & 0o777 != 0o600 {
+            fs::set_permissions(file_path, fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = file_path;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    // stands in for a backend, None plays one whose daemon isn't running
+    struct StandInKeyStore(Option<RefCell<HashMap<String, Vec<u8>>>>);
+
+    impl StandInKeyStore {
+        fn with_key(user_email: &str, raw_key: &[u8]) -> Self {
+            Self(Some(RefCell::new(HashMap::from([(user_email.to_owned(), raw_key.to_vec())]))))
+        }
+
+        fn empty() -> Self {
+            Self(Some(RefCell::new(HashMap::new())))
+        }
+
+        fn entries(&self) -> Result<&RefCell<HashMap<String, Vec<u8>>>, std::io::Error> {
+            self.0.as_ref().ok_or_else(|| std::io::Error::other("not running"))
+        }
+    }
+
+    impl KeyStore for StandInKeyStore {
+        fn read(&self, user_email: &str) -> Result<Option<Vec<u8>>, std::io::Error> {
+            Ok(self.entries()?.borrow().get(user_email).cloned())
+        }
+
+        fn write(&self, user_email: &str, raw_key: &[u8]) -> Result<(), std::io::Error> {
+            self.entries()?.borrow_mut().insert(user_email.to_owned(), raw_key.to_vec());
+            Ok(())
+        }
+
+        fn delete(&self, user_email: &str) -> Result<(), std::io::Error> {
+            self.entries()?.borrow_mut().remove(user_email);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn key_in_the_previous_backend_is_moved_over() {
+        let key_store = StandInKeyStore::empty();
+        let other_key_stores: Vec<Box<dyn KeyStore>> = vec![Box::new(StandInKeyStore::with_key("a@b.c", b"sealed"))];
+
+        let raw_key = find_private_key(&key_store, &other_key_stores, "a@b.c").unwrap();
+
+        assert_eq!(raw_key.as_deref(), Some(&b"sealed"[..]));
+        assert_eq!(key_store.read("a@b.c").unwrap().as_deref(), Some(&b"sealed"[..]));
+        assert!(other_key_stores[0].read("a@b.c").unwrap().is_none());
+    }
+
+    #[test]
+    fn key_in_the_chosen_backend_wins() {
+        let key_store = StandInKeyStore::with_key("a@b.c", b"current");
+        let other_key_stores: Vec<Box<dyn KeyStore>> = vec![Box::new(StandInKeyStore::with_key("a@b.c", b"stale"))];
+
+        let raw_key = find_private_key(&key_store, &other_key_stores, "a@b.c").unwrap();
+
+        assert_eq!(raw_key.as_deref(), Some(&b"current"[..]));
+        assert!(other_key_stores[0].read("a@b.c").unwrap().is_some());
+    }
+
+    #[test]
+    fn unreachable_previous_backend_reports_the_key_missing() {
+        let key_store = StandInKeyStore::empty();
+        let other_key_stores: Vec<Box<dyn KeyStore>> = vec![Box::new(StandInKeyStore(None))];
+
+        assert!(find_private_key(&key_store, &other_key_stores, "a@b.c").unwrap().is_none());
+    }
+
+    #[test]
+    fn unreachable_chosen_backend_is_an_error() {
+        let key_store = StandInKeyStore(None);
+        let other_key_stores: Vec<Box<dyn KeyStore>> = vec![Box::new(StandInKeyStore::with_key("a@b.c", b"sealed"))];
+
+        assert!(find_private_key(&key_store, &other_key_stores, "a@b.c").is_err());
+    }
+
+    // behaves like a Secret Service collection: items match when they carry every asked attribute,
+    // and replacing drops the item with the very same attributes
+    type StandInItem = (String, HashMap<String, String>, Vec<u8>);
+
+    #[derive(Default)]
+    struct StandInCollection(RefCell<Vec<StandInItem>>);
+
+    fn matches(item_attributes: &HashMap<String, String>, attributes: &HashMap<&str, &str>) -> bool {
+        attributes.iter().all(|(key, value)| item_attributes.get(*key).is_some_and(|v| v == value))
+    }
+
+    impl SecretCollection for &StandInCollection {
+        fn find_secret(&self, attributes: HashMap<&str, &str>) -> Result<Option<Vec<u8>>, std::io::Error> {
+            Ok(self.0.borrow().iter().find(|item| matches(&item.1, &attributes)).map(|item| item.2.clone()))
+        }
+
+        fn replace_secret(&self, label: &str, attributes: HashMap<&str, &str>, secret: &[u8]) -> Result<(), std::io::Error> {
+            let attributes: HashMap<String, String> = attributes.into_iter()
+                .map(|(key, value)| (key.to_owned(), value.to_owned()))
+                .collect();
+            let mut items = self.0.borrow_mut();
+            items.retain(|item| item.1 != attributes);
+            items.push((label.to_owned(), attributes, secret.to_vec()));
+            Ok(())
+        }
+
+        fn delete_secrets(&self, attributes: HashMap<&str, &str>) -> Result<(), std::io::Error> {
+            self.0.borrow_mut().retain(|item| !matches(&item.1, &attributes));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn keyring_keeps_one_key_per_account() {
+        let collection = StandInCollection::default();
+        let key_store = SecretServiceKeyStore(&collection);
+
+        key_store.write("a@b.c", b"first").unwrap();
+        key_store.write("d@e.f", b"other").unwrap();
+        key_store.write("a@b.c", b"second").unwrap();
+
+        assert_eq!(key_store.read("a@b.c").unwrap().as_deref(), Some(&b"second"[..]));
+        assert_eq!(key_store.read("d@e.f").unwrap().as_deref(), Some(&b"other"[..]));
+        assert_eq!(collection.0.borrow().len(), 2);
+        assert!(collection.0.borrow().iter().any(|item| item.0 == "NossoChat private key (a@b.c)"));
+    }
+
+    #[test]
+    fn keyring_items_of_other_applications_are_left_alone() {
+        let collection = StandInCollection::default();
+        collection.0.borrow_mut().push((
+            "Mail password".to_owned(),
+            HashMap::from([("email".to_owned(), "a@b.c".to_owned())]),
+            b"hunter2".to_vec()
+        ));
+        let key_store = SecretServiceKeyStore(&collection);
+
+        assert!(key_store.read("a@b.c").unwrap().is_none());
+
+        key_store.write("a@b.c", b"sealed").unwrap();
+        key_store.delete("a@b.c").unwrap();
+
+        assert!(key_store.read("a@b.c").unwrap().is_none());
+        assert_eq!(collection.0.borrow().len(), 1);
+        assert_eq!(collection.0.borrow()[0].2, b"hunter2");
+    }
+
+    #[test]
+    fn key_in_the_file_is_moved_to_the_keyring() {
+        let collection = StandInCollection::default();
+        let key_store = SecretServiceKeyStore(&collection);
+        let other_key_stores: Vec<Box<dyn KeyStore>> = vec![Box::new(StandInKeyStore::with_key("a@b.c", b"sealed"))];
+
+        let raw_key = find_private_key(&key_store, &other_key_stores, "a@b.c").unwrap();
+
+        assert_eq!(raw_key.as_deref(), Some(&b"sealed"[..]));
+        assert_eq!(key_store.read("a@b.c").unwrap().as_deref(), Some(&b"sealed"[..]));
+        assert!(other_key_stores[0].read("a@b.c").unwrap().is_none());
+    }
+
+    #[test]
+    fn sealed_keys_open_with_their_passphrase_only() {
+        let mut private_keys = PrivateKeySet::new([1; 32]);
+        private_keys.rotate([2; 32]);
+
+        let raw_key = seal_private_key(&private_keys, "correct horse").unwrap();
+
+        let Ok(opened_keys) = open_key_file(&raw_key, "correct horse") else {
+            panic!("the sealed key didn't open");
+        };
+        assert_eq!(opened_keys.current, [2; 32]);
+        assert_eq!(opened_keys.previous, [[1; 32]]);
+        assert!(matches!(open_key_file(&raw_key, "wrong horse"), Err(KeyFileError::WrongPassphrase)));
+    }
+
+    #[test]
+    fn legacy_key_is_sealed_with_the_passphrase_it_was_unlocked_with() {
+        let key_store = StandInKeyStore::with_key("a@b.c", &[3; 32]);
+
+        let Ok(private_keys) = unseal_private_key(&key_store, "a@b.c", &[3; 32], "correct horse") else {
+            panic!("the legacy key wasn't accepted");
+        };
+        assert_eq!(private_keys.current, [3; 32]);
+
+        let raw_key = key_store.read("a@b.c").unwrap().unwrap();
+        assert!(matches!(key_file_status(&raw_key), KeyFileStatus::Encrypted));
+        assert!(open_key_file(&raw_key, "correct horse").is_ok_and(|keys| keys.current == [3; 32]));
+    }
+
+    #[test]
+    fn merged_sets_keep_every_key_once() {
+        let mut private_keys = PrivateKeySet::new([1; 32]);
+        private_keys.rotate([2; 32]);
+        let mut other_private_keys = PrivateKeySet::new([3; 32]);
+        other_private_keys.rotate([1; 32]);
+
+        private_keys.merge(&other_private_keys);
+
+        assert_eq!(private_keys.current, [2; 32]);
+        assert_eq!(private_keys.previous, [[1; 32], [3; 32]]);
+    }
+
+    #[test]
+    fn key_file_asking_for_too_much_memory_is_rejected() {
+        let mut raw_key = seal_private_key(&PrivateKeySet::new([4; 32]), "correct horse").unwrap();
+        let params_offset = KEY_FILE_MAGIC.len() + 1;
+        raw_key[params_offset..params_offset + 4].copy_from_slice(&(MAX_M_COST + 1).to_le_bytes());
+
+        assert!(matches!(open_key_file(&raw_key, "correct horse"), Err(KeyFileError::Corrupted(_))));
+    }
 }
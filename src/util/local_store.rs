@@ -0,0 +1,300 @@
+use super::keyring_handler::write_private_file;
+use super::settings::app_folder_path;
+use chacha20poly1305::{
+    XChaCha20Poly1305,
+    XNonce,
+    KeyInit,
+    aead::{Aead, AeadCore}
+};
+use hkdf::Hkdf;
+use serde::{Serialize, de::DeserializeOwned};
+use sha2::Sha256;
+use x25519_dalek::StaticSecret;
+use std::fs::{self, OpenOptions};
+use std::io::prelude::*;
+use std::path::PathBuf;
+
+const SEALED_NONCE_LENGTH: usize = 24;
+// logs start with this, a file without it isn't one
+const LOG_MAGIC: &[u8; 4] = b"NCSL";
+// past this many appended records the log is rewritten as one on the next load
+const MAX_LOG_RECORDS: usize = 256;
+
+// every per-user file lives in the app folder as "{user_id}_{name}", readable only by the user
+fn store_path(user_id: u64, name: &str) -> PathBuf {
+    app_folder_path().join(format!("{}_{}", user_id, name))
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+}
+
+pub fn save_json<T: Serialize + ?Sized>(user_id: u64, name: &str, value: &T) -> Result<(), std::io::Error> {
+    fs::create_dir_all(app_folder_path())?;
+
+    let raw_value = serde_json::to_vec(value).map_err(invalid_data)?;
+    write_private_file(&store_path(user_id, name), &raw_value)
+}
+
+// nothing saved yet loads as empty
+pub fn load_json<T: DeserializeOwned + Default>(user_id: u64, name: &str) -> Result<T, std::io::Error> {
+    let raw_value = match fs::read(store_path(user_id, name)) {
+        Ok(raw_value) => raw_value,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(T::default()),
+        Err(e) => return Err(e)
+    };
+
+    serde_json::from_slice(&raw_value).map_err(invalid_data)
+}
+
+// what loading a log found besides its records
+#[derive(Default)]
+pub struct LogStatus {
+    // long logs, a record cut short by a crash and damaged records all ask for one
+    pub needs_rewrite: bool,
+    pub damaged_records: usize,
+    // a copy of the file kept before it's rewritten without the damaged records
+    pub set_aside_path: Option<PathBuf>
+}
+
+// a file sealed with a key derived from the private key current when it was written,
+// so it still opens after a rotation as long as the old key is kept
+pub struct SealedStore {
+    pub name: &'static str,
+    pub label: &'static str,
+    pub salt: &'static [u8],
+    pub info: &'static [u8]
+}
+
+impl SealedStore {
+    fn cipher(&self, private_key: &StaticSecret) -> XChaCha20Poly1305 {
+        let hk = Hkdf::<Sha256>::new(Some(self.salt), private_key.as_bytes());
+        let mut key = [0u8; 32];
+        hk.expand(self.info, &mut key).unwrap();
+
+        XChaCha20Poly1305::new_from_slice(&key).unwrap()
+    }
+
+    // nonce | sealed json
+    fn seal<T: Serialize + ?Sized>(&self, private_key: &StaticSecret, value: &T) -> Result<Vec<u8>, std::io::Error> {
+        let raw_value = serde_json::to_vec(value).map_err(invalid_data)?;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut rand::thread_rng());
+        let sealed_value = self.cipher(private_key).encrypt(&nonce, raw_value.as_ref())
+            .map_err(|_| std::io::Error::other(format!("Couldn't seal {}", self.label)))?;
+
+        Ok([nonce.as_slice(), &sealed_value].concat())
+    }
+
+    fn open<T: DeserializeOwned>(&self, private_keys: &[StaticSecret], sealed: &[u8]) -> Result<T, std::io::Error> {
+        if sealed.len() < SEALED_NONCE_LENGTH {
+            return Err(invalid_data(format!("{} is truncated", self.label)));
+        }
+        let (nonce, sealed_value) = sealed.split_at(SEALED_NONCE_LENGTH);
+
+        let raw_value = private_keys.iter()
+            .find_map(|private_key| self.cipher(private_key).decrypt(XNonce::from_slice(nonce), sealed_value).ok())
+            .ok_or_else(|| invalid_data(format!("{} doesn't open with any of your keys", self.label)))?;
+
+        serde_json::from_slice(&raw_value).map_err(invalid_data)
+    }
+
+    fn read(&self, user_id: u64) -> Result<Option<Vec<u8>>, std::io::Error> {
+        match fs::read(store_path(user_id, self.name)) {
+            Ok(raw_file) => Ok(Some(raw_file)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    pub fn save<T: Serialize + ?Sized>(&self, user_id: u64, private_key: &StaticSecret, value: &T) -> Result<(), std::io::Error> {
+        fs::create_dir_all(app_folder_path())?;
+        write_private_file(&store_path(user_id, self.name), &self.seal(private_key, value)?)
+    }
+
+    pub fn load<T: DeserializeOwned + Default>(&self, user_id: u64, private_keys: &[StaticSecret]) -> Result<T, std::io::Error> {
+        match self.read(user_id)? {
+            Some(raw_file) => self.open(private_keys, &raw_file),
+            None => Ok(T::default())
+        }
+    }
+
+    // log layout: magic | (record length as u32 | nonce | sealed json)*, each record holding
+    // only what changed since the one before
+    pub fn append<T: Serialize + ?Sized>(&self, user_id: u64, private_key: &StaticSecret, record: &T) -> Result<(), std::io::Error> {
+        fs::create_dir_all(app_folder_path())?;
+        let log_record = self.log_record(private_key, record)?;
+
+        let mut options = OpenOptions::new();
+        options.append(true).create(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(store_path(user_id, self.name))?;
+
+        let raw_record = if file.metadata()?.len() == 0 {
+            [LOG_MAGIC.as_slice(), &log_record].concat()
+        } else {
+            log_record
+        };
+        file.write_all(&raw_record)?;
+        file.sync_data()
+    }
+
+    // replaces the whole log with a single record
+    pub fn rewrite<T: Serialize + ?Sized>(&self, user_id: u64, private_key: &StaticSecret, record: &T) -> Result<(), std::io::Error> {
+        fs::create_dir_all(app_folder_path())?;
+        let raw_log = [LOG_MAGIC.as_slice(), &self.log_record(private_key, record)?].concat();
+        write_private_file(&store_path(user_id, self.name), &raw_log)
+    }
+
+    fn log_record<T: Serialize + ?Sized>(&self, private_key: &StaticSecret, record: &T) -> Result<Vec<u8>, std::io::Error> {
+        let sealed_record = self.seal(private_key, record)?;
+        Ok([(sealed_record.len() as u32).to_le_bytes().as_slice(), &sealed_record].concat())
+    }
+
+    // the records oldest first; a record that doesn't open is skipped, and the whole file is
+    // copied aside first so the rewrite that drops it loses nothing
+    pub fn load_log<T: DeserializeOwned>(&self, user_id: u64, private_keys: &[StaticSecret]) -> Result<(Vec<T>, LogStatus), std::io::Error> {
+        let Some(raw_file) = self.read(user_id)? else {
+            return Ok((Vec::new(), LogStatus::default()));
+        };
+        let (records, mut status) = self.parse_log(private_keys, &raw_file)?;
+
+        if status.damaged_records > 0 {
+            let set_aside_path = store_path(user_id, &format!("{}.damaged-{}", self.name, chrono::Utc::now().timestamp()));
+            write_private_file(&set_aside_path, &raw_file)?;
+            status.set_aside_path = Some(set_aside_path);
+        }
+        Ok((records, status))
+    }
+
+    fn parse_log<T: DeserializeOwned>(&self, private_keys: &[StaticSecret], raw_file: &[u8]) -> Result<(Vec<T>, LogStatus), std::io::Error> {
+        // a crash right after creating the file leaves it empty
+        if raw_file.is_empty() {
+            return Ok((Vec::new(), LogStatus::default()));
+        }
+        let Some(mut raw_records) = raw_file.strip_prefix(LOG_MAGIC.as_slice()) else {
+            return Err(invalid_data(format!("{} has an unknown format", self.label)));
+        };
+
+        let mut records = Vec::new();
+        let mut status = LogStatus::default();
+        while !raw_records.is_empty() {
+            let record_length = raw_records.get(..4)
+                .map(|length| u32::from_le_bytes(length.try_into().unwrap()) as usize);
+            let Some(sealed_record) = record_length.and_then(|length| raw_records.get(4..4 + length)) else {
+                status.needs_rewrite = true;
+                break;
+            };
+            match self.open(private_keys, sealed_record) {
+                Ok(record) => records.push(record),
+                Err(_) => status.damaged_records += 1
+            }
+            raw_records = &raw_records[4 + sealed_record.len()..];
+        }
+
+        status.needs_rewrite |= status.damaged_records > 0 || records.len() > MAX_LOG_RECORDS;
+        Ok((records, status))
+    }
+}
+
+// what load_log reads back from a log the records were appended to, for the stores' tests
+#[cfg(test)]
+impl SealedStore {
+    pub fn reload_log<T: Serialize + DeserializeOwned>(&self, private_key: &StaticSecret, records: &[T]) -> Vec<T> {
+        let mut raw_log = LOG_MAGIC.to_vec();
+        for record in records {
+            raw_log.extend(self.log_record(private_key, record).unwrap());
+        }
+
+        self.parse_log(std::slice::from_ref(private_key), &raw_log).unwrap().0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_STORE: SealedStore = SealedStore {
+        name: "test.bin",
+        label: "Test store",
+        salt: b"nossochat-test",
+        info: b"test_key"
+    };
+
+    fn log(private_key: &StaticSecret, records: &[&str]) -> Vec<u8> {
+        let mut raw_log = LOG_MAGIC.to_vec();
+        for record in records {
+            raw_log.extend(TEST_STORE.log_record(private_key, record).unwrap());
+        }
+        raw_log
+    }
+
+    #[test]
+    fn log_records_come_back_in_order() {
+        let private_key = StaticSecret::random_from_rng(rand::thread_rng());
+
+        let raw_log = log(&private_key, &["first", "second"]);
+
+        let (records, status) = TEST_STORE.parse_log::<String>(&[private_key], &raw_log).unwrap();
+
+        assert_eq!(records, ["first", "second"]);
+        assert!(!status.needs_rewrite);
+    }
+
+    #[test]
+    fn records_sealed_before_a_rotation_open_with_the_old_key() {
+        let old_private_key = StaticSecret::random_from_rng(rand::thread_rng());
+        let new_private_key = StaticSecret::random_from_rng(rand::thread_rng());
+        let mut raw_log = log(&old_private_key, &["before"]);
+        raw_log.extend(TEST_STORE.log_record(&new_private_key, "after").unwrap());
+
+        let (records, _) = TEST_STORE
+            .parse_log::<String>(&[new_private_key.clone(), old_private_key], &raw_log)
+            .unwrap();
+        assert_eq!(records, ["before", "after"]);
+
+        let (records, status) = TEST_STORE.parse_log::<String>(&[new_private_key], &raw_log).unwrap();
+        assert_eq!(records, ["after"]);
+        assert_eq!(status.damaged_records, 1);
+    }
+
+    #[test]
+    fn record_cut_short_is_dropped_and_asks_for_a_rewrite() {
+        let private_key = StaticSecret::random_from_rng(rand::thread_rng());
+        let mut raw_log = log(&private_key, &["kept", "cut"]);
+        raw_log.truncate(raw_log.len() - 5);
+
+        let (records, status) = TEST_STORE.parse_log::<String>(&[private_key], &raw_log).unwrap();
+
+        assert_eq!(records, ["kept"]);
+        assert!(status.needs_rewrite);
+        assert_eq!(status.damaged_records, 0);
+    }
+
+    #[test]
+    fn damaged_record_is_skipped_and_the_rest_still_load() {
+        let private_key = StaticSecret::random_from_rng(rand::thread_rng());
+        let mut raw_log = log(&private_key, &["first"]);
+        let damaged_byte = raw_log.len() + 4 + SEALED_NONCE_LENGTH;
+        raw_log.extend(TEST_STORE.log_record(&private_key, "damaged").unwrap());
+        raw_log.extend(TEST_STORE.log_record(&private_key, "last").unwrap());
+        raw_log[damaged_byte] ^= 1;
+
+        let (records, status) = TEST_STORE.parse_log::<String>(&[private_key], &raw_log).unwrap();
+
+        assert_eq!(records, ["first", "last"]);
+        assert_eq!(status.damaged_records, 1);
+        assert!(status.needs_rewrite);
+    }
+
+    #[test]
+    fn file_that_isnt_a_log_fails_to_load() {
+        let private_key = StaticSecret::random_from_rng(rand::thread_rng());
+        let sealed = TEST_STORE.seal(&private_key, "not a log").unwrap();
+
+        assert!(TEST_STORE.parse_log::<String>(&[private_key], &sealed).is_err());
+    }
+}
@@ -0,0 +1,98 @@
+use super::encryption::DecryptError;
+use super::local_store::{SealedStore, LogStatus};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use x25519_dalek::StaticSecret;
+use std::collections::{BTreeMap, HashMap};
+
+const MESSAGE_STORE: SealedStore = SealedStore {
+    name: "messages.bin",
+    label: "Message store",
+    salt: b"nossochat-messages",
+    info: b"message_store_key"
+};
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StoredMessage {
+    pub id: u64,
+    pub sender_id: u64,
+    pub content: String,
+    #[serde(default)]
+    pub decrypt_error: Option<DecryptError>,
+    #[serde(default)]
+    pub sent_at: Option<DateTime<Utc>>,
+    // restored from an export and not seen on the server yet
+    #[serde(default)]
+    pub is_imported: bool
+}
+
+// messages already fetched and opened, by chat id and oldest first
+pub type MessageStore = HashMap<u64, Vec<StoredMessage>>;
+
+// each save appends only the messages that are new or changed
+pub fn append_messages(user_id: u64, private_key: &StaticSecret, messages: &MessageStore) -> Result<(), std::io::Error> {
+    MESSAGE_STORE.append(user_id, private_key, messages)
+}
+
+pub fn rewrite_message_store(user_id: u64, private_key: &StaticSecret, message_store: &MessageStore) -> Result<(), std::io::Error> {
+    MESSAGE_STORE.rewrite(user_id, private_key, message_store)
+}
+
+pub fn load_message_store(user_id: u64, private_keys: &[StaticSecret]) -> Result<(MessageStore, LogStatus), std::io::Error> {
+    let (records, status) = MESSAGE_STORE.load_log::<MessageStore>(user_id, private_keys)?;
+
+    Ok((merge_records(records), status))
+}
+
+// a later copy of a message replaces the earlier one
+fn merge_records(records: Vec<MessageStore>) -> MessageStore {
+    let mut chats: HashMap<u64, BTreeMap<u64, StoredMessage>> = HashMap::new();
+    for (chat_id, messages) in records.into_iter().flatten() {
+        let chat = chats.entry(chat_id).or_default();
+        for message in messages {
+            chat.insert(message.id, message);
+        }
+    }
+
+    chats.into_iter()
+        .map(|(chat_id, messages)| (chat_id, messages.into_values().collect()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(id: u64, content: &str) -> StoredMessage {
+        StoredMessage { id, sender_id: 1, content: content.to_owned(), decrypt_error: None, sent_at: None, is_imported: false }
+    }
+
+    fn contents(message_store: &MessageStore, chat_id: u64) -> Vec<(u64, &str)> {
+        message_store[&chat_id].iter().map(|m| (m.id, m.content.as_str())).collect()
+    }
+
+    #[test]
+    fn later_copy_of_a_message_replaces_the_earlier_one() {
+        let records = vec![
+            HashMap::from([(7, vec![stored(1, "first"), stored(2, "undecrypted")])]),
+            HashMap::from([(7, vec![stored(2, "decrypted")])])
+        ];
+
+        let message_store = merge_records(records);
+
+        assert_eq!(contents(&message_store, 7), [(1, "first"), (2, "decrypted")]);
+    }
+
+    #[test]
+    fn messages_of_each_chat_come_back_oldest_first() {
+        let records = vec![
+            HashMap::from([(7, vec![stored(5, "newer")]), (8, vec![stored(3, "other chat")])]),
+            HashMap::from([(7, vec![stored(4, "older, fetched later")])])
+        ];
+
+        let message_store = merge_records(records);
+
+        assert_eq!(contents(&message_store, 7), [(4, "older, fetched later"), (5, "newer")]);
+        assert_eq!(contents(&message_store, 8), [(3, "other chat")]);
+    }
+}
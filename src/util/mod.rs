@@ -1,2 +1,13 @@
 pub mod encryption;
 pub mod keyring_handler;
+pub mod settings;
+pub mod local_store;
+pub mod outbox;
+pub mod contact_keys;
+pub mod safety_number;
+pub mod verified_contacts;
+pub mod ratchet;
+pub mod message_store;
+pub mod search_index;
+pub mod chat_export;
+pub mod time_format;
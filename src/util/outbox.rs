@@ -0,0 +1,44 @@
+use crate::thread::websocket_thread::WsContentMessage;
+use super::local_store::SealedStore;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use x25519_dalek::StaticSecret;
+
+pub const MAX_DELIVERY_ATTEMPTS: u32 = 3;
+const OUTBOX_STORE: SealedStore = SealedStore {
+    name: "outbox.bin",
+    label: "Outbox",
+    salt: b"nossochat-outbox",
+    info: b"outbox_key"
+};
+
+#[derive(Serialize, Deserialize)]
+pub struct OutboxEntry {
+    pub message: WsContentMessage,
+    // the session drops the message key once it sealed the message, so the sender keeps the plain text
+    pub content: String,
+    pub attempts: u32,
+    // entries saved before this was recorded get the time they're loaded
+    #[serde(default = "Utc::now")]
+    pub queued_at: DateTime<Utc>,
+    // written to the socket but not seen on the server yet, the entry stays until a
+    // fetched page has a message with its nonce
+    #[serde(default)]
+    pub written_at: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    pub in_flight: bool
+}
+
+impl OutboxEntry {
+    pub fn new(message: WsContentMessage, content: String, queued_at: DateTime<Utc>) -> Self {
+        Self { message, content, attempts: 0, queued_at, written_at: None, in_flight: false }
+    }
+}
+
+pub fn save_outbox(user_id: u64, private_key: &StaticSecret, entries: &[OutboxEntry]) -> Result<(), std::io::Error> {
+    OUTBOX_STORE.save(user_id, private_key, entries)
+}
+
+pub fn load_outbox(user_id: u64, private_keys: &[StaticSecret]) -> Result<Vec<OutboxEntry>, std::io::Error> {
+    OUTBOX_STORE.load(user_id, private_keys)
+}
@@ -0,0 +1,663 @@
+use super::encryption::{pad_plain_text, unpad_plain_text, DecryptError};
+use super::local_store::SealedStore;
+use base64::prelude::*;
+use chacha20poly1305::{
+    XChaCha20Poly1305,
+    XNonce,
+    KeyInit,
+    aead::{Aead, AeadCore, Payload}
+};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use x25519_dalek::{StaticSecret, PublicKey};
+use std::collections::{HashMap, HashSet};
+
+// ratchet messages are told apart from the ones sealed with the static keys by this prefix
+const ENVELOPE_MAGIC: &[u8; 3] = b"NCR";
+// envelopes of any other version are refused
+const ENVELOPE_VERSION: u8 = b'3';
+// magic | version | ratchet public key | previous chain length | index
+const HEADER_LENGTH: usize = 3 + 1 + 32 + 4 + 4;
+// keys of messages that haven't arrived yet, the oldest are dropped past this
+const MAX_SKIPPED_KEYS: u32 = 1000;
+// ids of opened messages kept for replay checks, past this the oldest quarter is forgotten
+const MAX_HISTORY_MESSAGE_IDS: usize = 10_000;
+const SESSIONS_STORE: SealedStore = SealedStore {
+    name: "sessions.bin",
+    label: "Sessions file",
+    salt: b"nossochat-sessions",
+    info: b"sessions_key"
+};
+
+struct Header {
+    ratchet_key: [u8; 32],
+    previous_count: u32,
+    index: u32
+}
+
+impl Header {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_LENGTH);
+        header.extend_from_slice(ENVELOPE_MAGIC);
+        header.push(ENVELOPE_VERSION);
+        header.extend_from_slice(&self.ratchet_key);
+        header.extend_from_slice(&self.previous_count.to_le_bytes());
+        header.extend_from_slice(&self.index.to_le_bytes());
+        header
+    }
+
+    fn parse(content: &[u8]) -> Option<Self> {
+        if content.len() < HEADER_LENGTH || !content.starts_with(ENVELOPE_MAGIC) {
+            return None;
+        }
+        if content[3] != ENVELOPE_VERSION {
+            return None;
+        }
+
+        Some(Self {
+            ratchet_key: content[4..36].try_into().unwrap(),
+            previous_count: u32::from_le_bytes(content[36..40].try_into().unwrap()),
+            index: u32::from_le_bytes(content[40..44].try_into().unwrap())
+        })
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct SkippedKey {
+    ratchet_key: [u8; 32],
+    index: u32,
+    message_key: [u8; 32]
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct RatchetState {
+    root_key: [u8; 32],
+    sending_secret: [u8; 32],
+    receiving_key: [u8; 32],
+    sending_chain: [u8; 32],
+    receiving_chain: [u8; 32],
+    sent_count: u32,
+    received_count: u32,
+    previous_sent_count: u32,
+    // set once the contact's ratchet key changed; the next message sent answers with a new key
+    needs_step: bool,
+    // contact ratchet keys taken since the bootstrap; up to the first one, a new bootstrap
+    // from the long-term keys opens the messages again
+    #[serde(default)]
+    receive_steps: u32,
+    skipped_keys: Vec<SkippedKey>
+}
+
+impl RatchetState {
+    // without a handshake both sides start from chains derived from the long-term keys;
+    // the lower user id takes the first DH step, the other one keeps its long-term key
+    // as ratchet key until it hears back
+    fn bootstrap(own_private_key: &StaticSecret, user_id: u64, contact_public_key: &PublicKey, contact_id: u64) -> Self {
+        let shared_secret = shared_secret(own_private_key, contact_public_key);
+
+        Self {
+            root_key: shared_secret,
+            sending_secret: own_private_key.to_bytes(),
+            receiving_key: contact_public_key.to_bytes(),
+            sending_chain: bootstrap_chain(&shared_secret, user_id, contact_id),
+            receiving_chain: bootstrap_chain(&shared_secret, contact_id, user_id),
+            sent_count: 0,
+            received_count: 0,
+            previous_sent_count: 0,
+            needs_step: user_id < contact_id,
+            receive_steps: 0,
+            skipped_keys: Vec::new()
+        }
+    }
+
+    fn next_sending_key(&mut self) -> (Header, [u8; 32]) {
+        if self.needs_step {
+            let sending_secret = StaticSecret::random_from_rng(rand::thread_rng());
+            let shared_key = sending_secret.diffie_hellman(&PublicKey::from(self.receiving_key));
+            (self.root_key, self.sending_chain) = kdf_root(&self.root_key, shared_key.as_bytes());
+
+            self.sending_secret = sending_secret.to_bytes();
+            self.previous_sent_count = self.sent_count;
+            self.sent_count = 0;
+            self.needs_step = false;
+        }
+
+        let (sending_chain, message_key) = kdf_chain(&self.sending_chain);
+        self.sending_chain = sending_chain;
+
+        let header = Header {
+            ratchet_key: PublicKey::from(&StaticSecret::from(self.sending_secret)).to_bytes(),
+            previous_count: self.previous_sent_count,
+            index: self.sent_count
+        };
+        self.sent_count += 1;
+
+        (header, message_key)
+    }
+
+    // works on a copy so a message that fails to open leaves the state untouched;
+    // the message key isn't kept once it opened the message
+    fn receive(
+        &self,
+        header: &Header,
+        associated_data: &[u8],
+        cipher_text: &[u8],
+        nonce: &[u8; 24]
+    ) -> Result<(Self, String), DecryptError> {
+        let mut state = self.clone();
+
+        let skipped_key = state.skipped_keys.iter()
+            .position(|k| k.ratchet_key == header.ratchet_key && k.index == header.index);
+        if let Some(position) = skipped_key {
+            let message_key = state.skipped_keys.remove(position).message_key;
+            let plain_text = open(&message_key, associated_data, cipher_text, nonce)?;
+            return Ok((state, plain_text));
+        }
+
+        if header.ratchet_key != state.receiving_key {
+            state.skip_until(header.previous_count).ok_or(DecryptError::NoMatchingKey)?;
+
+            let sending_secret = StaticSecret::from(state.sending_secret);
+            let shared_key = sending_secret.diffie_hellman(&PublicKey::from(header.ratchet_key));
+            (state.root_key, state.receiving_chain) = kdf_root(&state.root_key, shared_key.as_bytes());
+
+            state.receiving_key = header.ratchet_key;
+            state.received_count = 0;
+            state.needs_step = true;
+            state.receive_steps = state.receive_steps.saturating_add(1);
+        }
+
+        state.skip_until(header.index).ok_or(DecryptError::NoMatchingKey)?;
+        let (receiving_chain, message_key) = kdf_chain(&state.receiving_chain);
+        state.receiving_chain = receiving_chain;
+        state.received_count += 1;
+
+        let plain_text = open(&message_key, associated_data, cipher_text, nonce)?;
+        Ok((state, plain_text))
+    }
+
+    // keys of messages that haven't arrived yet are kept so they can still be opened out of order
+    fn skip_until(&mut self, index: u32) -> Option<()> {
+        if index.saturating_sub(self.received_count) > MAX_SKIPPED_KEYS {
+            return None;
+        }
+
+        while self.received_count < index {
+            let (receiving_chain, message_key) = kdf_chain(&self.receiving_chain);
+            self.skipped_keys.push(SkippedKey {
+                ratchet_key: self.receiving_key,
+                index: self.received_count,
+                message_key
+            });
+            self.receiving_chain = receiving_chain;
+            self.received_count += 1;
+        }
+
+        let excess = self.skipped_keys.len().saturating_sub(MAX_SKIPPED_KEYS as usize);
+        self.skipped_keys.drain(..excess);
+        Some(())
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RatchetSession {
+    user_id: u64,
+    contact_id: u64,
+    own_public_key: [u8; 32],
+    contact_public_key: [u8; 32],
+    state: RatchetState,
+    // server id each opened message was first seen with, a copy under another id is a replay;
+    // only the newest ones are kept
+    #[serde(default)]
+    history_message_ids: HashMap<String, u64>,
+    // lowest server id of a ratchet message from the contact; once they sent one, messages
+    // sealed with the static keys can only be older than it
+    #[serde(default)]
+    first_ratchet_message_id: Option<u64>,
+    // only a message newer than every one opened so far may start the session over
+    #[serde(default)]
+    newest_message_id: Option<u64>,
+    // nonces of messages a new bootstrap would open again, they never start the session over;
+    // only the first few messages of each session land here
+    #[serde(default)]
+    bootstrap_nonces: HashSet<String>
+}
+
+impl RatchetSession {
+    pub fn new(own_private_key: &StaticSecret, user_id: u64, contact_public_key: &PublicKey, contact_id: u64) -> Self {
+        Self {
+            user_id,
+            contact_id,
+            own_public_key: PublicKey::from(own_private_key).to_bytes(),
+            contact_public_key: contact_public_key.to_bytes(),
+            state: RatchetState::bootstrap(own_private_key, user_id, contact_public_key, contact_id),
+            history_message_ids: HashMap::new(),
+            first_ratchet_message_id: None,
+            newest_message_id: None,
+            bootstrap_nonces: HashSet::new()
+        }
+    }
+
+    pub fn is_for(&self, own_private_key: &StaticSecret, contact_public_key: &PublicKey) -> bool {
+        self.own_public_key == PublicKey::from(own_private_key).to_bytes() &&
+            self.contact_public_key == contact_public_key.to_bytes()
+    }
+
+    // a new long-term key on either side starts the chains over, what was seen of the history is kept
+    pub fn restart(&mut self, own_private_key: &StaticSecret, contact_public_key: &PublicKey) {
+        let history_message_ids = std::mem::take(&mut self.history_message_ids);
+        let first_ratchet_message_id = self.first_ratchet_message_id;
+        let newest_message_id = self.newest_message_id;
+        let bootstrap_nonces = std::mem::take(&mut self.bootstrap_nonces);
+        *self = Self::new(own_private_key, self.user_id, contact_public_key, self.contact_id);
+        self.history_message_ids = history_message_ids;
+        self.first_ratchet_message_id = first_ratchet_message_id;
+        self.newest_message_id = newest_message_id;
+        self.bootstrap_nonces = bootstrap_nonces;
+    }
+
+    // the sender can't open its own message afterwards, the plain text is kept by the caller
+    pub fn encrypt(&mut self, plain_text: &str, chat_id: u64) -> (Vec<u8>, [u8; 24]) {
+        let (header, message_key) = self.state.next_sending_key();
+        let header_bytes = header.to_bytes();
+        let associated_data = associated_data(&header_bytes, chat_id, self.user_id, self.contact_id);
+
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut rand::thread_rng());
+        let cipher_text = XChaCha20Poly1305::new_from_slice(&message_key).unwrap()
+            .encrypt(&nonce, Payload { msg: &pad_plain_text(plain_text.as_bytes()), aad: &associated_data })
+            .unwrap();
+
+        ([header_bytes, cipher_text].concat(), *nonce.as_ref())
+    }
+
+    // anything that isn't a ratchet message, doesn't open with this session or was sealed
+    // for another chat or sender fails with NoMatchingKey
+    pub fn decrypt(
+        &mut self,
+        own_private_key: &StaticSecret,
+        content: &[u8],
+        nonce: &[u8; 24],
+        chat_id: u64,
+        sender_id: u64,
+        message_id: Option<u64>
+    ) -> Result<String, DecryptError> {
+        let header = Header::parse(content).ok_or(DecryptError::NoMatchingKey)?;
+        let (header_bytes, cipher_text) = content.split_at(HEADER_LENGTH);
+
+        let receiver_id = match sender_id {
+            id if id == self.contact_id => self.user_id,
+            id if id == self.user_id => self.contact_id,
+            _ => return Err(DecryptError::NoMatchingKey)
+        };
+        let associated_data = associated_data(header_bytes, chat_id, sender_id, receiver_id);
+
+        // a message opened before lives in the message store, its key is gone
+        let history_id = BASE64_STANDARD.encode(nonce);
+        match self.history_message_ids.get(&history_id) {
+            Some(known_id) if message_id.is_some_and(|id| id != *known_id) => return Err(DecryptError::Replayed),
+            Some(_) => return Err(DecryptError::KeyDeleted),
+            None => {}
+        }
+
+        let (state, plain_text) = self.state.receive(&header, &associated_data, cipher_text, nonce)
+            .or_else(|e| self.receive_restarted(own_private_key, &header, &associated_data, cipher_text, nonce, message_id).ok_or(e))?;
+        self.state = state;
+        if self.state.receive_steps <= 1 {
+            self.bootstrap_nonces.insert(history_id.clone());
+        }
+        if let Some(message_id) = message_id {
+            self.record_message_id(history_id, message_id);
+            if sender_id == self.contact_id {
+                self.first_ratchet_message_id = Some(self.first_ratchet_message_id.map_or(message_id, |id| id.min(message_id)));
+            }
+        }
+        Ok(plain_text)
+    }
+
+    fn record_message_id(&mut self, history_id: String, message_id: u64) {
+        self.history_message_ids.insert(history_id, message_id);
+        self.newest_message_id = self.newest_message_id.max(Some(message_id));
+
+        if self.history_message_ids.len() > MAX_HISTORY_MESSAGE_IDS {
+            let mut message_ids: Vec<u64> = self.history_message_ids.values().copied().collect();
+            message_ids.sort_unstable();
+            let oldest_kept_id = message_ids[MAX_HISTORY_MESSAGE_IDS / 4];
+            self.history_message_ids.retain(|_, id| *id >= oldest_kept_id);
+        }
+    }
+
+    // a contact who lost their session bootstraps a new one from the long-term keys alone;
+    // a new message that only opens from there means this side starts over as well
+    fn receive_restarted(
+        &self,
+        own_private_key: &StaticSecret,
+        header: &Header,
+        associated_data: &[u8],
+        cipher_text: &[u8],
+        nonce: &[u8; 24],
+        message_id: Option<u64>
+    ) -> Option<(RatchetState, String)> {
+        let message_id = message_id?;
+        if self.newest_message_id.is_some_and(|newest_id| message_id <= newest_id) ||
+            self.bootstrap_nonces.contains(&BASE64_STANDARD.encode(nonce)) {
+            return None;
+        }
+
+        let contact_public_key = PublicKey::from(self.contact_public_key);
+        RatchetState::bootstrap(own_private_key, self.user_id, &contact_public_key, self.contact_id)
+            .receive(header, associated_data, cipher_text, nonce)
+            .ok()
+    }
+
+    // messages sealed with the static keys carry no chat or sender binding, so they're only
+    // taken from before the contact moved to the ratchet and under the id first seen with them
+    pub fn check_static_message(&self, nonce: &[u8; 24], message_id: Option<u64>) -> Result<(), DecryptError> {
+        let Some(message_id) = message_id else {
+            return Ok(());
+        };
+        if self.first_ratchet_message_id.is_some_and(|first_id| message_id > first_id) {
+            return Err(DecryptError::NoMatchingKey);
+        }
+
+        match self.history_message_ids.get(&BASE64_STANDARD.encode(nonce)) {
+            Some(known_id) if *known_id != message_id => Err(DecryptError::Replayed),
+            _ => Ok(())
+        }
+    }
+
+    pub fn record_static_message(&mut self, nonce: &[u8; 24], message_id: Option<u64>) {
+        if let Some(message_id) = message_id {
+            self.record_message_id(BASE64_STANDARD.encode(nonce), message_id);
+        }
+    }
+}
+
+// the header already carries the message counter (chain index and previous chain length)
+fn associated_data(header_bytes: &[u8], chat_id: u64, sender_id: u64, receiver_id: u64) -> Vec<u8> {
+    [
+        header_bytes,
+        &chat_id.to_le_bytes(),
+        &sender_id.to_le_bytes(),
+        &receiver_id.to_le_bytes()
+    ].concat()
+}
+
+fn open(
+    message_key: &[u8; 32],
+    associated_data: &[u8],
+    cipher_text: &[u8],
+    nonce: &[u8; 24]
+) -> Result<String, DecryptError> {
+    let padded = XChaCha20Poly1305::new_from_slice(message_key).unwrap()
+        .decrypt(XNonce::from_slice(nonce), Payload { msg: cipher_text, aad: associated_data })
+        .map_err(|_| DecryptError::NoMatchingKey)?;
+
+    let plain_text = unpad_plain_text(&padded).ok_or(DecryptError::InvalidPadding)?;
+    String::from_utf8(plain_text.to_vec()).map_err(|_| DecryptError::InvalidUtf8)
+}
+
+fn shared_secret(own_private_key: &StaticSecret, contact_public_key: &PublicKey) -> [u8; 32] {
+    let shared_key = own_private_key.diffie_hellman(contact_public_key);
+    let hk = Hkdf::<Sha256>::new(Some(b"nossochat-ratchet"), shared_key.as_bytes());
+    let mut shared_secret = [0u8; 32];
+    hk.expand(b"shared_secret", &mut shared_secret).unwrap();
+
+    shared_secret
+}
+
+fn bootstrap_chain(shared_secret: &[u8; 32], sender_id: u64, receiver_id: u64) -> [u8; 32] {
+    let hk = Hkdf::<Sha256>::from_prk(shared_secret).unwrap();
+    let info = [b"bootstrap_chain".as_ref(), &sender_id.to_le_bytes(), &receiver_id.to_le_bytes()].concat();
+    let mut chain_key = [0u8; 32];
+    hk.expand(&info, &mut chain_key).unwrap();
+
+    chain_key
+}
+
+fn kdf_root(root_key: &[u8; 32], shared_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let hk = Hkdf::<Sha256>::new(Some(root_key), shared_key);
+    let mut output = [0u8; 64];
+    hk.expand(b"root_key", &mut output).unwrap();
+
+    (output[..32].try_into().unwrap(), output[32..].try_into().unwrap())
+}
+
+// returns the next chain key and the message key
+fn kdf_chain(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let hk = Hkdf::<Sha256>::from_prk(chain_key).unwrap();
+    let mut next_chain_key = [0u8; 32];
+    let mut message_key = [0u8; 32];
+    hk.expand(b"chain_key", &mut next_chain_key).unwrap();
+    hk.expand(b"message_key", &mut message_key).unwrap();
+
+    (next_chain_key, message_key)
+}
+
+pub fn save_sessions(
+    user_id: u64,
+    private_key: &StaticSecret,
+    sessions: &HashMap<u64, RatchetSession>
+) -> Result<(), std::io::Error> {
+    SESSIONS_STORE.save(user_id, private_key, sessions)
+}
+
+pub fn load_sessions(user_id: u64, private_keys: &[StaticSecret]) -> Result<HashMap<u64, RatchetSession>, std::io::Error> {
+    SESSIONS_STORE.load(user_id, private_keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHAT_ID: u64 = 7;
+    const ALICE_ID: u64 = 1;
+    const BOB_ID: u64 = 2;
+
+    struct Peer {
+        private_key: StaticSecret,
+        session: RatchetSession
+    }
+
+    impl Peer {
+        fn encrypt(&mut self, plain_text: &str, chat_id: u64) -> (Vec<u8>, [u8; 24]) {
+            self.session.encrypt(plain_text, chat_id)
+        }
+
+        fn decrypt(
+            &mut self,
+            content: &[u8],
+            nonce: &[u8; 24],
+            chat_id: u64,
+            sender_id: u64,
+            message_id: Option<u64>
+        ) -> Result<String, DecryptError> {
+            self.session.decrypt(&self.private_key, content, nonce, chat_id, sender_id, message_id)
+        }
+
+        // what a reinstall or a sessions file that doesn't load leaves behind
+        fn lose_session(&mut self, contact: &Peer) {
+            self.session = RatchetSession::new(
+                &self.private_key,
+                self.session.user_id,
+                &PublicKey::from(&contact.private_key),
+                self.session.contact_id
+            );
+        }
+    }
+
+    fn sessions() -> (Peer, Peer) {
+        let alice_private_key = StaticSecret::random_from_rng(rand::thread_rng());
+        let bob_private_key = StaticSecret::random_from_rng(rand::thread_rng());
+
+        let alice_session = RatchetSession::new(&alice_private_key, ALICE_ID, &PublicKey::from(&bob_private_key), BOB_ID);
+        let bob_session = RatchetSession::new(&bob_private_key, BOB_ID, &PublicKey::from(&alice_private_key), ALICE_ID);
+        (
+            Peer { private_key: alice_private_key, session: alice_session },
+            Peer { private_key: bob_private_key, session: bob_session }
+        )
+    }
+
+    // a few messages both ways so both sides moved past the bootstrapped chains
+    fn talk(alice: &mut Peer, bob: &mut Peer, first_id: u64) -> u64 {
+        let mut message_id = first_id;
+        for _ in 0..2 {
+            let (content, nonce) = alice.encrypt("ping", CHAT_ID);
+            bob.decrypt(&content, &nonce, CHAT_ID, ALICE_ID, Some(message_id)).unwrap();
+            let (content, nonce) = bob.encrypt("pong", CHAT_ID);
+            alice.decrypt(&content, &nonce, CHAT_ID, BOB_ID, Some(message_id + 1)).unwrap();
+            message_id += 2;
+        }
+        message_id
+    }
+
+    #[test]
+    fn messages_open_on_the_other_side_in_both_directions() {
+        let (mut alice, mut bob) = sessions();
+
+        for round in 0..3 {
+            let (content, nonce) = alice.encrypt(&format!("ping {}", round), CHAT_ID);
+            assert_eq!(bob.decrypt(&content, &nonce, CHAT_ID, ALICE_ID, Some(round * 2)).unwrap(), format!("ping {}", round));
+
+            let (content, nonce) = bob.encrypt(&format!("pong {}", round), CHAT_ID);
+            assert_eq!(alice.decrypt(&content, &nonce, CHAT_ID, BOB_ID, Some(round * 2 + 1)).unwrap(), format!("pong {}", round));
+        }
+    }
+
+    #[test]
+    fn messages_arriving_out_of_order_still_open() {
+        let (mut alice, mut bob) = sessions();
+        let sent: Vec<_> = (0..3).map(|i| alice.encrypt(&format!("message {}", i), CHAT_ID)).collect();
+
+        for i in [2, 0, 1] {
+            let (content, nonce) = &sent[i];
+            assert_eq!(bob.decrypt(content, nonce, CHAT_ID, ALICE_ID, Some(i as u64)).unwrap(), format!("message {}", i));
+        }
+        assert!(bob.session.state.skipped_keys.is_empty());
+    }
+
+    #[test]
+    fn message_skipped_before_a_new_ratchet_key_still_opens() {
+        let (mut alice, mut bob) = sessions();
+        let (content, nonce) = alice.encrypt("hello", CHAT_ID);
+        bob.decrypt(&content, &nonce, CHAT_ID, ALICE_ID, Some(1)).unwrap();
+        let (content, nonce) = bob.encrypt("hi", CHAT_ID);
+        alice.decrypt(&content, &nonce, CHAT_ID, BOB_ID, Some(2)).unwrap();
+
+        let (late_content, late_nonce) = alice.encrypt("late", CHAT_ID);
+        let (content, nonce) = bob.encrypt("again", CHAT_ID);
+        alice.decrypt(&content, &nonce, CHAT_ID, BOB_ID, Some(3)).unwrap();
+        let (content, nonce) = alice.encrypt("next", CHAT_ID);
+
+        assert_eq!(bob.decrypt(&content, &nonce, CHAT_ID, ALICE_ID, Some(5)).unwrap(), "next");
+        assert_eq!(bob.session.state.skipped_keys.len(), 1);
+        assert_eq!(bob.decrypt(&late_content, &late_nonce, CHAT_ID, ALICE_ID, Some(4)).unwrap(), "late");
+        assert!(bob.session.state.skipped_keys.is_empty());
+    }
+
+    #[test]
+    fn gap_past_the_skipped_key_limit_is_refused() {
+        let (mut alice, mut bob) = sessions();
+        for _ in 0..=MAX_SKIPPED_KEYS {
+            alice.encrypt("lost", CHAT_ID);
+        }
+        let (content, nonce) = alice.encrypt("too late", CHAT_ID);
+
+        assert_eq!(bob.decrypt(&content, &nonce, CHAT_ID, ALICE_ID, Some(1)), Err(DecryptError::NoMatchingKey));
+    }
+
+    #[test]
+    fn message_sealed_for_another_chat_or_sender_is_refused() {
+        let (mut alice, mut bob) = sessions();
+        let (content, nonce) = alice.encrypt("hello", CHAT_ID);
+
+        assert_eq!(bob.decrypt(&content, &nonce, CHAT_ID + 1, ALICE_ID, Some(1)), Err(DecryptError::NoMatchingKey));
+        assert_eq!(bob.decrypt(&content, &nonce, CHAT_ID, BOB_ID, Some(1)), Err(DecryptError::NoMatchingKey));
+        assert_eq!(bob.decrypt(&content, &nonce, CHAT_ID, ALICE_ID, Some(1)).unwrap(), "hello");
+    }
+
+    #[test]
+    fn tampered_header_is_refused() {
+        let (mut alice, mut bob) = sessions();
+        let (mut content, nonce) = alice.encrypt("hello", CHAT_ID);
+        content[HEADER_LENGTH - 1] ^= 1;
+
+        assert_eq!(bob.decrypt(&content, &nonce, CHAT_ID, ALICE_ID, Some(1)), Err(DecryptError::NoMatchingKey));
+    }
+
+    #[test]
+    fn envelope_of_another_version_is_refused() {
+        let (mut alice, mut bob) = sessions();
+        let (content, nonce) = alice.encrypt("hello", CHAT_ID);
+
+        for version in [b'1', b'2'] {
+            let mut content = content.clone();
+            content[ENVELOPE_MAGIC.len()] = version;
+            assert_eq!(bob.decrypt(&content, &nonce, CHAT_ID, ALICE_ID, Some(1)), Err(DecryptError::NoMatchingKey));
+        }
+        assert_eq!(bob.decrypt(&content, &nonce, CHAT_ID, ALICE_ID, Some(1)).unwrap(), "hello");
+    }
+
+    #[test]
+    fn message_delivered_again_is_refused() {
+        let (mut alice, mut bob) = sessions();
+        let (content, nonce) = alice.encrypt("hello", CHAT_ID);
+        bob.decrypt(&content, &nonce, CHAT_ID, ALICE_ID, Some(1)).unwrap();
+
+        assert_eq!(bob.decrypt(&content, &nonce, CHAT_ID, ALICE_ID, Some(2)), Err(DecryptError::Replayed));
+        assert_eq!(bob.decrypt(&content, &nonce, CHAT_ID, ALICE_ID, Some(1)), Err(DecryptError::KeyDeleted));
+    }
+
+    #[test]
+    fn sides_recover_after_one_loses_its_session() {
+        for lost_side in [ALICE_ID, BOB_ID] {
+            let (mut alice, mut bob) = sessions();
+            let message_id = talk(&mut alice, &mut bob, 1);
+            let (mut lost, mut kept, lost_id, kept_id) = if lost_side == ALICE_ID {
+                (alice, bob, ALICE_ID, BOB_ID)
+            } else {
+                (bob, alice, BOB_ID, ALICE_ID)
+            };
+            lost.lose_session(&kept);
+
+            let (content, nonce) = kept.encrypt("lost", CHAT_ID);
+            assert_eq!(lost.decrypt(&content, &nonce, CHAT_ID, kept_id, Some(message_id)), Err(DecryptError::NoMatchingKey));
+
+            let (content, nonce) = lost.encrypt("starting over", CHAT_ID);
+            assert_eq!(kept.decrypt(&content, &nonce, CHAT_ID, lost_id, Some(message_id + 1)).unwrap(), "starting over");
+            let (content, nonce) = kept.encrypt("welcome back", CHAT_ID);
+            assert_eq!(lost.decrypt(&content, &nonce, CHAT_ID, kept_id, Some(message_id + 2)).unwrap(), "welcome back");
+
+            let (mut alice, mut bob) = if lost_side == ALICE_ID { (lost, kept) } else { (kept, lost) };
+            talk(&mut alice, &mut bob, message_id + 3);
+        }
+    }
+
+    #[test]
+    fn old_message_doesnt_start_the_session_over() {
+        let (mut alice, mut bob) = sessions();
+        let (first_content, first_nonce) = alice.encrypt("first", CHAT_ID);
+        bob.decrypt(&first_content, &first_nonce, CHAT_ID, ALICE_ID, Some(1)).unwrap();
+        let message_id = talk(&mut alice, &mut bob, 2);
+        bob.session.history_message_ids.clear();
+
+        assert_eq!(
+            bob.decrypt(&first_content, &first_nonce, CHAT_ID, ALICE_ID, Some(message_id)),
+            Err(DecryptError::NoMatchingKey)
+        );
+        talk(&mut alice, &mut bob, message_id + 1);
+    }
+
+    #[test]
+    fn only_the_newest_message_ids_are_kept() {
+        let (_, mut bob) = sessions();
+        for message_id in 0..=MAX_HISTORY_MESSAGE_IDS as u64 {
+            bob.session.record_message_id(message_id.to_string(), message_id);
+        }
+
+        let history_message_ids = &bob.session.history_message_ids;
+        assert_eq!(history_message_ids.len(), MAX_HISTORY_MESSAGE_IDS + 1 - MAX_HISTORY_MESSAGE_IDS / 4);
+        assert!(!history_message_ids.contains_key("0"));
+        assert!(history_message_ids.contains_key(&MAX_HISTORY_MESSAGE_IDS.to_string()));
+    }
+}
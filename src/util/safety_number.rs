@@ -0,0 +1,73 @@
+use sha2::{Digest, Sha512};
+use x25519_dalek::PublicKey;
+
+const SAFETY_NUMBER_GROUPS: usize = 12;
+const GROUP_BYTES: usize = 5;
+const GRID_SIZE: usize = 16;
+
+// the keys are sorted so both sides of a conversation get the same fingerprint
+fn fingerprint(public_key: &PublicKey, other_public_key: &PublicKey) -> [u8; 64] {
+    let mut public_keys = [public_key.as_bytes(), other_public_key.as_bytes()];
+    public_keys.sort();
+
+    let mut hasher = Sha512::new();
+    hasher.update(b"nossochat-safety-number");
+    for public_key in public_keys {
+        hasher.update(public_key);
+    }
+
+    hasher.finalize().into()
+}
+
+// twelve groups of five digits, each one taken from five bytes of the fingerprint
+pub fn safety_number(public_key: &PublicKey, other_public_key: &PublicKey) -> Vec<String> {
+    fingerprint(public_key, other_public_key)
+        .chunks_exact(GROUP_BYTES)
+        .take(SAFETY_NUMBER_GROUPS)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |value, byte| (value << 8) | *byte as u64);
+            format!("{:05}", value % 100_000)
+        })
+        .collect()
+}
+
+// one cell per bit of the fingerprint's first half, meant to be compared side by side
+pub fn safety_grid(public_key: &PublicKey, other_public_key: &PublicKey) -> String {
+    fingerprint(public_key, other_public_key)[..GRID_SIZE * GRID_SIZE / 8]
+        .chunks_exact(GRID_SIZE / 8)
+        .map(|row| {
+            row.iter()
+                .flat_map(|byte| (0..8).rev().map(move |bit| if (byte >> bit) & 1 == 1 { "██" } else { "  " }))
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x25519_dalek::StaticSecret;
+
+    #[test]
+    fn both_sides_see_the_same_safety_number() {
+        let public_key = PublicKey::from(&StaticSecret::random_from_rng(rand::thread_rng()));
+        let other_public_key = PublicKey::from(&StaticSecret::random_from_rng(rand::thread_rng()));
+
+        assert_eq!(safety_number(&public_key, &other_public_key), safety_number(&other_public_key, &public_key));
+        assert_eq!(safety_grid(&public_key, &other_public_key), safety_grid(&other_public_key, &public_key));
+    }
+
+    #[test]
+    fn another_key_changes_the_safety_number() {
+        let public_key = PublicKey::from(&StaticSecret::random_from_rng(rand::thread_rng()));
+        let other_public_key = PublicKey::from(&StaticSecret::random_from_rng(rand::thread_rng()));
+        let new_public_key = PublicKey::from(&StaticSecret::random_from_rng(rand::thread_rng()));
+
+        let number = safety_number(&public_key, &other_public_key);
+
+        assert_eq!(number.len(), SAFETY_NUMBER_GROUPS);
+        assert!(number.iter().all(|group| group.len() == 5));
+        assert_ne!(number, safety_number(&public_key, &new_public_key));
+    }
+}
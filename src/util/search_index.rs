@@ -0,0 +1,167 @@
+use super::local_store::{SealedStore, LogStatus};
+use serde::{Deserialize, Serialize};
+use x25519_dalek::StaticSecret;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+const SEARCH_INDEX_STORE: SealedStore = SealedStore {
+    name: "search.bin",
+    label: "Search index",
+    salt: b"nossochat-search",
+    info: b"search_index_key"
+};
+
+pub const MAX_SEARCH_RESULTS: usize = 50;
+
+// (chat id, message id)
+pub type SearchHit = (u64, u64);
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct SearchIndex {
+    terms: BTreeMap<String, BTreeSet<SearchHit>>,
+    indexed_message_ids: HashSet<u64>
+}
+
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(|term| term.to_lowercase())
+}
+
+impl SearchIndex {
+    pub fn add_message(&mut self, chat_id: u64, message_id: u64, content: &str) -> bool {
+        if !self.indexed_message_ids.insert(message_id) {
+            return false;
+        }
+        for term in tokenize(content) {
+            self.terms.entry(term).or_default().insert((chat_id, message_id));
+        }
+
+        true
+    }
+
+    pub fn merge(&mut self, other: SearchIndex) {
+        self.indexed_message_ids.extend(other.indexed_message_ids);
+        for (term, term_hits) in other.terms {
+            self.terms.entry(term).or_default().extend(term_hits);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indexed_message_ids.is_empty()
+    }
+
+    // every word of the query has to start some word of the message, newest messages first
+    pub fn search(&self, query: &str) -> Vec<SearchHit> {
+        let mut hits: Option<BTreeSet<SearchHit>> = None;
+
+        for query_term in tokenize(query) {
+            let term_hits: BTreeSet<SearchHit> = self.terms
+                .range(query_term.clone()..)
+                .take_while(|(term, _)| term.starts_with(&query_term))
+                .flat_map(|(_, term_hits)| term_hits.iter().copied())
+                .collect();
+
+            hits = Some(match hits {
+                Some(hits) => hits.intersection(&term_hits).copied().collect(),
+                None => term_hits
+            });
+        }
+
+        let mut hits: Vec<SearchHit> = hits.unwrap_or_default().into_iter().collect();
+        hits.sort_by_key(|(_, message_id)| std::cmp::Reverse(*message_id));
+        hits.truncate(MAX_SEARCH_RESULTS);
+        hits
+    }
+}
+
+// each save appends an index of only the messages added since the last one
+pub fn append_search_entries(user_id: u64, private_key: &StaticSecret, new_entries: &SearchIndex) -> Result<(), std::io::Error> {
+    SEARCH_INDEX_STORE.append(user_id, private_key, new_entries)
+}
+
+pub fn rewrite_search_index(user_id: u64, private_key: &StaticSecret, search_index: &SearchIndex) -> Result<(), std::io::Error> {
+    SEARCH_INDEX_STORE.rewrite(user_id, private_key, search_index)
+}
+
+pub fn load_search_index(user_id: u64, private_keys: &[StaticSecret]) -> Result<(SearchIndex, LogStatus), std::io::Error> {
+    let (records, status) = SEARCH_INDEX_STORE.load_log::<SearchIndex>(user_id, private_keys)?;
+
+    Ok((merge_records(records), status))
+}
+
+fn merge_records(records: Vec<SearchIndex>) -> SearchIndex {
+    let mut search_index = SearchIndex::default();
+    for record in records {
+        search_index.merge(record);
+    }
+    search_index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(messages: &[(u64, u64, &str)]) -> SearchIndex {
+        let mut search_index = SearchIndex::default();
+        for (chat_id, message_id, content) in messages {
+            search_index.add_message(*chat_id, *message_id, content);
+        }
+        search_index
+    }
+
+    #[test]
+    fn words_are_split_on_anything_but_letters_and_digits() {
+        let terms: Vec<String> = tokenize("Olá, MUNDO!  it's 2pm...").collect();
+
+        assert_eq!(terms, ["olá", "mundo", "it", "s", "2pm"]);
+    }
+
+    #[test]
+    fn every_query_word_has_to_start_a_word_of_the_message() {
+        let search_index = index(&[
+            (7, 1, "Meeting tomorrow at the office"),
+            (7, 2, "the meeting moved"),
+            (8, 3, "office party")
+        ]);
+
+        assert_eq!(search_index.search("meet"), [(7, 2), (7, 1)]);
+        assert_eq!(search_index.search("MEETING office"), [(7, 1)]);
+        assert_eq!(search_index.search("eting"), []);
+        assert_eq!(search_index.search("  "), []);
+    }
+
+    #[test]
+    fn message_is_indexed_once() {
+        let mut search_index = index(&[(7, 1, "hello")]);
+
+        assert!(!search_index.add_message(7, 1, "hello again"));
+        assert_eq!(search_index.search("again"), []);
+    }
+
+    #[test]
+    fn only_the_newest_hits_are_returned() {
+        let mut search_index = SearchIndex::default();
+        for message_id in 0..MAX_SEARCH_RESULTS as u64 + 10 {
+            search_index.add_message(7, message_id, "note");
+        }
+
+        let hits = search_index.search("note");
+
+        assert_eq!(hits.len(), MAX_SEARCH_RESULTS);
+        assert_eq!(hits[0], (7, MAX_SEARCH_RESULTS as u64 + 9));
+    }
+
+    #[test]
+    fn index_appended_in_pieces_answers_the_same_after_a_reload() {
+        let private_key = StaticSecret::random_from_rng(rand::thread_rng());
+        let messages = [(7, 1, "first message"), (8, 2, "second message"), (7, 3, "third one")];
+        let records = vec![index(&messages[..1]), index(&messages[1..]), index(&messages[2..])];
+
+        let mut search_index = merge_records(SEARCH_INDEX_STORE.reload_log(&private_key, &records));
+
+        assert_eq!(search_index.search("message"), index(&messages).search("message"));
+        assert_eq!(search_index.search("third"), [(7, 3)]);
+        assert_eq!(search_index.search("sec mess"), [(8, 2)]);
+        assert!(!search_index.add_message(7, 3, "third one"));
+    }
+}
@@ -0,0 +1,369 @@
+use crate::http::HttpClientOptions;
+use super::keyring_handler::KeyStoreKind;
+use http::Uri;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const SERVICE_NAME: &str = "nossochat-service";
+const SETTINGS_FILE_NAME: &str = "settings.json";
+
+const HTTP_URL_ENV: &str = "NOSSOCHAT_HTTP_URL";
+const WS_URL_ENV: &str = "NOSSOCHAT_WS_URL";
+const CA_CERT_ENV: &str = "NOSSOCHAT_CA_CERT";
+const HTTP_WORKERS_ENV: &str = "NOSSOCHAT_HTTP_WORKERS";
+const KEY_STORE_ENV: &str = "NOSSOCHAT_KEY_STORE";
+
+const DEFAULT_HTTP_URL: &str = "http://eduardodev.app.br/";
+const DEFAULT_WS_URL: &str = "ws://eduardodev.app.br/ws_server";
+const DEFAULT_HTTP_WORKERS: usize = 4;
+const MAX_HTTP_WORKERS: usize = 32;
+const DEFAULT_HTTP_CONNECT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_HTTP_READ_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_HTTP_MAX_RETRIES: u32 = 2;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Settings {
+    pub http_url: String,
+    pub ws_url: String,
+    #[serde(default)]
+    pub ca_cert_path: String,
+    #[serde(default = "default_http_workers")]
+    pub http_workers: usize,
+    #[serde(default = "default_http_connect_timeout_secs")]
+    pub http_connect_timeout_secs: u64,
+    #[serde(default = "default_http_read_timeout_secs")]
+    pub http_read_timeout_secs: u64,
+    #[serde(default = "default_http_max_retries")]
+    pub http_max_retries: u32,
+    #[serde(default)]
+    pub key_store: KeyStoreKind
+}
+
+// values taken from the environment, applied over the saved settings but never written back
+#[derive(Default, Clone)]
+pub struct SettingsOverrides {
+    pub http_url: Option<String>,
+    pub ws_url: Option<String>,
+    pub ca_cert_path: Option<String>,
+    pub http_workers: Option<usize>,
+    pub key_store: Option<KeyStoreKind>
+}
+
+#[derive(Clone)]
+pub struct ServerEndpoints {
+    pub http_url: Url,
+    pub ws_url: Uri,
+    pub ca_certificate: Option<Vec<u8>>,
+    // the websocket uses the same connect timeout as the http client
+    pub connect_timeout: Duration
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            http_url: DEFAULT_HTTP_URL.to_owned(),
+            ws_url: DEFAULT_WS_URL.to_owned(),
+            ca_cert_path: String::new(),
+            http_workers: DEFAULT_HTTP_WORKERS,
+            http_connect_timeout_secs: DEFAULT_HTTP_CONNECT_TIMEOUT_SECS,
+            http_read_timeout_secs: DEFAULT_HTTP_READ_TIMEOUT_SECS,
+            http_max_retries: DEFAULT_HTTP_MAX_RETRIES,
+            key_store: KeyStoreKind::default()
+        }
+    }
+}
+
+impl Default for ServerEndpoints {
+    fn default() -> Self {
+        Settings::default().validate().expect("Default settings should be valid")
+    }
+}
+
+fn default_http_workers() -> usize {
+    DEFAULT_HTTP_WORKERS
+}
+
+fn default_http_connect_timeout_secs() -> u64 {
+    DEFAULT_HTTP_CONNECT_TIMEOUT_SECS
+}
+
+fn default_http_read_timeout_secs() -> u64 {
+    DEFAULT_HTTP_READ_TIMEOUT_SECS
+}
+
+fn default_http_max_retries() -> u32 {
+    DEFAULT_HTTP_MAX_RETRIES
+}
+
+pub fn home_folder_path() -> PathBuf {
+    env::var("HOME")
+        .map(PathBuf::from)
+        .expect("Couldn't determine home directory")
+}
+
+pub fn app_folder_path() -> PathBuf {
+    home_folder_path().join(format!(".{}/", SERVICE_NAME))
+}
+
+impl SettingsOverrides {
+    pub fn from_env() -> (Self, Vec<std::io::Error>) {
+        Self::from_lookup(|name| env::var(name).ok())
+    }
+
+    // a variable that can't be parsed is left out and reported, the others still apply
+    pub fn from_lookup<F: Fn(&str) -> Option<String>>(lookup: F) -> (Self, Vec<std::io::Error>) {
+        let mut errors = Vec::new();
+
+        let http_workers = lookup(HTTP_WORKERS_ENV).and_then(|http_workers| {
+            http_workers.parse().map_err(|_| {
+                errors.push(invalid_input(format!("Ignoring invalid {}: {}", HTTP_WORKERS_ENV, http_workers)));
+            }).ok()
+        });
+        let key_store = lookup(KEY_STORE_ENV).and_then(|key_store| match key_store.as_str() {
+            "file" => Some(KeyStoreKind::File),
+            "secret_service" => Some(KeyStoreKind::SecretService),
+            _ => {
+                errors.push(invalid_input(format!("Ignoring invalid {}: {}", KEY_STORE_ENV, key_store)));
+                None
+            }
+        });
+
+        let overrides = Self {
+            http_url: lookup(HTTP_URL_ENV),
+            ws_url: lookup(WS_URL_ENV),
+            ca_cert_path: lookup(CA_CERT_ENV),
+            http_workers,
+            key_store
+        };
+
+        (overrides, errors)
+    }
+
+    pub fn variable_names(&self) -> Vec<&'static str> {
+        [
+            (self.http_url.is_some(), HTTP_URL_ENV),
+            (self.ws_url.is_some(), WS_URL_ENV),
+            (self.ca_cert_path.is_some(), CA_CERT_ENV),
+            (self.http_workers.is_some(), HTTP_WORKERS_ENV),
+            (self.key_store.is_some(), KEY_STORE_ENV)
+        ].into_iter()
+            .filter_map(|(is_set, name)| is_set.then_some(name))
+            .collect()
+    }
+}
+
+impl Settings {
+    // only what's saved on disk, the environment is applied with with_overrides
+    pub fn load() -> Result<Self, std::io::Error> {
+        let file_path = app_folder_path().join(SETTINGS_FILE_NAME);
+        if !file_path.exists() {
+            return Ok(Settings::default());
+        }
+
+        let raw_settings = fs::read_to_string(file_path)?;
+        serde_json::from_str(&raw_settings)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn with_overrides(&self, overrides: &SettingsOverrides) -> Settings {
+        let mut settings = self.clone();
+
+        if let Some(http_url) = &overrides.http_url {
+            settings.http_url = http_url.clone();
+        }
+        if let Some(ws_url) = &overrides.ws_url {
+            settings.ws_url = ws_url.clone();
+        }
+        if let Some(ca_cert_path) = &overrides.ca_cert_path {
+            settings.ca_cert_path = ca_cert_path.clone();
+        }
+        if let Some(http_workers) = overrides.http_workers {
+            settings.http_workers = http_workers;
+        }
+        if let Some(key_store) = overrides.key_store {
+            settings.key_store = key_store;
+        }
+
+        settings
+    }
+
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        let folder_path = app_folder_path();
+        fs::create_dir_all(&folder_path)?;
+
+        let raw_settings = serde_json::to_string_pretty(self)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        fs::write(folder_path.join(SETTINGS_FILE_NAME), raw_settings)
+    }
+
+    pub fn http_worker_count(&self) -> usize {
+        self.http_workers.clamp(1, MAX_HTTP_WORKERS)
+    }
+
+    // a zero timeout would fail every request, so it is bumped to one second
+    pub fn http_client_options(&self) -> HttpClientOptions {
+        HttpClientOptions {
+            connect_timeout: Duration::from_secs(self.http_connect_timeout_secs.max(1)),
+            read_timeout: Duration::from_secs(self.http_read_timeout_secs.max(1)),
+            max_retries: self.http_max_retries,
+            ..Default::default()
+        }
+    }
+
+    pub fn validate(&self) -> Result<ServerEndpoints, std::io::Error> {
+        let mut http_url = parse_url(&self.http_url, &["http", "https"], "HTTP")?;
+        // Url::join drops the last path segment when it has no trailing slash
+        if !http_url.path().ends_with('/') {
+            let path = format!("{}/", http_url.path());
+            http_url.set_path(&path);
+        }
+
+        let ws_url = parse_url(&self.ws_url, &["ws", "wss"], "WebSocket")?;
+        let ws_url: Uri = ws_url.as_str().parse().map_err(|_| invalid_input(
+            format!("Invalid WebSocket URL: {}", self.ws_url)
+        ))?;
+
+        let ca_certificate = if self.ca_cert_path.trim().is_empty() {
+            None
+        } else {
+            Some(read_ca_certificate(self.ca_cert_path.trim())?)
+        };
+
+        let connect_timeout = self.http_client_options().connect_timeout;
+
+        Ok(ServerEndpoints { http_url, ws_url, ca_certificate, connect_timeout })
+    }
+}
+
+// both TLS stacks must accept the certificate, otherwise only one of the transports would work
+fn read_ca_certificate(path: &str) -> Result<Vec<u8>, std::io::Error> {
+    let pem = fs::read(path)
+        .map_err(|e| invalid_input(format!("Couldn't read CA certificate {}: {}", path, e)))?;
+
+    reqwest::Certificate::from_pem(&pem)
+        .map_err(|_| invalid_input(format!("Invalid CA certificate: {}", path)))?;
+    native_tls::Certificate::from_pem(&pem)
+        .map_err(|_| invalid_input(format!("Invalid CA certificate: {}", path)))?;
+
+    Ok(pem)
+}
+
+fn parse_url(raw_url: &str, schemes: &[&str], label: &str) -> Result<Url, std::io::Error> {
+    let url = raw_url.trim().parse::<Url>()
+        .map_err(|_| invalid_input(format!("Invalid {} URL: {}", label, raw_url)))?;
+
+    if !schemes.contains(&url.scheme()) {
+        return Err(invalid_input(
+            format!("{} URL must start with {}://", label, schemes.join(":// or "))
+        ));
+    }
+    if url.host_str().is_none() {
+        return Err(invalid_input(format!("{} URL has no host: {}", label, raw_url)));
+    }
+
+    Ok(url)
+}
+
+fn invalid_input(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn overrides(variables: &[(&str, &str)]) -> (SettingsOverrides, Vec<std::io::Error>) {
+        let variables: HashMap<&str, &str> = variables.iter().copied().collect();
+        SettingsOverrides::from_lookup(|name| variables.get(name).map(|value| value.to_string()))
+    }
+
+    #[test]
+    fn environment_wins_over_saved_settings() {
+        let saved_settings = Settings {
+            http_url: "https://saved.example.com/".to_owned(),
+            ws_url: "wss://saved.example.com/ws".to_owned(),
+            http_workers: 2,
+            ..Default::default()
+        };
+        let (settings_overrides, errors) = overrides(&[
+            (HTTP_URL_ENV, "https://env.example.com/"),
+            (HTTP_WORKERS_ENV, "8"),
+            (KEY_STORE_ENV, "secret_service")
+        ]);
+
+        let settings = saved_settings.with_overrides(&settings_overrides);
+
+        assert!(errors.is_empty());
+        assert_eq!(settings.http_url, "https://env.example.com/");
+        assert_eq!(settings.ws_url, "wss://saved.example.com/ws");
+        assert_eq!(settings.http_workers, 8);
+        assert!(settings.key_store == KeyStoreKind::SecretService);
+        assert_eq!(settings_overrides.variable_names(), [HTTP_URL_ENV, HTTP_WORKERS_ENV, KEY_STORE_ENV]);
+    }
+
+    #[test]
+    fn unreadable_variable_is_reported_and_the_others_still_apply() {
+        let (settings_overrides, errors) = overrides(&[
+            (HTTP_WORKERS_ENV, "many"),
+            (KEY_STORE_ENV, "keychain"),
+            (WS_URL_ENV, "wss://env.example.com/ws")
+        ]);
+
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].to_string().contains(HTTP_WORKERS_ENV));
+        assert!(errors[1].to_string().contains(KEY_STORE_ENV));
+        assert_eq!(settings_overrides.variable_names(), [WS_URL_ENV]);
+    }
+
+    #[test]
+    fn endpoints_come_from_valid_urls() {
+        let settings = Settings {
+            http_url: " https://example.com/api ".to_owned(),
+            ws_url: "wss://example.com/ws_server".to_owned(),
+            ..Default::default()
+        };
+
+        let endpoints = settings.validate().unwrap();
+
+        assert_eq!(endpoints.http_url.as_str(), "https://example.com/api/");
+        assert_eq!(endpoints.http_url.join("login").unwrap().as_str(), "https://example.com/api/login");
+        assert_eq!(endpoints.ws_url.to_string(), "wss://example.com/ws_server");
+        assert!(endpoints.ca_certificate.is_none());
+        assert!(Settings::default().validate().is_ok());
+    }
+
+    #[test]
+    fn invalid_urls_are_refused() {
+        let with_urls = |http_url: &str, ws_url: &str| Settings {
+            http_url: http_url.to_owned(),
+            ws_url: ws_url.to_owned(),
+            ..Default::default()
+        };
+
+        assert!(with_urls("example.com", DEFAULT_WS_URL).validate().is_err());
+        assert!(with_urls("ftp://example.com/", DEFAULT_WS_URL).validate().is_err());
+        assert!(with_urls("http://", DEFAULT_WS_URL).validate().is_err());
+        assert!(with_urls(DEFAULT_HTTP_URL, "https://example.com/ws").validate().is_err());
+        assert!(with_urls(DEFAULT_HTTP_URL, "not a url").validate().is_err());
+    }
+
+    #[test]
+    fn invalid_ca_certificate_path_is_refused() {
+        let not_a_certificate = env::temp_dir().join(format!("nossochat-not-a-ca-{}.pem", std::process::id()));
+        fs::write(&not_a_certificate, "not a certificate").unwrap();
+        let with_ca = |ca_cert_path: &str| Settings { ca_cert_path: ca_cert_path.to_owned(), ..Default::default() };
+
+        let missing = with_ca("/nonexistent/ca.pem").validate().err().unwrap();
+        let invalid = with_ca(not_a_certificate.to_str().unwrap()).validate().err().unwrap();
+        fs::remove_file(&not_a_certificate).unwrap();
+
+        assert!(missing.to_string().starts_with("Couldn't read CA certificate"));
+        assert!(invalid.to_string().starts_with("Invalid CA certificate"));
+        assert!(with_ca("  ").validate().unwrap().ca_certificate.is_none());
+    }
+}
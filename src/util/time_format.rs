@@ -0,0 +1,116 @@
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, Utc};
+use serde::{Deserialize, Deserializer};
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawTimestamp {
+    Text(String),
+    UnixSeconds(i64)
+}
+
+// servers older than the timestamps send nothing, and times without an offset are taken as UTC
+pub fn deserialize_timestamp<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+where
+    D: Deserializer<'de>
+{
+    let timestamp = match Option::<RawTimestamp>::deserialize(deserializer)? {
+        None => None,
+        Some(RawTimestamp::UnixSeconds(seconds)) => DateTime::from_timestamp(seconds, 0),
+        Some(RawTimestamp::Text(text)) => DateTime::parse_from_rfc3339(&text)
+            .map(|time| time.with_timezone(&Utc))
+            .ok()
+            .or_else(|| {
+                ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"].iter()
+                    .find_map(|format| NaiveDateTime::parse_from_str(&text, format).ok())
+                    .map(|time| time.and_utc())
+            })
+    };
+
+    Ok(timestamp)
+}
+
+// under each message: relative while recent, the local time of day after that
+pub fn message_time(time: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let elapsed = now.signed_duration_since(time);
+
+    if elapsed.num_minutes() < 1 {
+        "just now".to_owned()
+    } else if elapsed.num_minutes() < 60 {
+        format!("{} min ago", elapsed.num_minutes())
+    } else if elapsed.num_hours() < 6 {
+        format!("{} h ago", elapsed.num_hours())
+    } else {
+        time.with_timezone(&Local).format("%H:%M").to_string()
+    }
+}
+
+pub fn full_time(time: DateTime<Utc>) -> String {
+    time.with_timezone(&Local).format("%A, %-d %B %Y %H:%M:%S").to_string()
+}
+
+pub fn local_date(time: DateTime<Utc>) -> NaiveDate {
+    time.with_timezone(&Local).date_naive()
+}
+
+pub fn day_separator(date: NaiveDate, today: NaiveDate) -> String {
+    match today.signed_duration_since(date).num_days() {
+        0 => "Today".to_owned(),
+        1 => "Yesterday".to_owned(),
+        2..7 => date.format("%A").to_string(),
+        _ => date.format("%-d %B %Y").to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+    use serde_json::json;
+
+    fn timestamp(value: serde_json::Value) -> Option<DateTime<Utc>> {
+        deserialize_timestamp(value).unwrap()
+    }
+
+    #[test]
+    fn server_times_are_read_in_every_format_it_sends() {
+        let time = Utc.with_ymd_and_hms(2024, 3, 9, 14, 5, 30).unwrap();
+
+        assert_eq!(timestamp(json!("2024-03-09T14:05:30Z")), Some(time));
+        assert_eq!(timestamp(json!("2024-03-09T11:05:30-03:00")), Some(time));
+        assert_eq!(timestamp(json!("2024-03-09T14:05:30")), Some(time));
+        assert_eq!(timestamp(json!("2024-03-09 14:05:30.250")), Some(time + Duration::milliseconds(250)));
+        assert_eq!(timestamp(json!(1709993130)), Some(time));
+    }
+
+    #[test]
+    fn missing_or_unreadable_time_is_left_out() {
+        assert_eq!(timestamp(json!(null)), None);
+        assert_eq!(timestamp(json!("yesterday")), None);
+        assert!(deserialize_timestamp(json!([1])).is_err());
+    }
+
+    #[test]
+    fn recent_messages_show_how_long_ago_they_were_sent() {
+        let now = Utc.with_ymd_and_hms(2024, 3, 9, 14, 5, 30).unwrap();
+
+        assert_eq!(message_time(now - Duration::seconds(59), now), "just now");
+        assert_eq!(message_time(now + Duration::seconds(30), now), "just now");
+        assert_eq!(message_time(now - Duration::minutes(5), now), "5 min ago");
+        assert_eq!(message_time(now - Duration::minutes(150), now), "2 h ago");
+
+        let older = now - Duration::hours(6);
+        assert_eq!(message_time(older, now), older.with_timezone(&Local).format("%H:%M").to_string());
+    }
+
+    #[test]
+    fn day_separator_names_the_last_week_by_day() {
+        let today = NaiveDate::from_ymd_opt(2024, 3, 9).unwrap();
+
+        assert_eq!(day_separator(today, today), "Today");
+        assert_eq!(day_separator(today - Duration::days(1), today), "Yesterday");
+        assert_eq!(day_separator(today - Duration::days(2), today), "Thursday");
+        assert_eq!(day_separator(today - Duration::days(6), today), "Sunday");
+        assert_eq!(day_separator(today - Duration::days(7), today), "2 March 2024");
+        assert_eq!(day_separator(today + Duration::days(1), today), "10 March 2024");
+    }
+}
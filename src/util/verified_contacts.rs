@@ -0,0 +1,13 @@
+use super::local_store::{save_json, load_json};
+use std::collections::HashMap;
+
+// the public key (base64) each contact had when the user compared safety numbers with them
+pub type VerifiedContacts = HashMap<u64, String>;
+
+pub fn save_verified_contacts(user_id: u64, verified_contacts: &VerifiedContacts) -> Result<(), std::io::Error> {
+    save_json(user_id, "verified_contacts.json", verified_contacts)
+}
+
+pub fn load_verified_contacts(user_id: u64) -> Result<VerifiedContacts, std::io::Error> {
+    load_json(user_id, "verified_contacts.json")
+}